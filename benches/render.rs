@@ -0,0 +1,96 @@
+//! Per-sample against block rendering, run with `cargo bench`
+
+use bevy_funk::{
+    avg, avg_block,
+    math::{saw, slice},
+    seq, seq_block,
+    sound::{
+        block::{BlockFn, BLOCK_LEN},
+        Float, OutputFn,
+    },
+};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+const SAMPLE_RATE: usize = 48_000;
+// what the native backend renders per callback
+const QUANTUM: usize = 128;
+const DETUNE: [Float; 8] = [110.0, 110.3, 109.7, 110.6, 109.4, 220.2, 219.8, 55.1];
+
+fn saws(t: Float) -> [Float; 2] {
+    let x: Float = DETUNE.iter().map(|f| saw(f * t)).sum::<Float>() * 0.1;
+    [x, x]
+}
+
+struct Saws;
+
+impl BlockFn for Saws {
+    fn render(&self, t0: Float, dt: Float, out: &mut [[Float; 2]]) {
+        let mut t = [0.0; BLOCK_LEN];
+        let mut phase = [0.0; BLOCK_LEN];
+        let mut acc = [0.0; BLOCK_LEN];
+        for (b, chunk) in out.chunks_mut(BLOCK_LEN).enumerate() {
+            let n = chunk.len();
+            slice::ramp(&mut t[..n], t0 + (b * BLOCK_LEN) as Float * dt, dt);
+            acc[..n].fill(0.0);
+            for f in DETUNE {
+                phase[..n].copy_from_slice(&t[..n]);
+                slice::scale(&mut phase[..n], f);
+                slice::saw(&mut phase[..n]);
+                slice::add(&mut acc[..n], &phase[..n]);
+            }
+            slice::scale(&mut acc[..n], 0.1);
+            for (frame, x) in chunk.iter_mut().zip(&acc[..n]) {
+                *frame = [*x, *x];
+            }
+        }
+    }
+}
+
+/// One second of audio, a quantum at a time like the backends
+fn render_second(sound_fn: &OutputFn, frames: &mut [Float]) {
+    let dt = 1.0 / SAMPLE_RATE as Float;
+    for q in 0..SAMPLE_RATE / QUANTUM {
+        sound_fn.render_block((q * QUANTUM) as Float * dt, dt, frames, 2);
+    }
+    black_box(frames);
+}
+
+fn oscillators(c: &mut Criterion) {
+    let mut frames = vec![0.0; QUANTUM * 2];
+    let mut group = c.benchmark_group("8 saws, 1s");
+    let per_sample = OutputFn::new(Box::new(saws));
+    group.bench_function("per sample", |b| {
+        b.iter(|| render_second(&per_sample, &mut frames))
+    });
+    let block = OutputFn::from_block(Saws);
+    group.bench_function("block", |b| b.iter(|| render_second(&block, &mut frames)));
+    group.finish();
+}
+
+fn combinators(c: &mut Criterion) {
+    let mut frames = vec![0.0; QUANTUM * 2];
+    let mut group = c.benchmark_group("seq and avg, 1s");
+    // how the per-sample macros are used, building the parts each sample
+    let per_sample = OutputFn::new(Box::new(|t| {
+        let x = avg![
+            seq![|t| saw(110.0 * t), |t| saw(220.0 * t)],
+            |t| saw(55.0 * t),
+            |t| saw(330.0 * t)
+        ](t);
+        [x, x]
+    }));
+    group.bench_function("per sample", |b| {
+        b.iter(|| render_second(&per_sample, &mut frames))
+    });
+    let mono = |f: Float| move |t: Float| [saw(f * t); 2];
+    let block = OutputFn::from_block(avg_block![
+        seq_block![mono(110.0), mono(220.0)],
+        mono(55.0),
+        mono(330.0)
+    ]);
+    group.bench_function("block", |b| b.iter(|| render_second(&block, &mut frames)));
+    group.finish();
+}
+
+criterion_group!(benches, oscillators, combinators);
+criterion_main!(benches);
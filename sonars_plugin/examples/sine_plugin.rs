@@ -0,0 +1,9 @@
+//! Build with `cargo build -p sonars_plugin --example sine_plugin`,
+//! then run `sonars --plugin target/debug/examples/libsine_plugin.so`
+
+use std::f64::consts::TAU;
+
+sonars_plugin::export_sound_fn!(|t: f64| {
+    let x = (t * 220.0 * TAU).sin() * (t * 0.5 * TAU).sin().abs() * 0.1;
+    [x, x]
+});
@@ -0,0 +1,111 @@
+//! The ABI between sonars and sound functions compiled into a separate `cdylib`.
+//!
+//! Rust closures and trait objects have no stable layout, so a plugin hands sonars a
+//! [`PluginSoundFn`] of plain C types instead. Plugins only need the [`export_sound_fn`] macro:
+//!
+//! ```ignore
+//! sonars_plugin::export_sound_fn!(|t: f64| {
+//!     let x = (t * 440.0 * std::f64::consts::TAU).sin() * 0.1;
+//!     [x, x]
+//! });
+//! ```
+
+use std::{ffi::c_void, panic::AssertUnwindSafe};
+
+/// Bumped whenever [`PluginSoundFn`] changes, sonars refuses plugins built against another version
+pub const ABI_VERSION: u32 = 1;
+
+/// Name of the function [`export_sound_fn`] exports, nul terminated for `dlsym`
+pub const ENTRY_POINT: &[u8] = b"sonars_sound_fn\0";
+
+pub type EntryPoint = extern "C" fn() -> PluginSoundFn;
+
+/// Render one frame at time `t` into `out`, which has room for `channels` values.
+/// Returns false if the sound function panicked, `out` is then left as it was
+pub type RenderFn = unsafe extern "C" fn(state: *const c_void, t: f64, out: *mut f64) -> bool;
+
+#[repr(C)]
+pub struct PluginSoundFn {
+    pub abi_version: u32,
+    pub channels: u32,
+    /// The boxed closure, only ever touched through `render` and `drop`
+    pub state: *mut c_void,
+    pub render: RenderFn,
+    pub drop: unsafe extern "C" fn(state: *mut c_void),
+}
+
+// `state` is a `Send + Sync` closure
+unsafe impl Send for PluginSoundFn {}
+unsafe impl Sync for PluginSoundFn {}
+
+/// Wrap a sound function in the C ABI. Panics are caught here, unwinding across `extern "C"` would abort
+pub fn export<F, const N: usize>(sound_fn: F) -> PluginSoundFn
+where
+    F: Fn(f64) -> [f64; N] + Send + Sync + 'static,
+{
+    unsafe extern "C" fn render<F, const N: usize>(
+        state: *const c_void,
+        t: f64,
+        out: *mut f64,
+    ) -> bool
+    where
+        F: Fn(f64) -> [f64; N],
+    {
+        let sound_fn = &*(state as *const F);
+        match std::panic::catch_unwind(AssertUnwindSafe(|| sound_fn(t))) {
+            Ok(frame) => {
+                std::ptr::copy_nonoverlapping(frame.as_ptr(), out, N);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    unsafe extern "C" fn drop_state<F>(state: *mut c_void) {
+        drop(Box::from_raw(state as *mut F));
+    }
+
+    PluginSoundFn {
+        abi_version: ABI_VERSION,
+        channels: N as u32,
+        state: Box::into_raw(Box::new(sound_fn)) as *mut c_void,
+        render: render::<F, N>,
+        drop: drop_state::<F>,
+    }
+}
+
+/// Export a sound function (any `Fn(f64) -> [f64; N]`) for sonars to load
+#[macro_export]
+macro_rules! export_sound_fn {
+    ($sound_fn:expr) => {
+        #[no_mangle]
+        pub extern "C" fn sonars_sound_fn() -> $crate::PluginSoundFn {
+            $crate::export($sound_fn)
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{export, ABI_VERSION};
+
+    #[test]
+    fn test_export() {
+        let plugin = export(|t: f64| {
+            assert!(t >= 0.0, "negative time");
+            [t, -t]
+        });
+        assert_eq!(plugin.abi_version, ABI_VERSION);
+        assert_eq!(plugin.channels, 2);
+
+        let mut out = [0.0; 2];
+        unsafe {
+            assert!((plugin.render)(plugin.state, 0.5, out.as_mut_ptr()));
+            assert_eq!(out, [0.5, -0.5]);
+            // the panic is caught and reported instead of unwinding into the caller
+            assert!(!(plugin.render)(plugin.state, -1.0, out.as_mut_ptr()));
+            assert_eq!(out, [0.5, -0.5]);
+            (plugin.drop)(plugin.state);
+        }
+    }
+}
@@ -0,0 +1,205 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+use bevy::{
+    ecs::event::EventWriter,
+    log::info,
+    prelude::{Res, ResMut, Resource},
+};
+use clap::Parser;
+
+use crate::{
+    lang::{evaluate, SubmitCode},
+    session::{LoadSession, Session, SessionFile},
+    sound::{
+        offline::{render, write_wav},
+        output_devices, Float, OutputSettings, SoundControl, SoundFn, DEFAULT_SAMPLE_RATE,
+    },
+};
+
+/// Live coded sound and visuals
+#[derive(Parser, Resource, Clone, Debug, Default)]
+#[command(name = "sonars", version)]
+pub struct Cli {
+    /// Script or session (.ron) to open
+    pub file: Option<PathBuf>,
+    /// Output device, by id or label
+    #[arg(long)]
+    pub device: Option<String>,
+    /// List output devices and exit
+    #[arg(long)]
+    pub list_devices: bool,
+    #[arg(long, value_parser = clap::value_parser!(u32).range(8_000..=384_000))]
+    pub sample_rate: Option<u32>,
+    /// Reload the script whenever it is saved
+    #[arg(long, requires = "file")]
+    pub watch: bool,
+    /// Play a sound function library built with `sonars_plugin`, reloading it when it is rebuilt
+    #[arg(long, value_name = "LIBRARY")]
+    pub plugin: Option<PathBuf>,
+    /// Start with the transport paused
+    #[arg(long)]
+    pub paused: bool,
+    /// Audio only, without a window
+    #[arg(long)]
+    pub headless: bool,
+    /// Render to a WAV file instead of playing, then exit
+    #[arg(long, value_name = "WAV")]
+    pub render: Option<PathBuf>,
+    /// Length of the render in seconds
+    #[arg(long, default_value_t = 10.0, requires = "render")]
+    pub duration: Float,
+}
+
+/// What [`Cli::file`] turned out to be
+pub enum Opened {
+    Script(String),
+    Session(Session),
+}
+
+impl Opened {
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        if path.extension().is_some_and(|e| e == "ron") {
+            Ok(Opened::Session(Session::load(path)?))
+        } else {
+            let source =
+                std::fs::read_to_string(path).with_context(|| format!("reading {path:?}"))?;
+            Ok(Opened::Script(source))
+        }
+    }
+
+    /// None for a session without any code
+    fn code(&self) -> Option<&str> {
+        match self {
+            Opened::Script(source) => Some(source),
+            Opened::Session(session) => (!session.code.is_empty()).then_some(&session.code),
+        }
+    }
+}
+
+impl Cli {
+    /// Checked before anything starts, so a typo fails straight away instead of playing the wrong device
+    pub fn output_settings(&self) -> anyhow::Result<OutputSettings> {
+        let mut settings = OutputSettings {
+            sample_rate: self.sample_rate,
+            ..Default::default()
+        };
+        if let Some(device) = &self.device {
+            let devices = output_devices();
+            match devices
+                .iter()
+                .find(|d| &d.id == device || &d.label == device)
+            {
+                Some(found) => settings.sink_id.clone_from(&found.id),
+                None => bail!(
+                    "no output device {device:?}, available devices are:\n{}",
+                    device_list()
+                ),
+            }
+        }
+        Ok(settings)
+    }
+
+    /// Render the opened file, or `fallback` if it has no code, to [`Cli::render`]
+    pub fn render(&self, opened: Option<&Opened>, fallback: SoundFn) -> anyhow::Result<()> {
+        let Some(path) = &self.render else {
+            bail!("no render path given");
+        };
+        let mut start = 0.0;
+        if let Some(Opened::Session(session)) = opened {
+            session.apply_globals();
+            start = session.position;
+        }
+        let sound_fn = match opened.and_then(Opened::code) {
+            Some(source) => evaluate(source)?,
+            None => fallback,
+        };
+        let rate = self.sample_rate.unwrap_or(DEFAULT_SAMPLE_RATE);
+        let frames = render(&sound_fn, rate, start, self.duration);
+        write_wav(path, &frames, rate)?;
+        info!("Rendered {} seconds to {path:?}", self.duration);
+        Ok(())
+    }
+}
+
+/// One device per line, id then label
+pub fn device_list() -> String {
+    output_devices()
+        .into_iter()
+        .map(|d| format!("{}\t{}", d.id, d.label))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Startup system applying the rest of the arguments once the app is running
+pub fn apply(
+    cli: Res<Cli>,
+    mut sound: ResMut<SoundControl>,
+    mut session_file: ResMut<SessionFile>,
+    mut load: EventWriter<LoadSession>,
+    mut code: EventWriter<SubmitCode>,
+) {
+    if cli.paused {
+        sound.pause();
+    }
+    let Some(path) = &cli.file else { return };
+    if path.extension().is_some_and(|e| e == "ron") {
+        session_file.path = path.to_string_lossy().into_owned();
+        // the session would otherwise restore its own transport state over `--paused`
+        load.send(LoadSession {
+            path: path.clone(),
+            paused: cli.paused.then_some(true),
+        });
+    } else if cli.watch {
+        // the watcher loads the script itself
+    } else if let Ok(source) = std::fs::read_to_string(path) {
+        code.send(SubmitCode::new(source));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::{Cli, Opened};
+
+    #[test]
+    fn test_args() {
+        let cli = Cli::try_parse_from([
+            "sonars",
+            "live.ron",
+            "--sample-rate",
+            "44100",
+            "--paused",
+            "--render",
+            "out.wav",
+            "--duration",
+            "2.5",
+        ])
+        .unwrap();
+        assert_eq!(cli.file.unwrap().to_str(), Some("live.ron"));
+        assert_eq!(cli.sample_rate, Some(44_100));
+        assert!(cli.paused && !cli.headless);
+        assert_eq!(cli.duration, 2.5);
+
+        assert!(Cli::try_parse_from(["sonars", "--sample-rate", "12"]).is_err());
+        assert!(Cli::try_parse_from(["sonars", "--duration", "3"]).is_err());
+        assert!(Cli::try_parse_from(["sonars", "--watch"]).is_err());
+    }
+
+    #[test]
+    fn test_render_script() {
+        let path = crate::test_dir("render_script").join("out.wav");
+        let cli = Cli {
+            render: Some(path.clone()),
+            duration: 0.1,
+            ..Default::default()
+        };
+        let script = Opened::Script("0.5 * sin(440 * t)".into());
+        cli.render(Some(&script), Box::new(|_| [0.0; 2])).unwrap();
+        assert!(std::fs::metadata(&path).unwrap().len() > 44);
+
+        let broken = Opened::Script("sin(".into());
+        assert!(cli.render(Some(&broken), Box::new(|_| [0.0; 2])).is_err());
+    }
+}
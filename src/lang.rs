@@ -1 +1,472 @@
+use std::collections::BTreeMap;
 
+use bevy::{
+    app::Update,
+    ecs::event::{Event, EventReader},
+    log::warn,
+    prelude::{Plugin, Res, ResMut, Resource},
+};
+
+use crate::{
+    math::{abs, clip, cos, pow, sat, saw, sin, sqr, tan, tri},
+    sound::{beats, events::When, mixer::MAIN_TRACK, params::param, Float, SoundControl, SoundFn},
+};
+
+/// Newly evaluated code fades in over this many seconds, so reloads don't click
+pub const CODE_CROSSFADE: Float = 0.1;
+
+pub struct LangPlugin;
+
+impl Plugin for LangPlugin {
+    fn build(&self, app: &mut bevy::prelude::App) {
+        app.add_event::<SubmitCode>()
+            .init_resource::<LangStatus>()
+            .init_resource::<LangSettings>()
+            .add_systems(Update, evaluate_submitted);
+    }
+}
+
+/// Source code to evaluate and play, sent by anything that edits code (OSC, watched files...)
+#[derive(Event, Clone, Debug)]
+pub struct SubmitCode {
+    pub source: String,
+    /// Only this track fades to the new sound
+    pub track: String,
+}
+
+impl SubmitCode {
+    /// Code for the main track
+    pub fn new(source: String) -> Self {
+        Self {
+            source,
+            track: MAIN_TRACK.into(),
+        }
+    }
+}
+
+/// Result of the last evaluation, the previous sound keeps playing when it fails
+#[derive(Resource, Default, Debug)]
+pub struct LangStatus {
+    /// The last code submitted to the main track, whether or not it evaluated
+    pub source: String,
+    /// The same for each of the other tracks
+    pub track_sources: BTreeMap<String, String>,
+    pub error: Option<String>,
+}
+
+#[derive(Resource, Default, Debug)]
+pub struct LangSettings {
+    /// When new code starts fading in, e.g. [`When::Bar`] so changes land on the downbeat
+    pub apply_on: When,
+}
+
+/// Turn source code into a sound function.
+///
+/// The code is one expression of `t` (seconds), e.g. `0.3 * sin(440 * t)`, or `[left, right]` for
+/// stereo. It has `+ - * / % ^`, the `t`, `beat` and `pi` names, the functions in [`crate::math`]
+/// (`sin`, `cos`, `tan`, `saw`, `tri`, `sqr`, `sat`, `clip`, `abs`, `pow`) plus `min` and `max`,
+/// and `param("name")` to read a registered parameter. `//` starts a comment
+pub fn evaluate(source: &str) -> anyhow::Result<SoundFn> {
+    let mut parser = Parser {
+        source,
+        tokens: tokenize(source)?,
+        next: 0,
+        nesting: 0,
+    };
+    let channels = if parser.eat(Token::Sym('[')) {
+        let mut channels = vec![parser.expr()?.0];
+        while parser.eat(Token::Sym(',')) {
+            channels.push(parser.expr()?.0);
+        }
+        parser.expect(Token::Sym(']'))?;
+        channels
+    } else {
+        vec![parser.expr()?.0]
+    };
+    if parser.next < parser.tokens.len() {
+        return Err(parser.error("expected the end of the code"));
+    }
+    match <[Expr; 2]>::try_from(channels) {
+        Ok([l, r]) => Ok(Box::new(move |t| [l.eval(t), r.eval(t)])),
+        Err(channels) => match <[Expr; 1]>::try_from(channels) {
+            Ok([mono]) => Ok(Box::new(move |t| [mono.eval(t); 2])),
+            Err(channels) => anyhow::bail!("expected 1 or 2 channels, got {}", channels.len()),
+        },
+    }
+}
+
+// how deeply code can nest, both the parser and evaluation recurse this far
+const MAX_DEPTH: usize = 256;
+
+#[derive(Clone, Debug)]
+enum Expr {
+    Num(Float),
+    Time,
+    Beat,
+    Param(String),
+    Call(fn(Float) -> Float, Box<Expr>),
+    Call2(fn(Float, Float) -> Float, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    fn eval(&self, t: Float) -> Float {
+        match self {
+            Expr::Num(n) => *n,
+            Expr::Time => t,
+            Expr::Beat => beats(t),
+            Expr::Param(name) => param(name)(t),
+            Expr::Call(f, a) => f(a.eval(t)),
+            Expr::Call2(f, a, b) => f(a.eval(t), b.eval(t)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Num(Float),
+    Name(String),
+    Str(String),
+    Sym(char),
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Num(n) => write!(f, "`{n}`"),
+            Token::Name(name) => write!(f, "`{name}`"),
+            Token::Str(text) => write!(f, "{text:?}"),
+            Token::Sym(c) => write!(f, "`{c}`"),
+        }
+    }
+}
+
+/// Tokens with their byte offsets in the source
+fn tokenize(source: &str) -> anyhow::Result<Vec<(Token, usize)>> {
+    let mut tokens = vec![];
+    let mut chars = source.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '/' if source[start..].starts_with("//") => {
+                while chars.next_if(|&(_, c)| c != '\n').is_some() {}
+                continue;
+            }
+            '0'..='9' | '.' => {
+                let mut end = start + 1;
+                while let Some((i, _)) = chars.next_if(|&(_, c)| c.is_ascii_digit() || c == '.') {
+                    end = i + 1;
+                }
+                let number = &source[start..end];
+                Token::Num(number.parse().map_err(|_| {
+                    anyhow::anyhow!("{}: bad number `{number}`", location(source, start))
+                })?)
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut end = start + c.len_utf8();
+                while let Some((i, c)) = chars.next_if(|&(_, c)| c.is_alphanumeric() || c == '_') {
+                    end = i + c.len_utf8();
+                }
+                Token::Name(source[start..end].into())
+            }
+            '"' => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, c)) => text.push(c),
+                        None => {
+                            anyhow::bail!("{}: unterminated string", location(source, start))
+                        }
+                    }
+                }
+                Token::Str(text)
+            }
+            '+' | '-' | '*' | '/' | '%' | '^' | '(' | ')' | '[' | ']' | ',' => Token::Sym(c),
+            c => anyhow::bail!("{}: unexpected `{c}`", location(source, start)),
+        };
+        tokens.push((token, start));
+    }
+    Ok(tokens)
+}
+
+/// 1-based line and column of a byte offset
+fn location(source: &str, offset: usize) -> String {
+    let before = &source[..offset];
+    let line = before.matches('\n').count() + 1;
+    let column = before.chars().rev().take_while(|&c| c != '\n').count() + 1;
+    format!("line {line}, column {column}")
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    tokens: Vec<(Token, usize)>,
+    next: usize,
+    // brackets and unary operators the parser is inside
+    nesting: usize,
+}
+
+/// An expression and the depth of its tree
+type Parsed = (Expr, usize);
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next).map(|(token, _)| token)
+    }
+
+    fn eat(&mut self, token: Token) -> bool {
+        let found = self.peek() == Some(&token);
+        self.next += found as usize;
+        found
+    }
+
+    fn expect(&mut self, token: Token) -> anyhow::Result<()> {
+        match self.eat(token.clone()) {
+            true => Ok(()),
+            false => Err(self.error(&format!("expected {token}"))),
+        }
+    }
+
+    // a new node over `children`, as long as the tree doesn't get too deep
+    fn node(&self, expr: Expr, children: &[usize]) -> anyhow::Result<Parsed> {
+        let depth = children.iter().max().map_or(1, |depth| depth + 1);
+        if depth > MAX_DEPTH {
+            return Err(self.error("the code nests too deeply"));
+        }
+        Ok((expr, depth))
+    }
+
+    fn error(&self, message: &str) -> anyhow::Error {
+        match self.tokens.get(self.next) {
+            Some((token, offset)) => anyhow::anyhow!(
+                "{}: {message}, found {token}",
+                location(self.source, *offset)
+            ),
+            None => anyhow::anyhow!("{message}, found the end of the code"),
+        }
+    }
+
+    /// `+` and `-`
+    fn expr(&mut self) -> anyhow::Result<Parsed> {
+        let mut left = self.term()?;
+        loop {
+            let op: fn(Float, Float) -> Float = match self.peek() {
+                Some(Token::Sym('+')) => |a, b| a + b,
+                Some(Token::Sym('-')) => |a, b| a - b,
+                _ => return Ok(left),
+            };
+            self.next += 1;
+            let right = self.term()?;
+            left = self.node(
+                Expr::Call2(op, left.0.into(), right.0.into()),
+                &[left.1, right.1],
+            )?;
+        }
+    }
+
+    /// `*`, `/` and `%`
+    fn term(&mut self) -> anyhow::Result<Parsed> {
+        let mut left = self.unary()?;
+        loop {
+            let op: fn(Float, Float) -> Float = match self.peek() {
+                Some(Token::Sym('*')) => |a, b| a * b,
+                Some(Token::Sym('/')) => |a, b| a / b,
+                Some(Token::Sym('%')) => |a, b| a % b,
+                _ => return Ok(left),
+            };
+            self.next += 1;
+            let right = self.unary()?;
+            left = self.node(
+                Expr::Call2(op, left.0.into(), right.0.into()),
+                &[left.1, right.1],
+            )?;
+        }
+    }
+
+    /// Negation, and `^` which binds tighter and to the right. Everything nested goes through
+    /// here, so this is where the parser stops before it runs out of stack
+    fn unary(&mut self) -> anyhow::Result<Parsed> {
+        if self.nesting >= MAX_DEPTH {
+            return Err(self.error("the code nests too deeply"));
+        }
+        self.nesting += 1;
+        let parsed = self.unary_inner();
+        self.nesting -= 1;
+        parsed
+    }
+
+    fn unary_inner(&mut self) -> anyhow::Result<Parsed> {
+        if self.eat(Token::Sym('-')) {
+            let (inner, depth) = self.unary()?;
+            return self.node(Expr::Call(|a| -a, inner.into()), &[depth]);
+        }
+        let base = self.atom()?;
+        match self.eat(Token::Sym('^')) {
+            true => {
+                let exponent = self.unary()?;
+                self.node(
+                    Expr::Call2(pow, base.0.into(), exponent.0.into()),
+                    &[base.1, exponent.1],
+                )
+            }
+            false => Ok(base),
+        }
+    }
+
+    fn atom(&mut self) -> anyhow::Result<Parsed> {
+        let Some((token, _)) = self.tokens.get(self.next).cloned() else {
+            return Err(self.error("expected a value"));
+        };
+        match token {
+            Token::Num(n) => {
+                self.next += 1;
+                self.node(Expr::Num(n), &[])
+            }
+            Token::Sym('(') => {
+                self.next += 1;
+                let inner = self.expr()?;
+                self.expect(Token::Sym(')'))?;
+                Ok(inner)
+            }
+            Token::Name(name) => {
+                self.next += 1;
+                if self.eat(Token::Sym('(')) {
+                    self.call(&name)
+                } else {
+                    match name.as_str() {
+                        "t" => self.node(Expr::Time, &[]),
+                        "beat" => self.node(Expr::Beat, &[]),
+                        "pi" => self.node(Expr::Num(std::f64::consts::PI as Float), &[]),
+                        _ => {
+                            self.next -= 1;
+                            Err(self.error("unknown name"))
+                        }
+                    }
+                }
+            }
+            _ => Err(self.error("expected a value")),
+        }
+    }
+
+    /// The arguments and closing bracket of a function call
+    fn call(&mut self, name: &str) -> anyhow::Result<Parsed> {
+        if name == "param" {
+            let Some(Token::Str(param)) = self.peek().cloned() else {
+                return Err(self.error("expected a parameter name in quotes"));
+            };
+            self.next += 1;
+            self.expect(Token::Sym(')'))?;
+            return self.node(Expr::Param(param), &[]);
+        }
+        let one: Option<fn(Float) -> Float> = match name {
+            "sin" => Some(sin),
+            "cos" => Some(cos),
+            "tan" => Some(tan),
+            "saw" => Some(saw),
+            "tri" => Some(tri),
+            "sqr" => Some(sqr),
+            "sat" => Some(sat),
+            "clip" => Some(clip),
+            "abs" => Some(abs),
+            _ => None,
+        };
+        let two: Option<fn(Float, Float) -> Float> = match name {
+            "pow" => Some(pow),
+            "min" => Some(Float::min),
+            "max" => Some(Float::max),
+            _ => None,
+        };
+        if one.is_none() && two.is_none() {
+            self.next -= 2;
+            return Err(self.error("unknown function"));
+        }
+        let first = self.expr()?;
+        let call = match (one, two) {
+            (Some(f), _) => self.node(Expr::Call(f, first.0.into()), &[first.1])?,
+            (_, Some(f)) => {
+                self.expect(Token::Sym(','))?;
+                let second = self.expr()?;
+                self.node(
+                    Expr::Call2(f, first.0.into(), second.0.into()),
+                    &[first.1, second.1],
+                )?
+            }
+            _ => unreachable!(),
+        };
+        self.expect(Token::Sym(')'))?;
+        Ok(call)
+    }
+}
+
+fn evaluate_submitted(
+    mut submitted: EventReader<SubmitCode>,
+    sound_control: ResMut<SoundControl>,
+    mut status: ResMut<LangStatus>,
+    settings: Res<LangSettings>,
+) {
+    for SubmitCode { source, track } in submitted.read() {
+        match track.as_str() {
+            MAIN_TRACK => status.source.clone_from(source),
+            _ => {
+                status.track_sources.insert(track.clone(), source.clone());
+            }
+        }
+        match evaluate(source) {
+            Ok(sound_fn) => {
+                sound_control.push_track_at(track, sound_fn, CODE_CROSSFADE, settings.apply_on);
+                status.error = None;
+            }
+            Err(e) => {
+                warn!("Couldn't evaluate code: {e:#}");
+                status.error = Some(format!("{e:#}"));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::evaluate;
+
+    #[test]
+    fn test_evaluate() {
+        let mono = evaluate("0.5 * sin(440 * t) // a quiet sine").unwrap();
+        let [l, r] = mono(0.25 / 440.0);
+        assert!((l - 0.5).abs() < 1e-9 && l == r);
+        let stereo = evaluate("[t, -t]").unwrap();
+        assert_eq!(stereo(2.0), [2.0, -2.0]);
+        // precedence, right-associative powers and unary minus
+        assert_eq!(evaluate("1 + 2 * 3 ^ 2 ^ 0.5 - -1").unwrap()(0.0)[0], {
+            1.0 + 2.0 * 3f64.powf(2f64.powf(0.5)) + 1.0
+        });
+        assert_eq!(evaluate("max(t, 1) % 2").unwrap()(0.5)[0], 1.0);
+        assert_eq!(
+            evaluate("param(\"test_lang_missing\")").unwrap()(0.0)[0],
+            0.0
+        );
+    }
+
+    #[test]
+    fn test_evaluate_errors() {
+        let error = |source: &str| format!("{:#}", evaluate(source).err().unwrap());
+        assert_eq!(error("sin(t"), "expected `)`, found the end of the code");
+        assert_eq!(
+            error("1 +\n  foo"),
+            "line 2, column 3: unknown name, found `foo`"
+        );
+        assert_eq!(
+            error("bar(t)"),
+            "line 1, column 1: unknown function, found `bar`"
+        );
+        assert_eq!(error("[t, t, t]"), "expected 1 or 2 channels, got 3");
+        assert_eq!(error("t $"), "line 1, column 3: unexpected `$`");
+        // too deep to parse or evaluate on any stack, rather than overflowing it
+        for deep in [
+            "(".repeat(100_000),
+            "-".repeat(100_000) + "t",
+            "t+".repeat(100_000) + "t",
+        ] {
+            assert!(error(&deep).contains("the code nests too deeply"));
+        }
+        assert!(evaluate(&("t+".repeat(200) + "t")).is_ok());
+    }
+}
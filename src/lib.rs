@@ -0,0 +1,31 @@
+pub mod cli;
+pub mod editor;
+mod fft;
+pub mod lang;
+pub mod link;
+pub mod math;
+pub mod midi;
+pub mod osc;
+pub mod session;
+pub mod sound;
+pub mod visuals;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod watch;
+
+/// An empty directory for one test, unique to the test run so parallel runs don't collide
+#[cfg(test)]
+pub fn test_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("sonars_test_{name}_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Held by tests that touch the audio thread's globals (the event queue, transport, load and
+/// panic reports), so they don't see each other's state when run in parallel
+#[cfg(test)]
+pub fn lock_globals() -> std::sync::MutexGuard<'static, ()> {
+    static GLOBALS: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    // a failed test shouldn't fail the others too
+    GLOBALS.lock().unwrap_or_else(|e| e.into_inner())
+}
@@ -0,0 +1,976 @@
+//! Tempo, beat phase and start/stop shared with other apps on the LAN over the Ableton Link
+//! protocol. Peers multicast their state to find each other, and a peer that finds a session it
+//! should join pings one of its members to measure that session's "ghost" clock against its own.
+//! The session's timeline, a tempo and the beat at some ghost time, then puts the same beat at
+//! the same moment on every peer.
+//!
+//! A session's ghost time starts at 0, so it counts how long the session has been running, and as
+//! in Link the peers move to whichever session has been running longest.
+//!
+//! todo_major: IPv4 on one interface only, and std can't set SO_REUSEADDR so only one app on a
+//! host can bind the discovery port
+
+use std::{
+    collections::{HashMap, HashSet},
+    hash::{BuildHasher, Hasher},
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use bevy::{
+    app::Update,
+    ecs::{change_detection::DetectChanges, schedule::IntoSystemConfigs},
+    log::{info, warn},
+    prelude::{Plugin, Res, ResMut, Resource},
+};
+
+use crate::sound::{
+    beat_error, beats, clock, load, set_tempo, shift_beats, tempo, Float, SoundControl,
+    SoundResources,
+};
+
+pub const MULTICAST_ADDR: Ipv4Addr = Ipv4Addr::new(224, 76, 78, 75);
+pub const LINK_PORT: u16 = 20808;
+const DISCOVERY_HEADER: &[u8] = b"_asdp_v\x01";
+const MEASUREMENT_HEADER: &[u8] = b"_link_v\x01";
+const ALIVE: u8 = 1;
+const RESPONSE: u8 = 2;
+const BYEBYE: u8 = 3;
+const PING: u8 = 1;
+const PONG: u8 = 2;
+const TIMELINE: &[u8; 4] = b"tmln";
+const SESSION: &[u8; 4] = b"sess";
+const START_STOP: &[u8; 4] = b"stst";
+const ENDPOINT: &[u8; 4] = b"mep4";
+const HOST_TIME: &[u8; 4] = b"__ht";
+const GHOST_TIME: &[u8; 4] = b"__gt";
+/// Seconds a peer is remembered for without hearing from it again
+const TTL: u8 = 5;
+const BROADCAST_PERIOD: Duration = Duration::from_millis(250);
+// how often the peer thread checks whether it should stop or broadcast
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+// pings per measurement, the median is taken so one slow round trip doesn't matter
+const PINGS: usize = 5;
+const PING_TIMEOUT: Duration = Duration::from_millis(50);
+// before trying to join a session again after a measurement failed
+const RETRY_PERIOD: Duration = Duration::from_secs(1);
+// sessions started closer together than this, in microseconds, count as the same age and the
+// lower id wins
+const SESSION_EPS: i64 = 500_000;
+// how long our own changes win over the session's, so a broadcast that crossed ours doesn't undo them
+const CHANGE_HOLD: Duration = Duration::from_millis(500);
+/// Beats the timeline can be off the session before it is moved back in phase
+pub const PHASE_TOLERANCE: Float = 0.02;
+
+pub type NodeId = [u8; 8];
+
+/// A tempo and where its beats fall, in the session's ghost time
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Timeline {
+    /// Beats per minute
+    pub tempo: Float,
+    /// The beat at `time_origin`
+    pub beat_origin: Float,
+    /// Ghost time in microseconds
+    pub time_origin: i64,
+}
+
+impl Timeline {
+    pub fn beat_at(&self, ghost: i64) -> Float {
+        self.beat_origin + (ghost - self.time_origin) as Float / 1e6 * self.tempo / 60.0
+    }
+
+    /// The same beats up to `ghost`, and the new tempo from there on
+    pub fn with_tempo(&self, tempo: Float, ghost: i64) -> Self {
+        Self {
+            tempo,
+            beat_origin: self.beat_at(ghost),
+            time_origin: ghost,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StartStop {
+    pub playing: bool,
+    /// The beat the transport started or stopped on
+    pub beat: Float,
+    /// Ghost time of the change in microseconds, the latest change wins
+    pub time: i64,
+}
+
+/// What a peer tells the others about itself
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeerState {
+    pub session: NodeId,
+    pub timeline: Timeline,
+    pub start_stop: Option<StartStop>,
+    /// Where to ping this peer to measure its session's ghost time
+    pub endpoint: Option<SocketAddrV4>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message {
+    /// Multicast regularly and whenever something changes
+    Alive(NodeId, PeerState),
+    /// Sent straight back to a peer we heard an [`Message::Alive`] from
+    Response(NodeId, PeerState),
+    /// The peer is leaving
+    ByeBye(NodeId),
+    /// Host time of the sender, in microseconds
+    Ping(i64),
+    /// The ping's host time back, with the ghost time it was answered at
+    Pong {
+        session: NodeId,
+        host_time: i64,
+        ghost_time: i64,
+    },
+}
+
+impl Message {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![];
+        match self {
+            Message::Alive(node, state) | Message::Response(node, state) => {
+                let kind = match self {
+                    Message::Alive(..) => ALIVE,
+                    _ => RESPONSE,
+                };
+                discovery_header(&mut out, kind, TTL, node);
+                let timeline = &state.timeline;
+                let mut value = vec![];
+                value.extend(((60e6 / timeline.tempo).round() as i64).to_be_bytes());
+                value.extend(micro(timeline.beat_origin).to_be_bytes());
+                value.extend(timeline.time_origin.to_be_bytes());
+                entry(&mut out, TIMELINE, &value);
+                entry(&mut out, SESSION, &state.session);
+                if let Some(start_stop) = &state.start_stop {
+                    let mut value = vec![start_stop.playing as u8];
+                    value.extend(micro(start_stop.beat).to_be_bytes());
+                    value.extend(start_stop.time.to_be_bytes());
+                    entry(&mut out, START_STOP, &value);
+                }
+                if let Some(endpoint) = &state.endpoint {
+                    let mut value = endpoint.ip().octets().to_vec();
+                    value.extend(endpoint.port().to_be_bytes());
+                    entry(&mut out, ENDPOINT, &value);
+                }
+            }
+            Message::ByeBye(node) => discovery_header(&mut out, BYEBYE, 0, node),
+            Message::Ping(host_time) => {
+                out.extend(MEASUREMENT_HEADER);
+                out.push(PING);
+                entry(&mut out, HOST_TIME, &host_time.to_be_bytes());
+            }
+            Message::Pong {
+                session,
+                host_time,
+                ghost_time,
+            } => {
+                out.extend(MEASUREMENT_HEADER);
+                out.push(PONG);
+                entry(&mut out, SESSION, session);
+                entry(&mut out, GHOST_TIME, &ghost_time.to_be_bytes());
+                entry(&mut out, HOST_TIME, &host_time.to_be_bytes());
+            }
+        }
+        out
+    }
+}
+
+// beats and the like go over the wire in millionths
+fn micro(x: Float) -> i64 {
+    (x * 1e6).round() as i64
+}
+
+fn discovery_header(out: &mut Vec<u8>, kind: u8, ttl: u8, node: &NodeId) {
+    out.extend(DISCOVERY_HEADER);
+    out.extend([kind, ttl]);
+    // the session group, always 0
+    out.extend([0, 0]);
+    out.extend(node);
+}
+
+fn entry(out: &mut Vec<u8>, key: &[u8; 4], value: &[u8]) {
+    out.extend(key);
+    out.extend((value.len() as u32).to_be_bytes());
+    out.extend(value);
+}
+
+/// Reads the big endian fields of a packet
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> anyhow::Result<&'a [u8]> {
+        if n > self.bytes.len() {
+            bail!("packet ended early");
+        }
+        let (taken, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn array<const N: usize>(&mut self) -> anyhow::Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn i64(&mut self) -> anyhow::Result<i64> {
+        Ok(i64::from_be_bytes(self.array()?))
+    }
+
+    /// The rest of the packet as keyed entries, unknown keys are for newer peers to use
+    fn entries(&mut self) -> anyhow::Result<HashMap<[u8; 4], Reader<'a>>> {
+        let mut entries = HashMap::new();
+        while !self.bytes.is_empty() {
+            let key = self.array()?;
+            let len = u32::from_be_bytes(self.array()?) as usize;
+            entries.insert(
+                key,
+                Reader {
+                    bytes: self.take(len)?,
+                },
+            );
+        }
+        Ok(entries)
+    }
+}
+
+pub fn decode(bytes: &[u8]) -> anyhow::Result<Message> {
+    if let Some(bytes) = bytes.strip_prefix(DISCOVERY_HEADER) {
+        let mut reader = Reader { bytes };
+        let [kind, _ttl, _, _] = reader.array()?;
+        let node = reader.array()?;
+        return Ok(match kind {
+            ALIVE => Message::Alive(node, read_state(&mut reader)?),
+            RESPONSE => Message::Response(node, read_state(&mut reader)?),
+            BYEBYE => Message::ByeBye(node),
+            x => bail!("unknown Link discovery message {x}"),
+        });
+    }
+    let bytes = bytes
+        .strip_prefix(MEASUREMENT_HEADER)
+        .context("not a Link packet")?;
+    let mut reader = Reader { bytes };
+    let [kind] = reader.array()?;
+    let mut entries = reader.entries()?;
+    let mut field = |key: &[u8; 4]| entries.remove(key).context("missing a field");
+    Ok(match kind {
+        PING => Message::Ping(field(HOST_TIME)?.i64()?),
+        PONG => Message::Pong {
+            session: field(SESSION)?.array()?,
+            host_time: field(HOST_TIME)?.i64()?,
+            ghost_time: field(GHOST_TIME)?.i64()?,
+        },
+        x => bail!("unknown Link measurement message {x}"),
+    })
+}
+
+fn read_state(reader: &mut Reader) -> anyhow::Result<PeerState> {
+    let mut entries = reader.entries()?;
+    let mut timeline = entries.remove(TIMELINE).context("no timeline")?;
+    let micros_per_beat = timeline.i64()?;
+    if micros_per_beat <= 0 {
+        bail!("tempo out of range");
+    }
+    let timeline = Timeline {
+        tempo: 60e6 / micros_per_beat as Float,
+        beat_origin: timeline.i64()? as Float / 1e6,
+        time_origin: timeline.i64()?,
+    };
+    let session = entries.remove(SESSION).context("no session")?.array()?;
+    let start_stop = match entries.remove(START_STOP) {
+        Some(mut value) => Some(StartStop {
+            playing: value.array::<1>()?[0] != 0,
+            beat: value.i64()? as Float / 1e6,
+            time: value.i64()?,
+        }),
+        None => None,
+    };
+    let endpoint = match entries.remove(ENDPOINT) {
+        Some(mut value) => {
+            let ip: [u8; 4] = value.array()?;
+            let port = u16::from_be_bytes(value.array()?);
+            Some(SocketAddrV4::new(ip.into(), port))
+        }
+        None => None,
+    };
+    Ok(PeerState {
+        session,
+        timeline,
+        start_stop,
+        endpoint,
+    })
+}
+
+/// Microseconds on the host clock, see [`load::now`]
+fn host_time() -> i64 {
+    micro(load::now())
+}
+
+fn new_node_id() -> NodeId {
+    // std seeds every RandomState differently, which is all the randomness an id needs
+    std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish()
+        .to_be_bytes()
+}
+
+/// Our side of the session, shared between the peer thread and the app
+struct Shared {
+    node: NodeId,
+    state: PeerState,
+    // ghost minus host time, in microseconds
+    ghost_offset: i64,
+    // with when they stop counting
+    peers: HashMap<NodeId, (PeerState, Instant)>,
+    // sessions measured and found to be younger than ours, forgotten when we move
+    younger: HashSet<NodeId>,
+    // something the others should hear about straight away
+    changed: bool,
+    changed_at: Option<Instant>,
+}
+
+impl Shared {
+    fn ghost_time(&self, host: i64) -> i64 {
+        host + self.ghost_offset
+    }
+
+    fn change(&mut self) {
+        self.changed = true;
+        self.changed_at = Some(Instant::now());
+    }
+
+    fn hear(&mut self, node: NodeId, state: PeerState) {
+        let now = Instant::now();
+        let ours = state.session == self.state.session;
+        let holding = self
+            .changed_at
+            .is_some_and(|at| now.duration_since(at) < CHANGE_HOLD);
+        if ours && !holding {
+            self.state.timeline = state.timeline;
+            let newer = match (state.start_stop, self.state.start_stop) {
+                (Some(theirs), Some(mine)) => theirs.time > mine.time,
+                (theirs, _) => theirs.is_some(),
+            };
+            if newer {
+                self.state.start_stop = state.start_stop;
+            }
+        }
+        let expires = now + Duration::from_secs(TTL as u64);
+        self.peers.insert(node, (state, expires));
+    }
+
+    /// The peer to measure, if there is a session that might have been running longer than ours
+    fn session_to_join(&self) -> Option<(NodeId, SocketAddrV4)> {
+        self.peers
+            .values()
+            .filter(|(state, _)| state.session != self.state.session)
+            .filter(|(state, _)| !self.younger.contains(&state.session))
+            .filter_map(|(state, _)| Some((state.session, state.endpoint?)))
+            .min()
+    }
+
+    /// Whether a session measured at `ghost_offset` has been running longer than ours, which is
+    /// how every peer ends up in the same one
+    fn should_join(&self, session: NodeId, ghost_offset: i64) -> bool {
+        let older = ghost_offset - self.ghost_offset;
+        older > SESSION_EPS || (older.abs() <= SESSION_EPS && session < self.state.session)
+    }
+
+    fn join(&mut self, session: NodeId, ghost_offset: i64) {
+        let Some((state, _)) = self
+            .peers
+            .values()
+            .find(|(state, _)| state.session == session)
+        else {
+            return;
+        };
+        self.state.session = session;
+        self.state.timeline = state.timeline;
+        self.state.start_stop = state.start_stop;
+        self.ghost_offset = ghost_offset;
+        self.younger.clear();
+        self.changed = true;
+    }
+
+    fn forget_expired(&mut self) {
+        let now = Instant::now();
+        self.peers.retain(|_, (_, expires)| *expires > now);
+    }
+}
+
+/// A Link peer run on its own threads, which leaves the session when dropped
+pub struct Peer {
+    shared: Arc<Mutex<Shared>>,
+    stop: Arc<AtomicBool>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Peer {
+    /// Find the other peers on the LAN over multicast, starting a session of our own at `tempo`
+    /// with `beat` now until there's one to join
+    pub fn multicast(tempo: Float, beat: Float) -> anyhow::Result<Self> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, LINK_PORT))
+            .with_context(|| format!("binding Link port {LINK_PORT}"))?;
+        socket.join_multicast_v4(&MULTICAST_ADDR, &Ipv4Addr::UNSPECIFIED)?;
+        // connecting doesn't send anything, it just picks the interface the others can reach us on
+        let probe = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        probe.connect((MULTICAST_ADDR, LINK_PORT))?;
+        let IpAddr::V4(ip) = probe.local_addr()?.ip() else {
+            bail!("no IPv4 interface for Link");
+        };
+        Self::start(socket, ip, (MULTICAST_ADDR, LINK_PORT).into(), tempo, beat)
+    }
+
+    /// Like [`Self::multicast`], hearing from the others on `discovery` and announcing ourselves
+    /// to `announce`, with pings answered on `ip`
+    pub fn start(
+        discovery: UdpSocket,
+        ip: Ipv4Addr,
+        announce: SocketAddr,
+        tempo: Float,
+        beat: Float,
+    ) -> anyhow::Result<Self> {
+        discovery.set_read_timeout(Some(POLL_INTERVAL))?;
+        // pings are answered on a thread of their own, a late pong would skew the measurement
+        let pongs = UdpSocket::bind((ip, 0)).context("binding the Link pong socket")?;
+        pongs.set_read_timeout(Some(POLL_INTERVAL))?;
+        let SocketAddr::V4(endpoint) = pongs.local_addr()? else {
+            bail!("Link pong socket isn't IPv4");
+        };
+        let pings = UdpSocket::bind((ip, 0)).context("binding the Link ping socket")?;
+        pings.set_read_timeout(Some(PING_TIMEOUT))?;
+        let node = new_node_id();
+        let started = host_time();
+        let shared = Arc::new(Mutex::new(Shared {
+            node,
+            state: PeerState {
+                session: node,
+                timeline: Timeline {
+                    tempo,
+                    beat_origin: beat,
+                    time_origin: 0,
+                },
+                start_stop: None,
+                endpoint: Some(endpoint),
+            },
+            ghost_offset: -started,
+            peers: Default::default(),
+            younger: Default::default(),
+            changed: true,
+            changed_at: None,
+        }));
+        let stop: Arc<AtomicBool> = Default::default();
+
+        let threads = vec![
+            std::thread::spawn({
+                let shared = shared.clone();
+                let stop = stop.clone();
+                move || run(discovery, pings, announce, shared, stop)
+            }),
+            std::thread::spawn({
+                let shared = shared.clone();
+                let stop = stop.clone();
+                move || answer_pings(pongs, shared, stop)
+            }),
+        ];
+
+        Ok(Self {
+            shared,
+            stop,
+            threads,
+        })
+    }
+
+    fn shared(&self) -> std::sync::MutexGuard<'_, Shared> {
+        self.shared.lock().unwrap()
+    }
+
+    pub fn session(&self) -> NodeId {
+        self.shared().state.session
+    }
+
+    /// Other peers heard from, in any session
+    pub fn peers(&self) -> usize {
+        self.shared().peers.len()
+    }
+
+    pub fn timeline(&self) -> Timeline {
+        self.shared().state.timeline
+    }
+
+    pub fn start_stop(&self) -> Option<StartStop> {
+        self.shared().state.start_stop
+    }
+
+    /// The session's beat at `host` seconds on the [`load::now`] clock
+    pub fn beat_at(&self, host: Float) -> Float {
+        let shared = self.shared();
+        shared
+            .state
+            .timeline
+            .beat_at(shared.ghost_time(micro(host)))
+    }
+
+    /// Change the session's tempo from now, keeping the beats so far
+    pub fn set_tempo(&self, tempo: Float) {
+        let mut shared = self.shared();
+        let ghost = shared.ghost_time(host_time());
+        shared.state.timeline = shared.state.timeline.with_tempo(tempo, ghost);
+        shared.change();
+    }
+
+    /// Start or stop every peer that syncs start and stop, on the current beat
+    pub fn set_playing(&self, playing: bool) {
+        let mut shared = self.shared();
+        let ghost = shared.ghost_time(host_time());
+        shared.state.start_stop = Some(StartStop {
+            playing,
+            beat: shared.state.timeline.beat_at(ghost),
+            time: ghost,
+        });
+        shared.change();
+    }
+}
+
+impl Drop for Peer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        // the peer thread says goodbye on its way out
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn run(
+    discovery: UdpSocket,
+    pings: UdpSocket,
+    announce: SocketAddr,
+    shared: Arc<Mutex<Shared>>,
+    stop: Arc<AtomicBool>,
+) {
+    let node = shared.lock().unwrap().node;
+    let mut buf = [0; 512];
+    let mut next_broadcast = Instant::now();
+    let mut next_join = Instant::now();
+    while !stop.load(Ordering::Relaxed) {
+        let now = Instant::now();
+        let alive = {
+            let mut shared = shared.lock().unwrap();
+            shared.forget_expired();
+            (now >= next_broadcast || shared.changed).then(|| {
+                shared.changed = false;
+                Message::Alive(node, shared.state.clone())
+            })
+        };
+        if let Some(alive) = alive {
+            if let Err(e) = discovery.send_to(&alive.encode(), announce) {
+                warn!("Couldn't announce to Link peers: {e}");
+            }
+            next_broadcast = now + BROADCAST_PERIOD;
+        }
+
+        if let Ok((len, from)) = discovery.recv_from(&mut buf) {
+            match decode(&buf[..len]) {
+                // multicast comes back to us too
+                Ok(Message::Alive(from_node, _) | Message::Response(from_node, _))
+                    if from_node == node => {}
+                Ok(Message::Alive(from_node, state)) => {
+                    let mut shared = shared.lock().unwrap();
+                    shared.hear(from_node, state);
+                    let response = Message::Response(node, shared.state.clone());
+                    let _ = discovery.send_to(&response.encode(), from);
+                }
+                Ok(Message::Response(from_node, state)) => {
+                    shared.lock().unwrap().hear(from_node, state)
+                }
+                Ok(Message::ByeBye(from_node)) => {
+                    shared.lock().unwrap().peers.remove(&from_node);
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Bad Link packet from {from}: {e:#}"),
+            }
+        }
+
+        if now >= next_join {
+            let join = shared.lock().unwrap().session_to_join();
+            if let Some((session, endpoint)) = join {
+                match measure(&pings, endpoint) {
+                    Some(offset) => {
+                        let mut shared = shared.lock().unwrap();
+                        if shared.should_join(session, offset) {
+                            shared.join(session, offset);
+                            info!("Joined Link session {session:02x?}");
+                        } else {
+                            shared.younger.insert(session);
+                        }
+                    }
+                    None => {
+                        warn!("Couldn't measure Link peer {endpoint}");
+                        next_join = now + RETRY_PERIOD;
+                    }
+                }
+            }
+        }
+    }
+    let _ = discovery.send_to(&Message::ByeBye(node).encode(), announce);
+}
+
+fn answer_pings(socket: UdpSocket, shared: Arc<Mutex<Shared>>, stop: Arc<AtomicBool>) {
+    let mut buf = [0; 512];
+    while !stop.load(Ordering::Relaxed) {
+        let Ok((len, from)) = socket.recv_from(&mut buf) else {
+            continue;
+        };
+        if let Ok(Message::Ping(host_time)) = decode(&buf[..len]) {
+            let pong = {
+                let shared = shared.lock().unwrap();
+                Message::Pong {
+                    session: shared.state.session,
+                    host_time,
+                    ghost_time: shared.ghost_time(self::host_time()),
+                }
+            };
+            let _ = socket.send_to(&pong.encode(), from);
+        }
+    }
+}
+
+/// Ghost minus host time for the session `endpoint` is in, taking the ghost time a pong was sent
+/// at to be halfway through the round trip
+fn measure(socket: &UdpSocket, endpoint: SocketAddrV4) -> Option<i64> {
+    let mut buf = [0; 512];
+    let mut offsets = vec![];
+    for _ in 0..PINGS {
+        let sent = host_time();
+        socket
+            .send_to(&Message::Ping(sent).encode(), endpoint)
+            .ok()?;
+        // a pong to an earlier ping that timed out is skipped
+        while let Ok((len, _)) = socket.recv_from(&mut buf) {
+            if let Ok(Message::Pong {
+                host_time,
+                ghost_time,
+                ..
+            }) = decode(&buf[..len])
+            {
+                if host_time == sent {
+                    offsets.push(ghost_time - (sent + self::host_time()) / 2);
+                    break;
+                }
+            }
+        }
+    }
+    offsets.sort();
+    offsets.get(offsets.len() / 2).copied()
+}
+
+pub struct LinkPlugin;
+
+impl Plugin for LinkPlugin {
+    fn build(&self, app: &mut bevy::prelude::App) {
+        app.init_resource::<LinkSettings>()
+            .init_resource::<LinkPeer>()
+            .add_systems(Update, (update_peer, follow_session).chain());
+    }
+}
+
+#[derive(Resource, Clone, Debug, Default, PartialEq)]
+pub struct LinkSettings {
+    pub enabled: bool,
+    /// Start and stop with the session as well as following its tempo and beats
+    pub start_stop_sync: bool,
+}
+
+#[derive(Resource, Default)]
+pub struct LinkPeer {
+    pub peer: Option<Peer>,
+    pub error: Option<String>,
+    // what we last agreed with the session, to tell our changes from the session's
+    tempo: Float,
+    playing: Option<bool>,
+}
+
+fn update_peer(
+    settings: Res<LinkSettings>,
+    mut link: ResMut<LinkPeer>,
+    sound_control: Res<SoundControl>,
+) {
+    if !settings.is_changed() || settings.enabled == link.peer.is_some() {
+        return;
+    }
+    link.peer = None;
+    link.error = None;
+    if !settings.enabled {
+        return;
+    }
+    match Peer::multicast(tempo(), beats(sound_control.time())) {
+        Ok(peer) => {
+            info!("Link started");
+            link.peer = Some(peer);
+            link.tempo = tempo();
+            link.playing = None;
+        }
+        Err(e) => {
+            warn!("Link unavailable: {e:#}");
+            link.error = Some(format!("{e:#}"));
+        }
+    }
+}
+
+/// Keep the tempo, beats and maybe the transport in step with the session
+fn follow_session(
+    settings: Res<LinkSettings>,
+    mut link: ResMut<LinkPeer>,
+    mut sound_control: ResMut<SoundControl>,
+    sound_resources: Option<Res<SoundResources>>,
+) {
+    let link = &mut *link;
+    let Some(peer) = &link.peer else {
+        return;
+    };
+
+    if tempo() != link.tempo {
+        peer.set_tempo(tempo());
+    } else if peer.timeline().tempo != tempo() {
+        set_tempo(peer.timeline().tempo);
+    }
+    link.tempo = tempo();
+
+    if settings.start_stop_sync {
+        let playing = !sound_control.is_paused();
+        let changed_here = link.playing.is_some_and(|agreed| agreed != playing);
+        match peer.start_stop() {
+            Some(session) if !changed_here && session.playing != playing => {
+                if session.playing {
+                    sound_control.play();
+                } else {
+                    sound_control.pause();
+                }
+            }
+            Some(session) if session.playing == playing => {}
+            _ => peer.set_playing(playing),
+        }
+        link.playing = Some(!sound_control.is_paused());
+    } else {
+        link.playing = None;
+    }
+
+    // a loop wraps wherever it ends, keeping it in phase would undo it
+    if sound_control.is_paused() || sound_control.loop_region().is_some() {
+        return;
+    }
+    // what is rendered now is heard this much later
+    let latency = sound_resources.map_or(0.0, |resources| resources.output_latency());
+    let error = beat_error(peer.beat_at(load::now() + latency), clock::now());
+    // the beats move rather than the transport, so nothing else jumps
+    if error.abs() > PHASE_TOLERANCE {
+        shift_beats(error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+        time::{Duration, Instant},
+    };
+
+    use super::{decode, host_time, Message, Peer, PeerState, Shared, StartStop, Timeline};
+
+    fn state(session: u8) -> PeerState {
+        PeerState {
+            session: [session; 8],
+            timeline: Timeline {
+                tempo: 125.0,
+                beat_origin: 16.5,
+                time_origin: 1_000_000,
+            },
+            start_stop: Some(StartStop {
+                playing: true,
+                beat: 8.0,
+                time: 500_000,
+            }),
+            endpoint: Some(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 4321)),
+        }
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let messages = [
+            Message::Alive(*b"abcdefgh", state(1)),
+            Message::Response(*b"abcdefgh", state(2)),
+            Message::ByeBye(*b"abcdefgh"),
+            Message::Ping(-5),
+            Message::Pong {
+                session: [3; 8],
+                host_time: 7,
+                ghost_time: 1 << 40,
+            },
+        ];
+        for message in messages {
+            assert_eq!(decode(&message.encode()).unwrap(), message);
+        }
+
+        let bytes = Message::ByeBye(*b"abcdefgh").encode();
+        assert_eq!(bytes, b"_asdp_v\x01\x03\x00\x00\x00abcdefgh");
+        // 125bpm is 480,000 microseconds a beat
+        let mut alive = Message::Alive([0; 8], state(1)).encode();
+        assert_eq!(&alive[20..36], b"tmln\0\0\0\x18\0\0\0\0\0\x07\x53\x00");
+        // entries we don't know are skipped
+        alive.extend(b"xtra\0\0\0\x02ab");
+        assert_eq!(decode(&alive).unwrap(), Message::Alive([0; 8], state(1)));
+        assert!(decode(&alive[..alive.len() - 1]).is_err());
+        assert!(decode(b"_asdp_v\x01\x01").is_err());
+    }
+
+    #[test]
+    fn test_timeline() {
+        let timeline = Timeline {
+            tempo: 120.0,
+            beat_origin: 4.0,
+            time_origin: 1_000_000,
+        };
+        assert_eq!(timeline.beat_at(2_000_000), 6.0);
+        let faster = timeline.with_tempo(240.0, 2_000_000);
+        assert_eq!(faster.beat_at(2_000_000), 6.0);
+        assert_eq!(faster.beat_at(3_000_000), 10.0);
+    }
+
+    fn wait_for(what: &str, mut done: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done() {
+            assert!(Instant::now() < deadline, "timed out waiting for {what}");
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn test_join_rule() {
+        let shared = Shared {
+            node: [5; 8],
+            state: state(5),
+            ghost_offset: -2_000_000,
+            peers: Default::default(),
+            younger: Default::default(),
+            changed: false,
+            changed_at: None,
+        };
+        // the session that has been running longest wins, whatever its id
+        assert!(shared.should_join([9; 8], 0));
+        assert!(!shared.should_join([1; 8], -4_000_000));
+        // sessions about as old as each other go by id
+        assert!(shared.should_join([1; 8], -2_100_000));
+        assert!(!shared.should_join([9; 8], -1_900_000));
+    }
+
+    #[test]
+    fn test_stand_in_peer() {
+        // another app's peer, played by hand on the loopback interface
+        let other = UdpSocket::bind("127.0.0.1:0").unwrap();
+        other
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let discovery = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let us = discovery.local_addr().unwrap();
+        let peer = Peer::start(
+            discovery,
+            Ipv4Addr::LOCALHOST,
+            other.local_addr().unwrap(),
+            100.0,
+            0.0,
+        )
+        .unwrap();
+
+        let mut buf = [0; 512];
+        let (len, _) = other.recv_from(&mut buf).unwrap();
+        let Ok(Message::Alive(_, ours)) = decode(&buf[..len]) else {
+            panic!("expected an alive message");
+        };
+        assert_eq!(ours.timeline.tempo, 100.0);
+
+        // a session that started 10s before the host clock did
+        let SocketAddr::V4(endpoint) = other.local_addr().unwrap() else {
+            unreachable!()
+        };
+        let theirs = PeerState {
+            session: [0; 8],
+            timeline: Timeline {
+                tempo: 150.0,
+                beat_origin: 0.0,
+                time_origin: 0,
+            },
+            start_stop: None,
+            endpoint: Some(endpoint),
+        };
+        other
+            .send_to(&Message::Alive([0; 8], theirs.clone()).encode(), us)
+            .unwrap();
+        let offset = 10_000_000;
+        let mut responded = false;
+        while peer.session() != [0; 8] {
+            let (len, from) = other.recv_from(&mut buf).unwrap();
+            match decode(&buf[..len]).unwrap() {
+                Message::Ping(host_time) => {
+                    let pong = Message::Pong {
+                        session: [0; 8],
+                        host_time,
+                        ghost_time: super::host_time() + offset,
+                    };
+                    other.send_to(&pong.encode(), from).unwrap();
+                }
+                Message::Response(_, state) => {
+                    responded = true;
+                    assert_eq!(state.session, ours.session);
+                }
+                _ => {}
+            }
+        }
+        assert!(responded);
+        assert_eq!(peer.timeline().tempo, 150.0);
+        // our beat now is theirs, give or take the loopback round trip
+        let expected = theirs.timeline.beat_at(host_time() + offset);
+        let beat = peer.beat_at(crate::sound::load::now());
+        assert!((beat - expected).abs() < 0.01, "{beat} vs {expected}");
+    }
+
+    #[test]
+    fn test_two_peers() {
+        let a = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let (a_addr, b_addr) = (a.local_addr().unwrap(), b.local_addr().unwrap());
+        let a = Peer::start(a, Ipv4Addr::LOCALHOST, b_addr, 120.0, 0.0).unwrap();
+        let b = Peer::start(b, Ipv4Addr::LOCALHOST, a_addr, 96.0, 3.0).unwrap();
+
+        wait_for("the peers to share a session", || {
+            a.session() == b.session()
+        });
+        let tempo = a.timeline().tempo;
+        assert!(tempo == 120.0 || tempo == 96.0);
+        wait_for("the tempo to settle", || b.timeline().tempo == tempo);
+
+        // a tempo change on either side reaches the other, one that fits the wire's whole
+        // microseconds a beat exactly
+        b.set_tempo(160.0);
+        wait_for("the new tempo", || a.timeline().tempo == 160.0);
+        let now = crate::sound::load::now();
+        assert!((a.beat_at(now) - b.beat_at(now)).abs() < 0.01);
+
+        a.set_playing(true);
+        wait_for("the start", || {
+            b.start_stop().is_some_and(|start_stop| start_stop.playing)
+        });
+
+        drop(a);
+        wait_for("the goodbye", || b.peers() == 0);
+    }
+}
@@ -1,72 +1,858 @@
-pub mod editor;
-mod fft;
-pub mod lang;
-pub mod math;
-mod sound;
-pub mod visuals;
+use std::{process::ExitCode, time::Duration};
 
-use bevy::prelude::*;
-use math::*;
+use bevy::{app::ScheduleRunnerPlugin, log::LogPlugin, prelude::*};
+#[cfg(not(target_arch = "wasm32"))]
+use bevy_funk::watch;
+use bevy_funk::{cli, lang, link, math::*, midi, osc, session, sound, visuals};
+use clap::Parser;
 
 use bevy_egui::{
     egui::{self, CollapsingHeader, DragValue},
     EguiContexts, EguiPlugin,
 };
-use visuals::{VisualsControls, VisualsPlugin};
+use visuals::{VisualsControls, VisualsField, VisualsPlugin};
 
-fn main() {
-    App::new()
-        .add_plugins(DefaultPlugins)
-        .add_plugins(EguiPlugin)
+fn main() -> ExitCode {
+    match run(cli::Cli::parse()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {e:#}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(cli: cli::Cli) -> anyhow::Result<()> {
+    if cli.list_devices {
+        println!("{}", cli::device_list());
+        return Ok(());
+    }
+    let opened = cli.file.as_deref().map(cli::Opened::read).transpose()?;
+    if cli.watch && matches!(opened, Some(cli::Opened::Session(_))) {
+        anyhow::bail!("only scripts can be watched, not sessions");
+    }
+    if cli.render.is_some() {
+        return cli.render(opened.as_ref(), example_sound());
+    }
+
+    let mut app = App::new();
+    if cli.headless {
+        app.add_plugins(MinimalPlugins.set(ScheduleRunnerPlugin::run_loop(
+            Duration::from_secs_f64(1.0 / 60.0),
+        )))
+        .add_plugins(LogPlugin::default())
+        .init_resource::<VisualsControls>();
+    } else {
+        app.add_plugins(DefaultPlugins)
+            .add_plugins(EguiPlugin)
+            .add_plugins(VisualsPlugin)
+            .add_systems(Update, ui);
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    if let (true, Some(path)) = (cli.watch, &cli.file) {
+        app.insert_resource(watch::ScriptWatcher::new(path)?)
+            .add_plugins(watch::WatchPlugin);
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    if let Some(path) = &cli.plugin {
+        app.insert_resource(sound::dylib::DylibWatcher::new(path)?)
+            .add_plugins(sound::dylib::DylibPlugin);
+    }
+    app.insert_resource(cli.output_settings()?)
+        .insert_resource(cli)
         .add_plugins(sound::SoundPlugin)
-        .add_plugins(VisualsPlugin)
-        .add_systems(Startup, setup)
-        .add_systems(Update, ui)
+        .add_plugins(midi::MidiPlugin)
+        .add_plugins(lang::LangPlugin)
+        .add_plugins(osc::OscPlugin)
+        .add_plugins(link::LinkPlugin)
+        .add_plugins(session::SessionPlugin)
+        .add_systems(Startup, (setup, cli::apply).chain())
         .run();
+    Ok(())
+}
+
+/// Load history as a line, with the deadline (100%) marked in red
+fn load_graph(ui: &mut egui::Ui, history: &std::collections::VecDeque<sound::Float>) {
+    let (rect, _) =
+        ui.allocate_exact_size(egui::vec2(ui.available_width(), 40.0), egui::Sense::hover());
+    let painter = ui.painter_at(rect);
+    painter.rect_filled(rect, 2.0, ui.visuals().extreme_bg_color);
+    // scale up when over the deadline, so the peaks stay on screen
+    let top = history.iter().copied().fold(1.0, sound::Float::max);
+    let y = |load: sound::Float| rect.bottom() - rect.height() * (load / top) as f32;
+    let dx = rect.width() / (sound::load::HISTORY_LEN - 1) as f32;
+    let points = history
+        .iter()
+        .enumerate()
+        .map(|(i, load)| egui::pos2(rect.left() + i as f32 * dx, y(*load)))
+        .collect();
+    painter.hline(
+        rect.x_range(),
+        y(1.0),
+        egui::Stroke::new(1.0, egui::Color32::RED),
+    );
+    painter.add(egui::Shape::line(
+        points,
+        egui::Stroke::new(1.0, ui.visuals().text_color()),
+    ));
+}
+
+/// Peak level bar, red once it clips
+fn meter(ui: &mut egui::Ui, level: f32) {
+    let (rect, _) =
+        ui.allocate_exact_size(egui::vec2(ui.available_width(), 6.0), egui::Sense::hover());
+    let painter = ui.painter_at(rect);
+    painter.rect_filled(rect, 1.0, ui.visuals().extreme_bg_color);
+    let mut bar = rect;
+    bar.set_width(rect.width() * level.min(1.0));
+    let color = if level >= 1.0 {
+        egui::Color32::RED
+    } else {
+        egui::Color32::GREEN
+    };
+    painter.rect_filled(bar, 1.0, color);
 }
+
+/// A strip's meter, gain and pan. `level` is what the meter showed last frame
+fn strip_controls(ui: &mut egui::Ui, strip: &sound::mixer::Strip, level: &mut f32) {
+    // fall back slowly so peaks stay visible for a moment
+    *level = (strip.take_peak() as f32).max(*level * 0.9);
+    meter(ui, *level);
+    let mut gain = strip.gain();
+    if ui
+        .add(egui::Slider::new(&mut gain, 0.0..=2.0).text("Gain"))
+        .changed()
+    {
+        strip.set_gain(gain);
+    }
+    let mut pan = strip.pan();
+    if ui
+        .add(egui::Slider::new(&mut pan, -1.0..=1.0).text("Pan"))
+        .changed()
+    {
+        strip.set_pan(pan);
+    }
+}
+
+/// New sends, or sends changing to or from feedback, as (from, to, level, feedback)
+type SendChanges = Vec<(String, String, sound::Float, bool)>;
+
+/// A send slider for every bus, the send is only added once its slider moves.
+/// Buses also get a toggle for feedback sends
+fn send_controls(
+    ui: &mut egui::Ui,
+    from: &str,
+    sends: &[sound::mixer::AuxSend],
+    buses: &[String],
+    from_bus: bool,
+    changes: &mut SendChanges,
+) {
+    for to in buses {
+        let send = sends.iter().find(|send| &send.to == to);
+        ui.horizontal(|ui| {
+            let mut level = send.map_or(0.0, |send| send.level.get());
+            let mut feedback = send.is_some_and(|send| send.feedback);
+            let slider = ui.add(egui::Slider::new(&mut level, 0.0..=1.0).text(format!("to {to}")));
+            let toggled = from_bus
+                && ui
+                    .toggle_value(&mut feedback, "FB")
+                    .on_hover_text("Feedback send, a block late so it can loop back")
+                    .changed();
+            match send {
+                Some(send) if !toggled && slider.changed() => send.level.set(level),
+                Some(_) if !toggled => {}
+                _ if slider.changed() || toggled => {
+                    changes.push((from.into(), to.clone(), level, feedback));
+                }
+                _ => {}
+            }
+        });
+    }
+}
+
+/// Tracks on the left, then buses by how many buses come before them, then the master.
+/// Feedback sends are dashed
+fn bus_graph(ui: &mut egui::Ui, list: &sound::mixer::TrackList) {
+    let buses: Vec<_> = list.buses().collect();
+    let index = |name: &str| buses.iter().position(|bus| bus.name == name);
+    let mut depth = vec![0; buses.len()];
+    for b in list.bus_order() {
+        for send in buses[b].sends.iter().filter(|send| !send.feedback) {
+            if let Some(to) = index(&send.to) {
+                depth[to] = depth[to].max(depth[b] + 1);
+            }
+        }
+    }
+    let columns = depth.iter().max().map_or(0, |d| d + 1) + 2;
+    let mut rows = vec![0; columns];
+    let mut place = |column: usize| {
+        rows[column] += 1;
+        (column, rows[column] - 1)
+    };
+    let track_cells: Vec<_> = list.iter().map(|_| place(0)).collect();
+    let bus_cells: Vec<_> = depth.iter().map(|d| place(d + 1)).collect();
+    let master_cell = place(columns - 1);
+
+    let row_height = 22.0;
+    let height = rows.iter().max().copied().unwrap_or(1) as f32 * row_height;
+    let (rect, _) = ui.allocate_exact_size(
+        egui::vec2(ui.available_width(), height),
+        egui::Sense::hover(),
+    );
+    let painter = ui.painter_at(rect);
+    painter.rect_filled(rect, 2.0, ui.visuals().extreme_bg_color);
+    let column_width = rect.width() / columns as f32;
+    let pos = |(column, row): (usize, usize)| {
+        egui::pos2(
+            rect.left() + (column as f32 + 0.5) * column_width,
+            rect.top() + (row as f32 + 0.5) * row_height,
+        )
+    };
+    let master = pos(master_cell);
+    let send_stroke = egui::Stroke::new(1.0, ui.visuals().weak_text_color());
+    let feedback_stroke = egui::Stroke::new(1.0, egui::Color32::LIGHT_RED);
+
+    // lines first, so the names are drawn over them
+    let edges = |from: egui::Pos2, sends: &[sound::mixer::AuxSend]| {
+        painter.line_segment([from, master], send_stroke);
+        for send in sends {
+            let Some(to) = index(&send.to) else {
+                continue;
+            };
+            let to = pos(bus_cells[to]);
+            if !send.feedback {
+                painter.line_segment([from, to], send_stroke);
+            } else if from == to {
+                painter.circle_stroke(from - egui::vec2(0.0, 8.0), 6.0, feedback_stroke);
+            } else {
+                painter.extend(egui::Shape::dashed_line(
+                    &[from, to],
+                    feedback_stroke,
+                    4.0,
+                    3.0,
+                ));
+            }
+        }
+    };
+    for (track, cell) in list.iter().zip(&track_cells) {
+        edges(pos(*cell), &track.sends);
+    }
+    for (bus, cell) in buses.iter().zip(&bus_cells) {
+        edges(pos(*cell), &bus.sends);
+    }
+
+    let node = |cell: (usize, usize), name: &str, muted: bool| {
+        let color = if muted {
+            ui.visuals().weak_text_color()
+        } else {
+            ui.visuals().strong_text_color()
+        };
+        // filled in once the text's size is known
+        let background = painter.add(egui::Shape::Noop);
+        let text = painter.text(
+            pos(cell),
+            egui::Align2::CENTER_CENTER,
+            name,
+            egui::FontId::proportional(12.0),
+            color,
+        );
+        painter.set(
+            background,
+            egui::Shape::rect_filled(text.expand(2.0), 2.0, ui.visuals().faint_bg_color),
+        );
+    };
+    for (track, cell) in list.iter().zip(&track_cells) {
+        node(*cell, &track.name, track.strip.muted());
+    }
+    for (bus, cell) in buses.iter().zip(&bus_cells) {
+        node(*cell, &bus.name, bus.strip.muted());
+    }
+    node(master_cell, "master", false);
+}
+
+#[derive(Default)]
+struct MixerUi {
+    // meter levels by track or bus name
+    meters: std::collections::HashMap<String, f32>,
+    // why the last send couldn't be made
+    error: Option<String>,
+}
+
+struct TransportUi {
+    // bar to jump to, counting from 1 like the display
+    bar: sound::Float,
+    // loop bounds as typed, in bars counting from 1 or in seconds
+    in_bars: bool,
+    loop_start: sound::Float,
+    loop_end: sound::Float,
+}
+
+impl Default for TransportUi {
+    fn default() -> Self {
+        Self {
+            bar: 1.0,
+            in_bars: true,
+            loop_start: 1.0,
+            loop_end: 5.0,
+        }
+    }
+}
+
+// bevy systems take their resources as arguments
+#[allow(clippy::too_many_arguments)]
 fn ui(
     mut egui_context: EguiContexts,
-    time: Res<Time>,
     mut visual_controls: ResMut<VisualsControls>,
+    mut sound_control: ResMut<sound::SoundControl>,
+    mut output_settings: ResMut<sound::OutputSettings>,
+    sound_resources: Option<Res<sound::SoundResources>>,
+    mut output_devices: Local<Option<Vec<sound::OutputDevice>>>,
+    (mut midi_learn, mut midi_sync_settings, midi_sync): (
+        ResMut<midi::MidiLearn>,
+        ResMut<midi::sync::MidiSyncSettings>,
+        Res<midi::sync::MidiSync>,
+    ),
+    mut osc_settings: ResMut<osc::OscSettings>,
+    osc_server: Res<osc::OscServer>,
+    mut session_file: ResMut<session::SessionFile>,
+    mut save_session: EventWriter<session::SaveSession>,
+    mut load_session: EventWriter<session::LoadSession>,
+    (lang_status, mut lang_settings): (Res<lang::LangStatus>, ResMut<lang::LangSettings>),
+    mut dsp_load: ResMut<sound::load::DspLoad>,
+    (mut mixer_ui, mut transport_ui): (Local<MixerUi>, Local<TransportUi>),
+    (mut link_settings, link_peer): (ResMut<link::LinkSettings>, Res<link::LinkPeer>),
 ) {
+    if let Some(message) = sound_control.last_panic().map(str::to_owned) {
+        egui::TopBottomPanel::bottom("status bar").show(egui_context.ctx_mut(), |ui| {
+            ui.horizontal(|ui| {
+                ui.colored_label(
+                    egui::Color32::RED,
+                    format!("Sound function panicked: {message}"),
+                );
+                if ui.small_button("Dismiss").clicked() {
+                    sound_control.clear_panic();
+                }
+            });
+        });
+    }
+
     egui::SidePanel::left("controls panel").show(egui_context.ctx_mut(), |ui| {
         CollapsingHeader::new("Sound")
             .default_open(true)
             .show(ui, |ui| {
-                ui.label(format!("Time: {:.2}", time.elapsed().as_secs_f32()));
+                let time = sound_control.time();
+                let bars = sound::bars(time);
+                let beat = (bars.fract() * sound::beats_per_bar() as sound::Float).floor();
+                ui.label(format!(
+                    "Time: {time:.2}, bar {}.{}",
+                    bars.floor() + 1.0,
+                    beat + 1.0
+                ));
+                // the timeline grows a minute at a time as the piece goes on
+                let loop_end = sound_control.loop_region().map_or(0.0, |(_, end)| end);
+                let length = (time.max(loop_end) / 60.0).floor() * 60.0 + 60.0;
+                let mut position = time;
+                if ui
+                    .add(egui::Slider::new(&mut position, 0.0..=length).show_value(false))
+                    .on_hover_text("Drag to scrub through the piece")
+                    .changed()
+                {
+                    sound_control.seek(position);
+                }
+                let transport = &mut *transport_ui;
+                ui.horizontal(|ui| {
+                    if ui.button("Go to bar").clicked() {
+                        sound_control.seek_bar(transport.bar - 1.0);
+                    }
+                    ui.add(DragValue::new(&mut transport.bar).clamp_range(1.0..=9999.0));
+                    if ui.button("Go to start").clicked() {
+                        sound_control.seek(0.0);
+                    }
+                });
+                ui.horizontal(|ui| {
+                    let mut looping = sound_control.loop_region().is_some();
+                    let mut changed = ui.checkbox(&mut looping, "Loop from").changed();
+                    let min = if transport.in_bars { 1.0 } else { 0.0 };
+                    changed |= ui
+                        .add(DragValue::new(&mut transport.loop_start).clamp_range(min..=9999.0))
+                        .changed();
+                    ui.label("to");
+                    changed |= ui
+                        .add(DragValue::new(&mut transport.loop_end).clamp_range(min..=9999.0))
+                        .changed();
+                    let in_bars = transport.in_bars;
+                    ui.selectable_value(&mut transport.in_bars, true, "bars");
+                    ui.selectable_value(&mut transport.in_bars, false, "seconds");
+                    if transport.in_bars != in_bars {
+                        // keep the same region, just typed in the other units
+                        let to_bars = transport.in_bars;
+                        let convert = |bound: sound::Float| {
+                            if to_bars {
+                                sound::bars(bound) + 1.0
+                            } else {
+                                sound::bar_time(bound - 1.0)
+                            }
+                        };
+                        transport.loop_start = convert(transport.loop_start);
+                        transport.loop_end = convert(transport.loop_end);
+                    }
+                    if changed {
+                        let region = looping.then_some((transport.loop_start, transport.loop_end));
+                        if transport.in_bars {
+                            sound_control
+                                .set_loop_bars(region.map(|(start, end)| (start - 1.0, end - 1.0)));
+                        } else {
+                            sound_control.set_loop(region);
+                        }
+                    }
+                });
+                if let Some(error) = &lang_status.error {
+                    ui.colored_label(egui::Color32::RED, error);
+                }
                 ui.horizontal(|ui| {
                     if ui.button("Play").clicked() {
-                        todo!()
+                        sound_control.play();
                     }
                     if ui.button("Pause").clicked() {
-                        todo!()
+                        sound_control.pause();
+                    }
+                    if ui
+                        .button("Stop at bar")
+                        .on_hover_text("Pause on the next downbeat")
+                        .clicked()
+                    {
+                        sound_control
+                            .schedule(sound::events::When::Bar, sound::events::Action::Stop);
                     }
                 });
+                ui.horizontal(|ui| {
+                    ui.label("Tempo:");
+                    let mut bpm = sound::tempo();
+                    if ui
+                        .add(DragValue::new(&mut bpm).clamp_range(1.0..=999.0))
+                        .changed()
+                    {
+                        sound::set_tempo(bpm);
+                    }
+                    ui.label("Beats per bar:");
+                    let mut beats = sound::beats_per_bar();
+                    if ui
+                        .add(DragValue::new(&mut beats).clamp_range(1..=32))
+                        .changed()
+                    {
+                        sound::set_beats_per_bar(beats);
+                    }
+                });
+                ui.horizontal(|ui| {
+                    use sound::events::When;
+                    ui.label("New code starts:");
+                    let apply_on = &mut lang_settings.apply_on;
+                    ui.selectable_value(apply_on, When::Now, "Now");
+                    ui.selectable_value(apply_on, When::Beat, "On the beat");
+                    ui.selectable_value(apply_on, When::Bar, "On the bar");
+                });
                 if ui.button("Restart audio server").clicked() {
-                    todo!()
+                    sound_control.restart();
+                }
+
+                CollapsingHeader::new("Mixer")
+                    .default_open(true)
+                    .show(ui, |ui| {
+                        let MixerUi { meters, error } = &mut *mixer_ui;
+                        let list = sound_control.tracks();
+                        let buses: Vec<_> = list.buses().map(|bus| bus.name.clone()).collect();
+                        let mut changes = SendChanges::new();
+                        let mut removed_buses = vec![];
+                        for track in list.iter() {
+                            let strip = &track.strip;
+                            ui.group(|ui| {
+                                ui.horizontal(|ui| {
+                                    ui.label(&track.name);
+                                    let mut muted = strip.muted();
+                                    if ui.toggle_value(&mut muted, "M").changed() {
+                                        strip.set_muted(muted);
+                                    }
+                                    let mut soloed = strip.soloed();
+                                    if ui.toggle_value(&mut soloed, "S").changed() {
+                                        strip.set_soloed(soloed);
+                                    }
+                                    if track.name != sound::mixer::MAIN_TRACK
+                                        && ui.small_button("x").on_hover_text("Remove").clicked()
+                                    {
+                                        sound_control.remove_track(&track.name);
+                                    }
+                                });
+                                let level = meters.entry(track.name.clone()).or_default();
+                                strip_controls(ui, strip, level);
+                                send_controls(
+                                    ui,
+                                    &track.name,
+                                    &track.sends,
+                                    &buses,
+                                    false,
+                                    &mut changes,
+                                );
+                            });
+                        }
+                        for bus in list.buses() {
+                            let strip = &bus.strip;
+                            ui.group(|ui| {
+                                ui.horizontal(|ui| {
+                                    ui.label(format!("{} (bus)", bus.name));
+                                    let mut muted = strip.muted();
+                                    if ui.toggle_value(&mut muted, "M").changed() {
+                                        strip.set_muted(muted);
+                                    }
+                                    let mut soloed = strip.soloed();
+                                    if ui.toggle_value(&mut soloed, "S").changed() {
+                                        strip.set_soloed(soloed);
+                                    }
+                                    if ui.small_button("x").on_hover_text("Remove").clicked() {
+                                        removed_buses.push(bus.name.clone());
+                                    }
+                                });
+                                let level = meters.entry(bus.name.clone()).or_default();
+                                strip_controls(ui, strip, level);
+                                send_controls(
+                                    ui,
+                                    &bus.name,
+                                    &bus.sends,
+                                    &buses,
+                                    true,
+                                    &mut changes,
+                                );
+                            });
+                        }
+                        ui.collapsing("Routing", |ui| bus_graph(ui, list));
+                        meters
+                            .retain(|name, _| list.get(name).is_some() || list.bus(name).is_some());
+
+                        for (from, to, level, feedback) in changes {
+                            *error = sound_control
+                                .set_send(&from, &to, level, feedback)
+                                .err()
+                                .map(|e| e.to_string());
+                        }
+                        for name in removed_buses {
+                            sound_control.remove_bus(&name);
+                        }
+                        if let Some(message) = error {
+                            ui.colored_label(egui::Color32::RED, message.as_str());
+                        }
+                    });
+
+                CollapsingHeader::new("DSP load")
+                    .default_open(true)
+                    .show(ui, |ui| {
+                        let color = if dsp_load.load > 0.8 {
+                            egui::Color32::RED
+                        } else {
+                            ui.visuals().text_color()
+                        };
+                        ui.colored_label(color, format!("Load: {:.0}%", dsp_load.load * 100.0));
+                        ui.label(format!(
+                            "Worst block: {:.2}ms of {:.2}ms",
+                            dsp_load.worst_block * 1000.0,
+                            dsp_load.deadline * 1000.0
+                        ));
+                        ui.horizontal(|ui| {
+                            ui.label(format!("Xruns: {}", dsp_load.xruns));
+                            if ui.small_button("Reset").clicked() {
+                                dsp_load.reset();
+                            }
+                        });
+                        load_graph(ui, &dsp_load.history);
+                    });
+
+                ui.collapsing("Safety", |ui| {
+                    let mut enabled = sound::safety::enabled();
+                    if ui.checkbox(&mut enabled, "Limiter").changed() {
+                        sound::safety::set_enabled(enabled);
+                    }
+                    ui.horizontal(|ui| {
+                        ui.label("Ceiling (dB):");
+                        let mut ceiling = sound::safety::ceiling_db();
+                        if ui
+                            .add(
+                                DragValue::new(&mut ceiling)
+                                    .clamp_range(-24.0..=0.0)
+                                    .speed(0.1),
+                            )
+                            .changed()
+                        {
+                            sound::safety::set_ceiling_db(ceiling);
+                        }
+                    });
+                    let telemetry = sound::safety::telemetry();
+                    ui.label(format!("NaN samples: {}", telemetry.nan_samples));
+                    ui.label(format!("Clipped samples: {}", telemetry.clipped_samples));
+                    if ui.button("Reset").clicked() {
+                        sound::safety::reset_telemetry();
+                    }
+                });
+
+                ui.collapsing("Output", |ui| {
+                    let devices = output_devices.get_or_insert_with(sound::output_devices);
+                    let mut settings = output_settings.clone();
+
+                    let selected = devices
+                        .iter()
+                        .find(|d| d.id == settings.sink_id)
+                        .map_or("Default", |d| d.label.as_str());
+                    egui::ComboBox::from_label("Device")
+                        .selected_text(selected)
+                        .show_ui(ui, |ui| {
+                            ui.selectable_value(&mut settings.sink_id, "".into(), "Default");
+                            for device in devices.iter() {
+                                ui.selectable_value(
+                                    &mut settings.sink_id,
+                                    device.id.clone(),
+                                    &device.label,
+                                );
+                            }
+                        });
+                    if ui.button("Refresh devices").clicked() {
+                        *output_devices = None;
+                    }
+
+                    egui::ComboBox::from_label("Latency")
+                        .selected_text(settings.latency.to_string())
+                        .show_ui(ui, |ui| {
+                            for latency in [
+                                sound::Latency::Interactive,
+                                sound::Latency::Balanced,
+                                sound::Latency::Playback,
+                            ] {
+                                ui.selectable_value(
+                                    &mut settings.latency,
+                                    latency,
+                                    latency.to_string(),
+                                );
+                            }
+                            if !matches!(settings.latency, sound::Latency::BufferSize(_)) {
+                                ui.selectable_value(
+                                    &mut settings.latency,
+                                    sound::Latency::BufferSize(512),
+                                    "Buffer size",
+                                );
+                            }
+                        });
+                    if let sound::Latency::BufferSize(frames) = &mut settings.latency {
+                        // the backend rounds up to a power of two anyway
+                        egui::ComboBox::from_label("Buffer size")
+                            .selected_text(frames.to_string())
+                            .show_ui(ui, |ui| {
+                                for size in (7..=14).map(|p| 1 << p) {
+                                    ui.selectable_value(frames, size, size.to_string());
+                                }
+                            });
+                    }
+
+                    let rate_text = |rate: Option<u32>| match rate {
+                        Some(rate) => format!("{rate} Hz"),
+                        None => "Device default".into(),
+                    };
+                    egui::ComboBox::from_label("Sample rate")
+                        .selected_text(rate_text(settings.sample_rate))
+                        .show_ui(ui, |ui| {
+                            ui.selectable_value(&mut settings.sample_rate, None, rate_text(None));
+                            for rate in sound::SAMPLE_RATES {
+                                ui.selectable_value(
+                                    &mut settings.sample_rate,
+                                    Some(rate),
+                                    rate_text(Some(rate)),
+                                );
+                            }
+                        });
+
+                    egui::ComboBox::from_label("Channels")
+                        .selected_text(settings.channels.to_string())
+                        .show_ui(ui, |ui| {
+                            for channels in [1, 2, 4, 6, 8, 16] {
+                                ui.selectable_value(
+                                    &mut settings.channels,
+                                    channels,
+                                    channels.to_string(),
+                                );
+                            }
+                        });
+
+                    if let Some(resources) = &sound_resources {
+                        ui.label(format!(
+                            "Current channels: {} (device max {})",
+                            resources.channels, resources.max_channels
+                        ));
+                        ui.label(format!("Current sample rate: {} Hz", sound::sample_rate()));
+                        ui.label(format!("Current latency: {}", resources.latency));
+                        ui.label(format!(
+                            "Measured latency: {:.1} ms",
+                            resources.output_latency() * 1000.0
+                        ));
+                    }
+
+                    // hot-switch by restarting the audio server with the new settings
+                    if settings.sink_id != output_settings.sink_id
+                        || settings.latency != output_settings.latency
+                        || settings.sample_rate != output_settings.sample_rate
+                        || settings.channels != output_settings.channels
+                    {
+                        *output_settings = settings;
+                        sound_control.restart();
+                    }
+                });
+            });
+
+        ui.collapsing("Session", |ui| {
+            ui.text_edit_singleline(&mut session_file.path);
+            ui.horizontal(|ui| {
+                if ui.button("Save").clicked() {
+                    save_session.send(session::SaveSession(session_file.path.clone().into()));
+                }
+                if ui.button("Load").clicked() {
+                    load_session.send(session::LoadSession::new(session_file.path.clone().into()));
                 }
             });
+            if let Some(status) = &session_file.status {
+                ui.label(status);
+            }
+        });
+
+        ui.collapsing("Parameters", |ui| {
+            for (name, param) in sound::params::params() {
+                let spec = param.spec();
+                let mut value = param.target();
+                if ui
+                    .add(egui::Slider::new(&mut value, spec.min..=spec.max).text(name))
+                    .changed()
+                {
+                    param.set(value);
+                }
+            }
+        });
+
+        ui.collapsing("MIDI", |ui| {
+            let held: Vec<_> = midi::held_notes().map(|n| n.to_string()).collect();
+            ui.label(format!("Held notes: {}", held.join(" ")));
+            ui.label(format!("Virtual port: {}", midi::VIRTUAL_PORT_NAME));
+
+            ui.separator();
+            let mut settings = midi_sync_settings.clone();
+            ui.horizontal(|ui| {
+                ui.label("Clock:");
+                ui.selectable_value(&mut settings.mode, midi::sync::SyncMode::Off, "Off");
+                ui.selectable_value(&mut settings.mode, midi::sync::SyncMode::Master, "Send");
+                ui.selectable_value(&mut settings.mode, midi::sync::SyncMode::Follow, "Follow");
+            });
+            match settings.mode {
+                midi::sync::SyncMode::Master => {
+                    let selected = if settings.output.is_empty() {
+                        midi::sync::CLOCK_PORT_NAME
+                    } else {
+                        &settings.output
+                    }
+                    .to_string();
+                    egui::ComboBox::from_label("Clock port")
+                        .selected_text(selected)
+                        .show_ui(ui, |ui| {
+                            ui.selectable_value(
+                                &mut settings.output,
+                                "".into(),
+                                midi::sync::CLOCK_PORT_NAME,
+                            );
+                            for output in &midi_sync.outputs {
+                                ui.selectable_value(&mut settings.output, output.clone(), output);
+                            }
+                        });
+                    ui.checkbox(&mut settings.send_mtc, "Send MTC");
+                    match &midi_sync.error {
+                        Some(error) => ui.label(error),
+                        None => ui.label("Sending clock"),
+                    };
+                }
+                midi::sync::SyncMode::Follow => {
+                    match midi_sync.followed_tempo() {
+                        Some(tempo) => ui.label(format!("Following {tempo:.1} bpm")),
+                        None => ui.label("Waiting for clock"),
+                    };
+                }
+                midi::sync::SyncMode::Off => {}
+            }
+            if settings != *midi_sync_settings {
+                *midi_sync_settings = settings;
+            }
+        });
+
+        ui.collapsing("OSC", |ui| {
+            let mut settings = osc_settings.clone();
+            ui.horizontal(|ui| {
+                ui.label("Port:");
+                ui.add(DragValue::new(&mut settings.port));
+            });
+            ui.checkbox(&mut settings.allow_remote, "Allow remote connections");
+            if settings != *osc_settings {
+                *osc_settings = settings;
+            }
+            match (&osc_server.listener, &osc_server.error) {
+                (Some(listener), _) => ui.label(format!("Listening on {}", listener.addr())),
+                (None, Some(error)) => ui.label(error),
+                (None, None) => ui.label("Not listening"),
+            };
+        });
+
+        ui.collapsing("Link", |ui| {
+            let mut settings = link_settings.clone();
+            ui.checkbox(&mut settings.enabled, "Sync tempo and beats with Link");
+            ui.checkbox(&mut settings.start_stop_sync, "Start and stop together");
+            if settings != *link_settings {
+                *link_settings = settings;
+            }
+            match (&link_peer.peer, &link_peer.error) {
+                (Some(peer), _) => ui.label(match peer.peers() {
+                    1 => "1 other peer".to_string(),
+                    peers => format!("{peers} other peers"),
+                }),
+                (None, Some(error)) => ui.label(error),
+                (None, None) => ui.label("Not connected"),
+            };
+        });
+
+        ui.collapsing("Samples", |ui| {
+            let bank = sound::samples::sample_bank();
+            let mut names: Vec<_> = bank.names().collect();
+            names.sort();
+            for name in names {
+                ui.label(name);
+            }
+            if ui.button("Reload").clicked() {
+                sound::samples::load_dir(sound::samples::DEFAULT_SAMPLE_DIR);
+            }
+        });
 
         ui.collapsing("Wave", |ui| {
             ui.horizontal(|ui| {
                 ui.label("No. samples:");
-                ui.add(DragValue::new(&mut visual_controls.wave_samples));
+                ui.add(DragValue::new(&mut visual_controls.wave_samples).clamp_range(1..=1 << 16));
+                midi_learn_button(ui, &mut midi_learn, VisualsField::WaveSamples);
             });
             ui.horizontal(|ui| {
                 ui.label("Height:");
                 ui.add(DragValue::new(&mut visual_controls.wave_height_scale));
+                midi_learn_button(ui, &mut midi_learn, VisualsField::WaveHeightScale);
             });
             ui.horizontal(|ui| {
                 ui.label("Line width:");
                 ui.add(DragValue::new(&mut visual_controls.wave_line_width));
+                midi_learn_button(ui, &mut midi_learn, VisualsField::WaveLineWidth);
             });
             ui.horizontal(|ui| {
                 ui.label("Time rounding:");
                 ui.add(DragValue::new(&mut visual_controls.wave_time_rounding));
+                midi_learn_button(ui, &mut midi_learn, VisualsField::WaveTimeRounding);
             });
             ui.horizontal(|ui| {
                 ui.label("Time scale:");
                 ui.add(DragValue::new(&mut visual_controls.wave_inv_time_scale));
+                midi_learn_button(ui, &mut midi_learn, VisualsField::WaveInvTimeScale);
                 if ui.button("-").clicked() {
                     visual_controls.wave_inv_time_scale -= 1.0;
                 }
@@ -83,10 +869,12 @@ fn ui(
             ui.horizontal(|ui| {
                 ui.label("No. ghosts");
                 ui.add(DragValue::new(&mut visual_controls.wave_history_len));
+                midi_learn_button(ui, &mut midi_learn, VisualsField::WaveHistoryLen);
             });
             ui.horizontal(|ui| {
                 ui.label("Ghost fadeoff:");
                 ui.add(DragValue::new(&mut visual_controls.wave_fade_off));
+                midi_learn_button(ui, &mut midi_learn, VisualsField::WaveFadeOff);
             });
         });
 
@@ -94,14 +882,46 @@ fn ui(
             ui.horizontal(|ui| {
                 ui.label("Line width:");
                 ui.add(DragValue::new(&mut visual_controls.fft_line_width));
+                midi_learn_button(ui, &mut midi_learn, VisualsField::FftLineWidth);
             });
         })
     });
 }
 
+/// Click, then move a controller to bind it to the field
+fn midi_learn_button(ui: &mut egui::Ui, learn: &mut midi::MidiLearn, field: VisualsField) {
+    let text = if learn.learning == Some(field) {
+        "...".to_string()
+    } else if let Some(cc) = learn.binding(field) {
+        format!("CC{cc}")
+    } else {
+        "M".to_string()
+    };
+    let response = ui
+        .small_button(text)
+        .on_hover_text("MIDI learn, right click to unbind");
+    if response.clicked() {
+        learn.learning = match learn.learning {
+            Some(learning) if learning == field => None,
+            _ => Some(field),
+        };
+    }
+    if response.secondary_clicked() {
+        learn.bindings.retain(|_, f| *f != field);
+    }
+}
+
 fn setup(mut sound: ResMut<sound::SoundControl>) {
     sound.start();
-    sound.push_soundfn(Box::new(|t| {
+    sound.push_soundfn(example_sound());
+    // only the main track exists yet, so the names are free
+    let _ = sound.add_bus("reverb", sound::effects::Reverb::default());
+    let _ = sound.add_bus("delay", sound::effects::Delay::default());
+}
+
+fn example_sound() -> sound::SoundFn {
+    sound::params::register_param("volume", sound::params::ParamSpec::new(0.0, 0.5, 0.1));
+    Box::new(|t| {
         //here
 
         // let note = seq![440.0, 440.0, 330.0, 660.0](t);
@@ -118,8 +938,8 @@ fn setup(mut sound: ResMut<sound::SoundControl>) {
         let out = sin(880.0 * t) + sin(440. * t) + sin(220. * t);
         let out = out / 3.0;
 
-        let vol = 0.1;
+        let vol = sound::params::param("volume")(t);
         let out = clip(out) * vol;
         [out, out]
-    }));
+    })
 }
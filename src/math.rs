@@ -1,6 +1,8 @@
 use crate::{euc, sound::Float};
 
 pub mod bjorklund;
+pub mod slice;
+pub mod surround;
 
 const TAU: Float = std::f64::consts::TAU as Float;
 
@@ -69,6 +71,71 @@ pub fn quant(f: Float, n: usize) -> Float {
     (((f * n) as usize) as Float) / n
 }
 
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum PanLaw {
+    /// -6dB in the centre, loudness dips in the middle
+    Linear,
+    /// -3dB in the centre, constant loudness across the field
+    #[default]
+    EqualPower,
+    /// -4.5dB in the centre, halfway between the two
+    Compromise,
+}
+
+/// Pan a mono signal, `pos` goes from -1 (left) to 1 (right)
+pub fn pan_with(x: Float, pos: impl Callable, law: PanLaw, t: Float) -> [Float; 2] {
+    let p = (pos.call(t).clamp(-1.0, 1.0) + 1.0) * 0.5;
+    let (l, r) = match law {
+        PanLaw::Linear => (1.0 - p, p),
+        PanLaw::EqualPower => (cos(p * 0.25), sin(p * 0.25)),
+        PanLaw::Compromise => (
+            ((1.0 - p) * cos(p * 0.25)).sqrt(),
+            (p * sin(p * 0.25)).sqrt(),
+        ),
+    };
+    [x * l, x * r]
+}
+
+/// Equal-power pan, e.g. `pan(out, |t| sin(0.25 * t), t)` to swing left and right every 4 seconds
+pub fn pan(x: Float, pos: impl Callable, t: Float) -> [Float; 2] {
+    pan_with(x, pos, PanLaw::EqualPower, t)
+}
+
+/// Mid/side width, 0 is mono, 1 leaves the frame alone, above 1 widens
+pub fn width([l, r]: [Float; 2], width: impl Callable, t: Float) -> [Float; 2] {
+    let mid = (l + r) * 0.5;
+    let side = (l - r) * 0.5 * width.call(t).max(0.0);
+    [mid + side, mid - side]
+}
+
+/// Haas effect widening, delays one side by `delay` seconds (negative delays the left).
+/// Keep it under ~0.03 or it starts to sound like an echo
+pub fn haas(signal: impl Callable, delay: impl Callable, t: Float) -> [Float; 2] {
+    let delay = delay.call(t);
+    [
+        signal.call(t - delay.min(0.0).abs()),
+        signal.call(t - delay.max(0.0)),
+    ]
+}
+
+const HEAD_RADIUS: Float = 0.0875;
+const SPEED_OF_SOUND: Float = 343.0;
+
+/// Simple binaural pan for headphones, using the interaural time difference of a spherical head
+/// and a bit of level difference for the shadowed ear. `pos` goes from -1 (left) to 1 (right)
+pub fn binaural(signal: impl Callable, pos: impl Callable, t: Float) -> [Float; 2] {
+    let azimuth = pos.call(t).clamp(-1.0, 1.0) * std::f64::consts::FRAC_PI_2 as Float;
+    // Woodworth's formula
+    let itd = HEAD_RADIUS / SPEED_OF_SOUND * (azimuth.abs() + azimuth.abs().sin());
+    let near = signal.call(t);
+    let far = signal.call(t - itd) * (1.0 - 0.3 * azimuth.sin().abs());
+    if azimuth >= 0.0 {
+        [far, near]
+    } else {
+        [near, far]
+    }
+}
+
 #[macro_export]
 macro_rules! seq {
     ($($e:expr),*) => {
@@ -144,3 +211,42 @@ impl Callable for usize {
         *self as Float
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{binaural, haas, pan, pan_with, sin, width, PanLaw};
+
+    #[test]
+    fn test_pan() {
+        assert_eq!(pan_with(1.0, -1.0, PanLaw::Linear, 0.0), [1.0, 0.0]);
+        assert_eq!(pan_with(1.0, 0.0, PanLaw::Linear, 0.0), [0.5, 0.5]);
+        let [l, r] = pan_with(1.0, 0.0, PanLaw::Compromise, 0.0);
+        assert!((20.0 * l.log10() + 4.5).abs() < 0.1 && l == r);
+        for i in 0..=20 {
+            let pos = i as f64 / 10.0 - 1.0;
+            let [l, r] = pan(1.0, pos, 0.0);
+            assert!((l * l + r * r - 1.0).abs() < 1e-9);
+        }
+        // automated position
+        let [l, r] = pan(1.0, |t| t, 1.0);
+        assert!(l.abs() < 1e-9 && (r - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_width() {
+        assert_eq!(width([1.0, 0.0], 0.0, 0.0), [0.5, 0.5]);
+        assert_eq!(width([1.0, 0.0], 1.0, 0.0), [1.0, 0.0]);
+        assert_eq!(width([1.0, 0.0], 2.0, 0.0), [1.5, -0.5]);
+    }
+
+    #[test]
+    fn test_delays() {
+        let signal = |t| t;
+        assert_eq!(haas(signal, 0.01, 1.0), [1.0, 0.99]);
+        assert_eq!(haas(signal, -0.01, 1.0), [0.99, 1.0]);
+        let [l, r] = binaural(signal, 1.0, 1.0);
+        assert!(r == 1.0 && l < 0.7);
+        let [l, r] = binaural(sin, 0.0, 0.3);
+        assert_eq!(l, r);
+    }
+}
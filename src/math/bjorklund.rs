@@ -16,7 +16,7 @@ fn bjorklund_bool(steps: usize, pulses: usize) -> Vec<bool> {
         counts.push(divisor / remainders[level]);
         remainders.push(divisor % remainders[level]);
         divisor = remainders[level];
-        level = level + 1;
+        level += 1;
         if remainders[level] <= 1 {
             break;
         }
@@ -47,9 +47,9 @@ fn bjorklund_bool(steps: usize, pulses: usize) -> Vec<bool> {
     let level = level as isize;
     build(&mut pattern, &mut counts, &mut remainders, level);
     let i = pattern.iter().position(|x| *x == 1).unwrap();
-    let result = pattern[i as usize..]
+    let result = pattern[i..]
         .iter()
-        .chain(pattern[0..i as usize].iter())
+        .chain(pattern[0..i].iter())
         .map(|x| *x == 1)
         .collect::<Vec<_>>();
 
@@ -74,9 +74,9 @@ static CACHED_BJORKLUND: Lazy<Vec<bool>> = Lazy::new(|| {
 });
 
 fn cached_bjorklund_index(steps: usize, pulses: usize, index: usize) -> usize {
-    let steps_offset = (steps - 1) as usize * BJORKLUND_CACHE_MAX * BJORKLUND_CACHE_MAX;
-    let pulses_offset = (pulses - 1) as usize * BJORKLUND_CACHE_MAX;
-    steps_offset + pulses_offset + index as usize
+    let steps_offset = (steps - 1) * BJORKLUND_CACHE_MAX * BJORKLUND_CACHE_MAX;
+    let pulses_offset = (pulses - 1) * BJORKLUND_CACHE_MAX;
+    steps_offset + pulses_offset + index
 }
 
 pub fn cached_bjorklund(steps: usize, pulses: usize, index: usize) -> bool {
@@ -0,0 +1,96 @@
+//! Slice versions of the math primitives, for block rendering. They work in place and are
+//! plain loops with no branches, so the compiler can vectorise them
+
+use crate::sound::Float;
+
+use super::TAU;
+
+/// Fill `out` with the times `t0`, `t0 + dt`, `t0 + 2 * dt`, ...
+pub fn ramp(out: &mut [Float], t0: Float, dt: Float) {
+    for (i, x) in out.iter_mut().enumerate() {
+        *x = t0 + i as Float * dt;
+    }
+}
+
+pub fn sat(xs: &mut [Float]) {
+    xs.iter_mut().for_each(|x| *x = x.clamp(0.0, 1.0));
+}
+
+pub fn clip(xs: &mut [Float]) {
+    xs.iter_mut().for_each(|x| *x = x.clamp(-1.0, 1.0));
+}
+
+pub fn abs(xs: &mut [Float]) {
+    xs.iter_mut().for_each(|x| *x = x.abs());
+}
+
+pub fn sin(xs: &mut [Float]) {
+    xs.iter_mut().for_each(|x| *x = (*x * TAU).sin());
+}
+
+pub fn cos(xs: &mut [Float]) {
+    xs.iter_mut().for_each(|x| *x = (*x * TAU).cos());
+}
+
+pub fn saw(xs: &mut [Float]) {
+    xs.iter_mut().for_each(|x| *x = (*x % 1.0) * 2.0 - 1.0);
+}
+
+pub fn tri(xs: &mut [Float]) {
+    xs.iter_mut()
+        .for_each(|x| *x = ((*x % 1.0) * 2.0 - 1.0).abs() * 2.0 - 1.0);
+}
+
+pub fn sqr(xs: &mut [Float]) {
+    xs.iter_mut()
+        .for_each(|x| *x = ((*x % 2.0) as usize) as Float - 1.0);
+}
+
+/// `xs * by`
+pub fn scale(xs: &mut [Float], by: Float) {
+    xs.iter_mut().for_each(|x| *x *= by);
+}
+
+/// `xs + other`, element by element
+pub fn add(xs: &mut [Float], other: &[Float]) {
+    xs.iter_mut().zip(other).for_each(|(x, y)| *x += y);
+}
+
+/// `xs + other * by`, element by element
+pub fn add_scaled(xs: &mut [Float], other: &[Float], by: Float) {
+    xs.iter_mut().zip(other).for_each(|(x, y)| *x += y * by);
+}
+
+/// `xs * other`, element by element
+pub fn mul(xs: &mut [Float], other: &[Float]) {
+    xs.iter_mut().zip(other).for_each(|(x, y)| *x *= y);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{math, sound::Float};
+
+    type Primitive = (fn(&mut [Float]), fn(Float) -> Float);
+
+    #[test]
+    fn test_matches_per_sample() {
+        let primitives: [Primitive; 7] = [
+            (super::sat, math::sat),
+            (super::clip, math::clip),
+            (super::sin, math::sin),
+            (super::cos, math::cos),
+            (super::saw, math::saw),
+            (super::tri, math::tri),
+            (super::sqr, math::sqr),
+        ];
+        let mut t = [0.0; 100];
+        super::ramp(&mut t, -0.3, 0.037);
+        for (block, per_sample) in primitives {
+            let mut xs = t;
+            block(&mut xs);
+            for (x, t) in xs.iter().zip(t) {
+                assert_eq!(*x, per_sample(t));
+            }
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use crate::sound::Float;
+
+use super::Callable;
+
+// Speaker angles are in turns, clockwise from the front, like the phase of `sin`
+
+/// Quad in WAV channel order: front left, front right, back left, back right
+pub const QUAD: [Option<Float>; 4] = [Some(-0.125), Some(0.125), Some(-0.375), Some(0.375)];
+
+/// 5.1 in WAV channel order: front left, front right, centre, LFE, back left, back right.
+/// The LFE has no position, panning never sends anything to it
+pub const SURROUND_5_1: [Option<Float>; 6] = [
+    Some(-30.0 / 360.0),
+    Some(30.0 / 360.0),
+    Some(0.0),
+    None,
+    Some(-110.0 / 360.0),
+    Some(110.0 / 360.0),
+];
+
+/// `N` speakers spaced evenly in a ring, channel 0 at the front, going clockwise
+pub fn ring<const N: usize>() -> [Option<Float>; N] {
+    std::array::from_fn(|i| Some(i as Float / N as Float))
+}
+
+/// Equal-power pan of a mono signal between the two speakers either side of `angle`
+pub fn pan_speakers<const N: usize>(
+    x: Float,
+    angle: Float,
+    speakers: &[Option<Float>; N],
+) -> [Float; N] {
+    let mut out = [0.0; N];
+    // (channel, distance in turns) of the nearest speaker on each side
+    let mut before: Option<(usize, Float)> = None;
+    let mut after: Option<(usize, Float)> = None;
+    for (channel, speaker) in speakers.iter().enumerate() {
+        let Some(speaker) = speaker else { continue };
+        let back = (angle - speaker).rem_euclid(1.0);
+        let forward = (speaker - angle).rem_euclid(1.0);
+        if before.is_none_or(|(_, d)| back < d) {
+            before = Some((channel, back));
+        }
+        if after.is_none_or(|(_, d)| forward < d) {
+            after = Some((channel, forward));
+        }
+    }
+
+    match (before, after) {
+        (Some((b, back)), Some((a, forward))) if a != b && back > 0.0 => {
+            let frac = back / (back + forward);
+            out[b] = x * (frac * std::f64::consts::FRAC_PI_2 as Float).cos();
+            out[a] = x * (frac * std::f64::consts::FRAC_PI_2 as Float).sin();
+        }
+        (Some((b, _)), _) => out[b] = x,
+        _ => {}
+    }
+    out
+}
+
+/// Pan around a quad layout, the angle can be automated e.g. `pan_quad(x, |t| t * 0.1, t)`
+pub fn pan_quad(x: Float, angle: impl Callable, t: Float) -> [Float; 4] {
+    pan_speakers(x, angle.call(t), &QUAD)
+}
+
+/// Pan around a 5.1 layout, see [`SURROUND_5_1`]
+pub fn pan_5_1(x: Float, angle: impl Callable, t: Float) -> [Float; 6] {
+    pan_speakers(x, angle.call(t), &SURROUND_5_1)
+}
+
+/// Pan around a ring of `N` speakers, e.g. `pan_ring::<8>(x, angle, t)` for an 8 speaker installation
+pub fn pan_ring<const N: usize>(x: Float, angle: impl Callable, t: Float) -> [Float; N] {
+    pan_speakers(x, angle.call(t), &ring::<N>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pan_speakers, ring, QUAD, SURROUND_5_1};
+
+    #[test]
+    fn test_pan_speakers() {
+        // straight at a speaker
+        assert_eq!(pan_speakers(1.0, 0.125, &QUAD), [0.0, 1.0, 0.0, 0.0]);
+        assert_eq!(pan_speakers(1.0, 0.0, &SURROUND_5_1)[2], 1.0);
+        assert_eq!(pan_speakers(1.0, 0.25, &ring::<8>())[2], 1.0);
+
+        // equal power everywhere around the ring, and nothing to the LFE
+        for i in 0..100 {
+            let angle = i as f64 / 100.0;
+            for power in [
+                pan_speakers(0.5, angle, &QUAD)
+                    .map(|x| x * x)
+                    .iter()
+                    .sum::<f64>(),
+                pan_speakers(0.5, angle, &ring::<8>())
+                    .map(|x| x * x)
+                    .iter()
+                    .sum(),
+            ] {
+                assert!((power - 0.25).abs() < 1e-9, "{angle}: {power}");
+            }
+            assert_eq!(pan_speakers(0.5, angle, &SURROUND_5_1)[3], 0.0);
+        }
+
+        // halfway between front left and front right
+        let [l, r, _, _] = pan_speakers(1.0, 0.0, &QUAD);
+        assert!((l - r).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,466 @@
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use arc_swap::ArcSwapOption;
+use bevy::{
+    app::{Startup, Update},
+    log::{info, warn},
+    prelude::{Plugin, ResMut, Resource},
+};
+use crossbeam_queue::SegQueue;
+use once_cell::sync::Lazy;
+
+use crate::{
+    sound::{audio_time, load, voices::Voices, Float},
+    visuals::{VisualsControls, VisualsField},
+};
+
+pub mod sync;
+
+use sync::Timecode;
+
+/// Name of the virtual port other software can connect to
+pub const VIRTUAL_PORT_NAME: &str = "sonars in";
+
+pub struct MidiPlugin;
+
+impl Plugin for MidiPlugin {
+    fn build(&self, app: &mut bevy::prelude::App) {
+        app.init_resource::<MidiLearn>()
+            .add_plugins(sync::MidiSyncPlugin)
+            .add_systems(Startup, connect)
+            .add_systems(Update, apply_bindings);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MidiMessage {
+    NoteOn {
+        channel: u8,
+        note: u8,
+        velocity: u8,
+    },
+    NoteOff {
+        channel: u8,
+        note: u8,
+    },
+    ControlChange {
+        channel: u8,
+        controller: u8,
+        value: u8,
+    },
+    /// 24 a beat while the sender's transport runs
+    Clock,
+    Start,
+    Continue,
+    Stop,
+    /// Where [`MidiMessage::Continue`] carries on from
+    SongPosition {
+        sixteenths: u16,
+    },
+    /// One nibble of a timecode, 8 of them make up a whole one
+    QuarterFrame {
+        piece: u8,
+        value: u8,
+    },
+    /// A whole timecode at once, sent when it jumps
+    FullFrame(Timecode),
+}
+
+impl MidiMessage {
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let (&status, data) = bytes.split_first()?;
+        // data bytes have the top bit clear, a message with one that doesn't is malformed
+        if status != 0xf0 && data.iter().take(2).any(|&byte| byte >= 0x80) {
+            return None;
+        }
+        if status >= 0xf0 {
+            return Self::parse_system(status, data);
+        }
+        let channel = status & 0x0f;
+        match (status & 0xf0, data) {
+            (0x90, &[note, 0, ..]) | (0x80, &[note, _, ..]) => {
+                Some(MidiMessage::NoteOff { channel, note })
+            }
+            (0x90, &[note, velocity, ..]) => Some(MidiMessage::NoteOn {
+                channel,
+                note,
+                velocity,
+            }),
+            (0xb0, &[controller, value, ..]) => Some(MidiMessage::ControlChange {
+                channel,
+                controller,
+                value,
+            }),
+            _ => None,
+        }
+    }
+
+    // messages for every channel, the sync ones are all we use
+    fn parse_system(status: u8, data: &[u8]) -> Option<Self> {
+        match (status, data) {
+            (0xf8, _) => Some(MidiMessage::Clock),
+            (0xfa, _) => Some(MidiMessage::Start),
+            (0xfb, _) => Some(MidiMessage::Continue),
+            (0xfc, _) => Some(MidiMessage::Stop),
+            (0xf2, &[lsb, msb, ..]) => Some(MidiMessage::SongPosition {
+                sixteenths: (msb as u16) << 7 | lsb as u16,
+            }),
+            (0xf1, &[data, ..]) => Some(MidiMessage::QuarterFrame {
+                piece: data >> 4,
+                value: data & 0x0f,
+            }),
+            // a universal real time sysex, the MTC full frame
+            (0xf0, &[0x7f, _, 0x01, 0x01, hours, minutes, seconds, frames, 0xf7]) => {
+                Some(MidiMessage::FullFrame(Timecode {
+                    hours: hours & 0x1f,
+                    minutes,
+                    seconds,
+                    frames,
+                    rate: hours >> 5 & 0x03,
+                }))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        match *self {
+            MidiMessage::NoteOn {
+                channel,
+                note,
+                velocity,
+            } => vec![0x90 | channel, note, velocity],
+            MidiMessage::NoteOff { channel, note } => vec![0x80 | channel, note, 0],
+            MidiMessage::ControlChange {
+                channel,
+                controller,
+                value,
+            } => vec![0xb0 | channel, controller, value],
+            MidiMessage::Clock => vec![0xf8],
+            MidiMessage::Start => vec![0xfa],
+            MidiMessage::Continue => vec![0xfb],
+            MidiMessage::Stop => vec![0xfc],
+            MidiMessage::SongPosition { sixteenths } => {
+                vec![
+                    0xf2,
+                    (sixteenths & 0x7f) as u8,
+                    (sixteenths >> 7 & 0x7f) as u8,
+                ]
+            }
+            MidiMessage::QuarterFrame { piece, value } => vec![0xf1, piece << 4 | value],
+            MidiMessage::FullFrame(timecode) => vec![
+                0xf0,
+                0x7f,
+                // to every device
+                0x7f,
+                0x01,
+                0x01,
+                timecode.rate << 5 | timecode.hours,
+                timecode.minutes,
+                timecode.seconds,
+                timecode.frames,
+                0xf7,
+            ],
+        }
+    }
+}
+
+// Written by the MIDI thread, read by sound functions. Values are f64 bits
+struct MidiState {
+    cc: [AtomicU64; 128],
+    cc_time: [AtomicU64; 128],
+    // velocity from 0 to 1, 0 when the note isn't held
+    notes: [AtomicU64; 128],
+    note_time: [AtomicU64; 128],
+}
+
+fn atomic_array() -> [AtomicU64; 128] {
+    std::array::from_fn(|_| AtomicU64::new(0))
+}
+
+static MIDI_STATE: Lazy<MidiState> = Lazy::new(|| MidiState {
+    cc: atomic_array(),
+    cc_time: atomic_array(),
+    notes: atomic_array(),
+    note_time: atomic_array(),
+});
+
+static MIDI_VOICES: Lazy<ArcSwapOption<Voices>> = Lazy::new(Default::default);
+
+// Control changes for the bevy side, for MIDI learn
+static CC_QUEUE: SegQueue<(u8, u8)> = SegQueue::new();
+
+fn load(atomic: &AtomicU64) -> Float {
+    Float::from_bits(atomic.load(Ordering::Relaxed))
+}
+
+fn store(atomic: &AtomicU64, value: Float) {
+    atomic.store(value.to_bits(), Ordering::Relaxed)
+}
+
+/// Latest value of a control change, from 0 to 1 (on any channel)
+pub fn cc(controller: u8) -> Float {
+    load(&MIDI_STATE.cc[controller as usize & 0x7f])
+}
+
+/// Audio time of the latest change to a controller
+pub fn cc_time(controller: u8) -> Float {
+    load(&MIDI_STATE.cc_time[controller as usize & 0x7f])
+}
+
+/// Velocity from 0 to 1 if the note is held, otherwise 0
+pub fn note(note: u8) -> Float {
+    load(&MIDI_STATE.notes[note as usize & 0x7f])
+}
+
+/// Audio time the note was last pressed or released
+pub fn note_time(note: u8) -> Float {
+    load(&MIDI_STATE.note_time[note as usize & 0x7f])
+}
+
+pub fn held_notes() -> impl Iterator<Item = u8> {
+    (0..128u8).filter(|n| note(*n) > 0.0)
+}
+
+/// Play incoming notes on a voice pool
+pub fn connect_voices(voices: Option<Voices>) {
+    MIDI_VOICES.store(voices.map(Into::into));
+}
+
+/// Apply a message as if it came in from a port, timestamped with the current audio time
+pub fn handle_message(message: MidiMessage) {
+    let time = audio_time();
+    let state = &*MIDI_STATE;
+    // masked like the getters, a message built by hand can have any bytes in it
+    match message {
+        MidiMessage::NoteOn { note, velocity, .. } => {
+            let note = note & 0x7f;
+            let velocity = (velocity & 0x7f) as Float / 127.0;
+            store(&state.notes[note as usize], velocity);
+            store(&state.note_time[note as usize], time);
+            if let Some(voices) = &*MIDI_VOICES.load() {
+                voices.note_on(note, velocity, time);
+            }
+        }
+        MidiMessage::NoteOff { note, .. } => {
+            let note = note & 0x7f;
+            store(&state.notes[note as usize], 0.0);
+            store(&state.note_time[note as usize], time);
+            if let Some(voices) = &*MIDI_VOICES.load() {
+                voices.note_off(note, time);
+            }
+        }
+        MidiMessage::ControlChange {
+            controller, value, ..
+        } => {
+            let (controller, value) = (controller & 0x7f, value & 0x7f);
+            store(&state.cc[controller as usize], value as Float / 127.0);
+            store(&state.cc_time[controller as usize], time);
+            CC_QUEUE.push((controller, value));
+        }
+        // arrival time on the wall clock, the follower smooths out the jitter
+        message => sync::handle_message(message, load::now()),
+    }
+}
+
+fn on_midi(_timestamp: u64, bytes: &[u8], _: &mut ()) {
+    if let Some(message) = MidiMessage::parse(bytes) {
+        handle_message(message);
+    }
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(target_arch = "wasm32")] {
+        fn connect() {
+            // todo_major: web MIDI
+            info!("MIDI input isn't supported on the web yet");
+        }
+    } else {
+        use midir::{Ignore, MidiInput, MidiInputConnection};
+
+        // midir drops clock and sysex unless asked not to
+        fn midi_input() -> anyhow::Result<MidiInput> {
+            let mut input = MidiInput::new("sonars")?;
+            input.ignore(Ignore::ActiveSense);
+            Ok(input)
+        }
+
+        // Connections close when dropped, so they are kept for the lifetime of the app
+        static CONNECTIONS: std::sync::Mutex<Vec<MidiInputConnection<()>>> =
+            std::sync::Mutex::new(Vec::new());
+
+        /// Open a virtual port and connect to every existing input port
+        pub fn open_ports() -> anyhow::Result<()> {
+            let mut connections = CONNECTIONS.lock().unwrap();
+
+            #[cfg(unix)]
+            {
+                use midir::os::unix::VirtualInput;
+                let input = midi_input()?;
+                connections.push(
+                    input
+                        .create_virtual(VIRTUAL_PORT_NAME, on_midi, ())
+                        .map_err(|e| anyhow::anyhow!("{e}"))?,
+                );
+            }
+
+            let ports = MidiInput::new("sonars")?.ports();
+            for port in ports {
+                let input = midi_input()?;
+                let name = input.port_name(&port).unwrap_or_default();
+                // our own ports, listening to the clock we send would be a loop
+                if name.contains(VIRTUAL_PORT_NAME) || name.contains(sync::CLOCK_PORT_NAME) {
+                    continue;
+                }
+                match input.connect(&port, "sonars", on_midi, ()) {
+                    Ok(connection) => {
+                        info!("Connected to MIDI port {name}");
+                        connections.push(connection);
+                    }
+                    Err(e) => warn!("Couldn't connect to MIDI port {name}: {e}"),
+                }
+            }
+            Ok(())
+        }
+
+        fn connect() {
+            if let Err(e) = open_ports() {
+                warn!("MIDI unavailable: {e}");
+            }
+        }
+    }
+}
+
+/// Binds MIDI controllers to [`VisualsControls`] fields
+#[derive(Resource, Default)]
+pub struct MidiLearn {
+    /// The next controller that moves gets bound to this
+    pub learning: Option<VisualsField>,
+    pub bindings: HashMap<u8, VisualsField>,
+}
+
+impl MidiLearn {
+    pub fn binding(&self, field: VisualsField) -> Option<u8> {
+        self.bindings
+            .iter()
+            .find_map(|(cc, f)| (*f == field).then_some(*cc))
+    }
+}
+
+fn apply_bindings(mut learn: ResMut<MidiLearn>, mut controls: ResMut<VisualsControls>) {
+    while let Some((controller, value)) = CC_QUEUE.pop() {
+        if let Some(field) = learn.learning.take() {
+            learn.bindings.retain(|_, f| *f != field);
+            learn.bindings.insert(controller, field);
+            info!("Bound CC{controller} to {}", field.name());
+        }
+        if let Some(field) = learn.bindings.get(&controller) {
+            controls.set_normalized(*field, value as f64 / 127.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cc, held_notes, sync::Timecode, MidiMessage};
+
+    #[test]
+    fn test_parse() {
+        // a data byte with the top bit set
+        assert_eq!(MidiMessage::parse(&[0x90, 200, 100]), None);
+        assert_eq!(MidiMessage::parse(&[0xb0, 1, 0x80]), None);
+        assert_eq!(
+            MidiMessage::parse(&[0x91, 60, 100]),
+            Some(MidiMessage::NoteOn {
+                channel: 1,
+                note: 60,
+                velocity: 100
+            })
+        );
+        assert_eq!(
+            MidiMessage::parse(&[0x90, 60, 0]),
+            Some(MidiMessage::NoteOff {
+                channel: 0,
+                note: 60
+            })
+        );
+        assert_eq!(
+            MidiMessage::parse(&[0x80, 61, 64]),
+            Some(MidiMessage::NoteOff {
+                channel: 0,
+                note: 61
+            })
+        );
+        assert_eq!(
+            MidiMessage::parse(&[0xbf, 74, 127]),
+            Some(MidiMessage::ControlChange {
+                channel: 15,
+                controller: 74,
+                value: 127
+            })
+        );
+        assert_eq!(MidiMessage::parse(&[0x90, 60]), None);
+        assert_eq!(MidiMessage::parse(&[0xfe]), None);
+
+        let messages = [
+            MidiMessage::ControlChange {
+                channel: 2,
+                controller: 1,
+                value: 64,
+            },
+            MidiMessage::Clock,
+            MidiMessage::Start,
+            MidiMessage::Continue,
+            MidiMessage::Stop,
+            MidiMessage::SongPosition { sixteenths: 1000 },
+            MidiMessage::QuarterFrame { piece: 7, value: 3 },
+            MidiMessage::FullFrame(Timecode {
+                hours: 1,
+                minutes: 2,
+                seconds: 3,
+                frames: 24,
+                rate: 1,
+            }),
+        ];
+        for message in messages {
+            assert_eq!(MidiMessage::parse(&message.encode()), Some(message));
+        }
+        // 1000 sixteenths is 7 lots of 128 and 104
+        assert_eq!(
+            MidiMessage::SongPosition { sixteenths: 1000 }.encode(),
+            [0xf2, 104, 7]
+        );
+    }
+
+    /// Sends through the virtual port like another program would, skipped when there's no MIDI system
+    #[cfg(unix)]
+    #[test]
+    fn test_virtual_port() {
+        use midir::{MidiOutput, MidiOutputPort};
+
+        if let Err(e) = super::open_ports() {
+            eprintln!("skipping, no MIDI: {e}");
+            return;
+        }
+        let output = MidiOutput::new("sonars test").unwrap();
+        let port: MidiOutputPort = output
+            .ports()
+            .into_iter()
+            .find(|p| {
+                output
+                    .port_name(p)
+                    .is_ok_and(|n| n.contains(super::VIRTUAL_PORT_NAME))
+            })
+            .expect("virtual port should be visible");
+        let mut connection = output.connect(&port, "test").unwrap();
+        connection.send(&[0xb0, 75, 127]).unwrap();
+        connection.send(&[0x90, 62, 127]).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(100));
+
+        assert_eq!(cc(75), 1.0);
+        assert!(held_notes().any(|n| n == 62));
+    }
+}
@@ -0,0 +1,834 @@
+//! MIDI clock and timecode. As the master, 24 clock pulses a beat go out with start, stop and song
+//! position as the transport moves, and MTC if asked for. Following, the tempo comes from the
+//! incoming pulses through a delay-locked loop that smooths out their jitter, and the transport
+//! follows start, stop, song position and timecode
+
+use std::sync::Mutex;
+
+use bevy::{
+    app::Update,
+    log::{info, warn},
+    prelude::{DetectChanges, IntoSystemConfigs, Local, Plugin, Res, ResMut, Resource},
+};
+use crossbeam_queue::SegQueue;
+use once_cell::sync::Lazy;
+
+use super::MidiMessage;
+use crate::sound::{
+    beat_error, clock, load, set_tempo, set_timeline, tempo, timeline, Float, SoundControl,
+    SoundResources, Timeline,
+};
+
+pub const PULSES_PER_BEAT: i64 = 24;
+// song position counts in sixteenths
+const PULSES_PER_SIXTEENTH: i64 = 6;
+/// Frames a second of the timecode we send
+pub const MTC_FPS: i64 = 25;
+/// MTC's code for [`MTC_FPS`], 0 to 3 are 24, 25, 29.97 drop frame and 30
+pub const MTC_RATE: u8 = 1;
+/// Name of the virtual port the clock goes out on when no other port is picked
+pub const CLOCK_PORT_NAME: &str = "sonars clock";
+// how quickly the follower takes up a change in the pulses, in Hz. It starts out quick, to settle
+// on the first few pulses, and narrows over the first beats to smooth out the jitter
+const START_BANDWIDTH: Float = 4.0;
+const FOLLOW_BANDWIDTH: Float = 0.3;
+// pulses the follower runs on by itself past the last one it heard
+const MAX_EXTRAPOLATION: Float = 2.0;
+// a followed tempo this close to ours is left alone, the pulses only give it to about this
+const TEMPO_TOLERANCE: Float = 0.1;
+// beats the transport can drift from the clock before it is put back in phase
+const PHASE_TOLERANCE: Float = 0.02;
+// seconds to let a phase correction reach the audio before checking again
+const CORRECTION_SETTLE: Float = 0.5;
+// timecode frames the transport can be out before it is moved to the timecode
+const MTC_TOLERANCE: Float = 2.0;
+// longest the clock sender sleeps, so it notices the transport starting and stopping
+const MAX_SLEEP: Float = 0.005;
+
+/// An SMPTE time as MTC sends it
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Timecode {
+    pub hours: u8,
+    pub minutes: u8,
+    pub seconds: u8,
+    pub frames: u8,
+    /// See [`MTC_RATE`]
+    pub rate: u8,
+}
+
+impl Timecode {
+    /// The timecode `frames` frames in at [`MTC_FPS`], wrapping after a day
+    pub fn from_frames(frames: i64) -> Self {
+        let frames = frames.rem_euclid(24 * 3600 * MTC_FPS);
+        let seconds = frames / MTC_FPS;
+        Self {
+            hours: (seconds / 3600) as u8,
+            minutes: (seconds / 60 % 60) as u8,
+            seconds: (seconds % 60) as u8,
+            frames: (frames % MTC_FPS) as u8,
+            rate: MTC_RATE,
+        }
+    }
+
+    pub fn fps(&self) -> Float {
+        match self.rate {
+            0 => 24.0,
+            1 => 25.0,
+            2 => 29.97,
+            _ => 30.0,
+        }
+    }
+
+    /// Seconds in, drop frame timecode is taken as if nothing were dropped
+    pub fn seconds(&self) -> Float {
+        let whole = (self.hours as u32 * 60 + self.minutes as u32) * 60 + self.seconds as u32;
+        whole as Float + self.frames as Float / self.fps()
+    }
+
+    /// The nibble quarter frame `piece` carries
+    pub fn piece(&self, piece: u8) -> u8 {
+        match piece {
+            0 => self.frames & 0x0f,
+            1 => self.frames >> 4,
+            2 => self.seconds & 0x0f,
+            3 => self.seconds >> 4,
+            4 => self.minutes & 0x0f,
+            5 => self.minutes >> 4,
+            6 => self.hours & 0x0f,
+            _ => self.hours >> 4 | self.rate << 1,
+        }
+    }
+}
+
+/// Puts timecodes back together from quarter frames
+#[derive(Clone, Debug, Default)]
+pub struct MtcReader {
+    nibbles: [u8; 8],
+    // the piece that should come next, anything else waits for piece 0
+    next: u8,
+}
+
+impl MtcReader {
+    /// Seconds of timecode as this piece arrives, once all 8 have come in order
+    pub fn quarter_frame(&mut self, piece: u8, value: u8) -> Option<Float> {
+        if piece != self.next && piece != 0 {
+            self.next = 0;
+            return None;
+        }
+        self.nibbles[piece as usize & 7] = value;
+        self.next = piece + 1;
+        if piece < 7 {
+            return None;
+        }
+        self.next = 0;
+        let n = &self.nibbles;
+        let timecode = Timecode {
+            hours: n[6] | (n[7] & 1) << 4,
+            minutes: n[4] | n[5] << 4,
+            seconds: n[2] | n[3] << 4,
+            frames: n[0] | n[1] << 4,
+            rate: n[7] >> 1 & 0x03,
+        };
+        // the timecode is from when piece 0 was sent, 7 quarter frames ago
+        Some(timecode.seconds() + 1.75 / timecode.fps())
+    }
+}
+
+// a straight line through the pulses, nudged by each one
+#[derive(Clone, Copy, Debug)]
+struct PulseLine {
+    pulse: i64,
+    // pulses followed since the line started
+    count: i64,
+    time: Float,
+    period: Float,
+}
+
+impl PulseLine {
+    fn follow(self, pulse: i64, host: Float) -> Option<Self> {
+        let pulses = (pulse - self.pulse) as Float;
+        let predicted = self.time + pulses * self.period;
+        let error = host - predicted;
+        // this far out is a new tempo or a dropout rather than jitter
+        if pulses <= 0.0 || error.abs() > self.period / 2.0 {
+            return None;
+        }
+        let beats = self.count as Float / PULSES_PER_BEAT as Float;
+        let bandwidth = (START_BANDWIDTH / (1.0 + beats)).max(FOLLOW_BANDWIDTH);
+        let omega = 2.0 * std::f64::consts::PI * bandwidth * pulses * self.period;
+        Some(Self {
+            pulse,
+            count: self.count + 1,
+            time: predicted + std::f64::consts::SQRT_2 * omega * error,
+            period: self.period + omega * omega * error / pulses,
+        })
+    }
+}
+
+/// Tempo and beats from incoming clock pulses, timed by the host clock
+#[derive(Clone, Debug, Default)]
+pub struct ClockFollower {
+    // pulses since the start, counting the next one to arrive
+    next: i64,
+    // the last pulse and when it came, for the first period
+    previous: Option<(i64, Float)>,
+    line: Option<PulseLine>,
+    // seconds a pulse, kept while stopped
+    period: Option<Float>,
+}
+
+impl ClockFollower {
+    /// The next pulse is number `pulse`, after a start or song position
+    pub fn locate(&mut self, pulse: i64) {
+        self.next = pulse;
+        self.stop();
+    }
+
+    /// The pulses stop for a while, a continue carries on counting from here
+    pub fn stop(&mut self) {
+        self.previous = None;
+        self.line = None;
+    }
+
+    pub fn pulse(&mut self, host: Float) {
+        let pulse = self.next;
+        self.next += 1;
+        let previous = self.previous.replace((pulse, host));
+        self.line = self
+            .line
+            .and_then(|line| line.follow(pulse, host))
+            .or_else(|| {
+                let (previous, at) = previous?;
+                (host > at).then(|| PulseLine {
+                    pulse,
+                    count: 0,
+                    time: host,
+                    period: (host - at) / (pulse - previous) as Float,
+                })
+            });
+        if let Some(line) = self.line {
+            self.period = Some(line.period);
+        }
+    }
+
+    pub fn tempo(&self) -> Option<Float> {
+        self.period
+            .map(|period| 60.0 / (period * PULSES_PER_BEAT as Float))
+    }
+
+    /// Beats since the start at host time `host`, while the clock is running
+    pub fn beat_at(&self, host: Float) -> Option<Float> {
+        let line = self.line?;
+        let pulses =
+            ((host - line.time) / line.period).clamp(-MAX_EXTRAPOLATION, MAX_EXTRAPOLATION);
+        Some((line.pulse as Float + pulses) / PULSES_PER_BEAT as Float)
+    }
+}
+
+// what the bevy side has to do to the transport when following
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Transport {
+    Start,
+    Continue,
+    Stop,
+    SongPosition(u16),
+    /// Seconds of timecode at a host time
+    Timecode(Float, Float),
+}
+
+// written by the MIDI thread
+static FOLLOWER: Lazy<Mutex<ClockFollower>> = Lazy::new(Default::default);
+static MTC_READER: Lazy<Mutex<MtcReader>> = Lazy::new(Default::default);
+static TRANSPORT: SegQueue<Transport> = SegQueue::new();
+
+/// Take in a sync message that arrived at host time `host`
+pub fn handle_message(message: MidiMessage, host: Float) {
+    let mut follower = FOLLOWER.lock().unwrap();
+    match message {
+        MidiMessage::Clock => follower.pulse(host),
+        MidiMessage::Start => {
+            follower.locate(0);
+            TRANSPORT.push(Transport::Start);
+        }
+        MidiMessage::Continue => TRANSPORT.push(Transport::Continue),
+        MidiMessage::Stop => {
+            follower.stop();
+            TRANSPORT.push(Transport::Stop);
+        }
+        MidiMessage::SongPosition { sixteenths } => {
+            follower.locate(sixteenths as i64 * PULSES_PER_SIXTEENTH);
+            TRANSPORT.push(Transport::SongPosition(sixteenths));
+        }
+        MidiMessage::QuarterFrame { piece, value } => {
+            if let Some(seconds) = MTC_READER.lock().unwrap().quarter_frame(piece, value) {
+                TRANSPORT.push(Transport::Timecode(seconds, host));
+            }
+        }
+        MidiMessage::FullFrame(timecode) => {
+            *MTC_READER.lock().unwrap() = Default::default();
+            TRANSPORT.push(Transport::Timecode(timecode.seconds(), host));
+        }
+        _ => {}
+    }
+}
+
+/// Works out what to send for the transport, driven by the audio time being heard
+#[derive(Clone, Debug, Default)]
+pub struct ClockMaster {
+    // the last pulse sent, None while the followers are stopped
+    pulse: Option<i64>,
+    // the last quarter frame sent
+    quarter: Option<i64>,
+}
+
+impl ClockMaster {
+    /// Push the messages due by audio time `time` onto `out`, returning when the next one is due
+    pub fn step(
+        &mut self,
+        time: Float,
+        playing: bool,
+        send_mtc: bool,
+        out: &mut Vec<MidiMessage>,
+    ) -> Float {
+        if !playing {
+            if self.pulse.take().is_some() {
+                out.push(MidiMessage::Stop);
+            }
+            self.quarter = None;
+            return time + MAX_SLEEP;
+        }
+        // pulses follow the beats through tempo changes, rather than starting over at the new tempo
+        let timeline = timeline();
+        let due = (timeline.beats(time) * PULSES_PER_BEAT as Float).floor() as i64;
+        match self.pulse {
+            // the smoothed clock can wobble back over a pulse
+            Some(last) if due == last || due == last - 1 => {}
+            // a late wake up sends the pulses it missed
+            Some(last) if due > last && due - last <= PULSES_PER_SIXTEENTH => {
+                out.extend((last..due).map(|_| MidiMessage::Clock));
+                self.pulse = Some(due);
+            }
+            // the transport jumped, the followers start again from the next sixteenth
+            Some(_) => {
+                out.push(MidiMessage::Stop);
+                self.pulse = None;
+            }
+            None => {}
+        }
+        if self.pulse.is_none() && due >= 0 && due % PULSES_PER_SIXTEENTH == 0 {
+            if due == 0 {
+                out.push(MidiMessage::Start);
+            } else {
+                let sixteenths = (due / PULSES_PER_SIXTEENTH).min(0x3fff) as u16;
+                out.push(MidiMessage::SongPosition { sixteenths });
+                out.push(MidiMessage::Continue);
+            }
+            out.push(MidiMessage::Clock);
+            self.pulse = Some(due);
+        }
+        let next_pulse = if self.pulse.is_some() {
+            due + 1
+        } else {
+            (due.max(0) / PULSES_PER_SIXTEENTH + 1) * PULSES_PER_SIXTEENTH
+        };
+        let next = timeline.time_at(next_pulse as Float / PULSES_PER_BEAT as Float);
+
+        if !send_mtc {
+            self.quarter = None;
+            return next;
+        }
+        let quarters_per_second = MTC_FPS as Float * 4.0;
+        let quarter = (time * quarters_per_second).floor() as i64;
+        match self.quarter {
+            // wobbling back, or waiting for the next piece 0 after a full frame
+            Some(last) if (last - 8..=last).contains(&quarter) => {}
+            Some(last) if quarter > last && quarter - last <= 8 => {
+                for quarter in last + 1..=quarter {
+                    let piece = quarter.rem_euclid(8);
+                    let timecode = Timecode::from_frames((quarter - piece) / 4);
+                    out.push(MidiMessage::QuarterFrame {
+                        piece: piece as u8,
+                        value: timecode.piece(piece as u8),
+                    });
+                }
+                self.quarter = Some(quarter);
+            }
+            // started or jumped, quarter frames carry on from the next piece 0
+            _ => {
+                out.push(MidiMessage::FullFrame(Timecode::from_frames(quarter / 4)));
+                self.quarter = Some(quarter - quarter.rem_euclid(8) + 7);
+            }
+        }
+        next.min((quarter + 1) as Float / quarters_per_second)
+    }
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(target_arch = "wasm32")] {
+        pub struct ClockSender;
+
+        impl ClockSender {
+            pub fn start(_output: &str) -> anyhow::Result<Self> {
+                // todo_major: web MIDI
+                anyhow::bail!("MIDI output isn't supported on the web yet")
+            }
+
+            pub fn set_latency(&self, _latency: Float) {}
+
+            pub fn set_send_mtc(&self, _send_mtc: bool) {}
+        }
+
+        pub fn output_ports() -> Vec<String> {
+            Vec::new()
+        }
+    } else {
+        use std::{
+            sync::{
+                atomic::{AtomicBool, AtomicU64, Ordering},
+                Arc,
+            },
+            thread::JoinHandle,
+            time::Duration,
+        };
+
+        use anyhow::{anyhow, Context};
+        use midir::{MidiOutput, MidiOutputConnection};
+
+        use crate::sound::paused;
+
+        /// Sends clock, and MTC if asked, from its own thread until dropped
+        pub struct ClockSender {
+            stop: Arc<AtomicBool>,
+            latency: Arc<AtomicU64>,
+            send_mtc: Arc<AtomicBool>,
+            thread: Option<JoinHandle<()>>,
+        }
+
+        #[cfg(unix)]
+        fn virtual_output(output: MidiOutput) -> anyhow::Result<MidiOutputConnection> {
+            use midir::os::unix::VirtualOutput;
+            output
+                .create_virtual(CLOCK_PORT_NAME)
+                .map_err(|e| anyhow!("{e}"))
+        }
+
+        #[cfg(not(unix))]
+        fn virtual_output(_output: MidiOutput) -> anyhow::Result<MidiOutputConnection> {
+            anyhow::bail!("virtual MIDI ports aren't supported here, pick an output port")
+        }
+
+        /// Names of the ports the clock can go to
+        pub fn output_ports() -> Vec<String> {
+            let Ok(output) = MidiOutput::new("sonars") else {
+                return Vec::new();
+            };
+            output
+                .ports()
+                .iter()
+                .filter_map(|port| output.port_name(port).ok())
+                .filter(|name| !name.contains(CLOCK_PORT_NAME))
+                .collect()
+        }
+
+        impl ClockSender {
+            /// Send to the port called `output`, or from a virtual port called
+            /// [`CLOCK_PORT_NAME`] when it's empty
+            pub fn start(output: &str) -> anyhow::Result<Self> {
+                let midi = MidiOutput::new("sonars")?;
+                let mut connection = if output.is_empty() {
+                    virtual_output(midi)?
+                } else {
+                    let port = midi
+                        .ports()
+                        .into_iter()
+                        .find(|port| midi.port_name(port).is_ok_and(|name| name == output))
+                        .with_context(|| format!("no MIDI output port {output:?}"))?;
+                    midi.connect(&port, "sonars clock")
+                        .map_err(|e| anyhow!("{e}"))?
+                };
+
+                let stop = Arc::new(AtomicBool::new(false));
+                let latency = Arc::new(AtomicU64::new(0.0f64.to_bits()));
+                let send_mtc = Arc::new(AtomicBool::new(false));
+                let thread = {
+                    let (stop, latency, send_mtc) = (stop.clone(), latency.clone(), send_mtc.clone());
+                    std::thread::spawn(move || {
+                        let mut master = ClockMaster::default();
+                        let mut out = Vec::new();
+                        while !stop.load(Ordering::Relaxed) {
+                            // what is being heard now, which the followers play along to
+                            let latency = Float::from_bits(latency.load(Ordering::Relaxed));
+                            let time = clock::now() - latency;
+                            let playing = !paused();
+                            let next =
+                                master.step(time, playing, send_mtc.load(Ordering::Relaxed), &mut out);
+                            for message in out.drain(..) {
+                                if let Err(e) = connection.send(&message.encode()) {
+                                    warn!("MIDI clock output failed: {e}");
+                                    return;
+                                }
+                            }
+                            let sleep = (next - time).clamp(0.0, MAX_SLEEP);
+                            std::thread::sleep(Duration::from_secs_f64(sleep));
+                        }
+                        // rather than leaving the followers waiting on pulses
+                        let _ = connection.send(&MidiMessage::Stop.encode());
+                    })
+                };
+                Ok(Self {
+                    stop,
+                    latency,
+                    send_mtc,
+                    thread: Some(thread),
+                })
+            }
+
+            /// Seconds between the audio being rendered and heard
+            pub fn set_latency(&self, latency: Float) {
+                self.latency.store(latency.to_bits(), Ordering::Relaxed);
+            }
+
+            pub fn set_send_mtc(&self, send_mtc: bool) {
+                self.send_mtc.store(send_mtc, Ordering::Relaxed);
+            }
+        }
+
+        impl Drop for ClockSender {
+            fn drop(&mut self) {
+                self.stop.store(true, Ordering::Relaxed);
+                if let Some(thread) = self.thread.take() {
+                    let _ = thread.join();
+                }
+            }
+        }
+    }
+}
+
+pub struct MidiSyncPlugin;
+
+impl Plugin for MidiSyncPlugin {
+    fn build(&self, app: &mut bevy::prelude::App) {
+        app.init_resource::<MidiSyncSettings>()
+            .init_resource::<MidiSync>()
+            .add_systems(Update, (update_sender, follow_clock).chain());
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SyncMode {
+    #[default]
+    Off,
+    /// Send clock for other devices to follow
+    Master,
+    /// Take the tempo and transport from incoming clock
+    Follow,
+}
+
+#[derive(Resource, Clone, Debug, Default, PartialEq)]
+pub struct MidiSyncSettings {
+    pub mode: SyncMode,
+    /// Send MTC as well as clock as the master
+    pub send_mtc: bool,
+    /// Port the clock goes to, empty for a virtual port called [`CLOCK_PORT_NAME`]
+    pub output: String,
+}
+
+#[derive(Resource, Default)]
+pub struct MidiSync {
+    pub sender: Option<ClockSender>,
+    pub error: Option<String>,
+    /// Ports the clock can go to, listed when the settings change
+    pub outputs: Vec<String>,
+    // host time of the last phase correction
+    corrected_at: Float,
+}
+
+impl MidiSync {
+    /// Tempo of the incoming clock, if any has come in
+    pub fn followed_tempo(&self) -> Option<Float> {
+        FOLLOWER.lock().unwrap().tempo()
+    }
+}
+
+fn update_sender(
+    settings: Res<MidiSyncSettings>,
+    mut sync: ResMut<MidiSync>,
+    mut started: Local<Option<(SyncMode, String)>>,
+    sound_resources: Option<Res<SoundResources>>,
+) {
+    if settings.is_changed() {
+        sync.outputs = output_ports();
+    }
+    // the MTC setting doesn't need a new port
+    let wanted = (settings.mode, settings.output.clone());
+    if started.as_ref() != Some(&wanted) {
+        *started = Some(wanted);
+        sync.sender = None;
+        sync.error = None;
+        if settings.mode == SyncMode::Master {
+            match ClockSender::start(&settings.output) {
+                Ok(sender) => {
+                    info!("Sending MIDI clock");
+                    sync.sender = Some(sender);
+                }
+                Err(e) => {
+                    warn!("MIDI clock output unavailable: {e:#}");
+                    sync.error = Some(format!("{e:#}"));
+                }
+            }
+        }
+    }
+    if let Some(sender) = &sync.sender {
+        sender.set_send_mtc(settings.send_mtc);
+        sender.set_latency(sound_resources.map_or(0.0, |resources| resources.output_latency()));
+    }
+}
+
+/// Keep the tempo, beats and transport with the incoming clock and timecode
+fn follow_clock(
+    settings: Res<MidiSyncSettings>,
+    mut sync: ResMut<MidiSync>,
+    mut sound_control: ResMut<SoundControl>,
+    sound_resources: Option<Res<SoundResources>>,
+) {
+    let following = settings.mode == SyncMode::Follow;
+    // drained either way, so nothing stale is acted on when following starts
+    while let Some(event) = TRANSPORT.pop() {
+        if !following {
+            continue;
+        }
+        match event {
+            Transport::Start => {
+                // the song starts on beat 0 whatever tempo changes came before
+                set_timeline(Timeline {
+                    beat: 0.0,
+                    time: 0.0,
+                    ..timeline()
+                });
+                sound_control.seek(0.0);
+                sound_control.play();
+            }
+            Transport::Continue => sound_control.play(),
+            Transport::Stop => sound_control.pause(),
+            Transport::SongPosition(sixteenths) => {
+                sound_control.seek(timeline().time_at(sixteenths as Float / 4.0));
+            }
+            Transport::Timecode(seconds, at) => {
+                let seconds = seconds + (load::now() - at);
+                if (seconds - sound_control.time()).abs() > MTC_TOLERANCE / MTC_FPS as Float {
+                    sound_control.seek(seconds);
+                }
+            }
+        }
+    }
+    if !following {
+        return;
+    }
+
+    let follower = FOLLOWER.lock().unwrap().clone();
+    if let Some(followed) = follower.tempo() {
+        if (followed - tempo()).abs() > TEMPO_TOLERANCE {
+            set_tempo((followed * 10.0).round() / 10.0);
+        }
+    }
+
+    // a loop wraps wherever it ends, keeping it in phase would undo it
+    if sound_control.is_paused() || sound_control.loop_region().is_some() {
+        return;
+    }
+    let (host, time) = (load::now(), clock::now());
+    if host - sync.corrected_at < CORRECTION_SETTLE {
+        return;
+    }
+    // what is rendered now is heard this much later
+    let latency = sound_resources.map_or(0.0, |resources| resources.output_latency());
+    let Some(beat) = follower.beat_at(host + latency) else {
+        return;
+    };
+    let error = beat_error(beat, time);
+    if error.abs() > PHASE_TOLERANCE {
+        sound_control.shift(error * 60.0 / tempo());
+        sync.corrected_at = host;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ClockFollower, ClockMaster, MtcReader, Timecode, MTC_FPS};
+    use crate::{
+        midi::MidiMessage,
+        sound::{set_tempo_at, set_timeline, tempo, Float, Timeline},
+    };
+
+    #[test]
+    fn test_timecode() {
+        let timecode = Timecode::from_frames((3723 * MTC_FPS) + 12);
+        assert_eq!(
+            timecode,
+            Timecode {
+                hours: 1,
+                minutes: 2,
+                seconds: 3,
+                frames: 12,
+                rate: 1
+            }
+        );
+        assert_eq!(timecode.seconds(), 3723.48);
+
+        let mut reader = MtcReader::default();
+        // a stray piece is ignored until the next piece 0
+        assert_eq!(reader.quarter_frame(5, 1), None);
+        for piece in 0..7 {
+            assert_eq!(reader.quarter_frame(piece, timecode.piece(piece)), None);
+        }
+        let seconds = reader.quarter_frame(7, timecode.piece(7)).unwrap();
+        assert!((seconds - (3723.48 + 0.07)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_follower() {
+        let mut follower = ClockFollower::default();
+        assert_eq!(follower.tempo(), None);
+        follower.locate(0);
+        // 128bpm with up to a millisecond of jitter either way
+        let period = 60.0 / (128.0 * 24.0);
+        let mut seed = 1u32;
+        let mut jitter = || {
+            seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            (seed >> 8) as f64 / (1 << 24) as f64 * 0.002 - 0.001
+        };
+        for pulse in 0..24 * 32 {
+            follower.pulse(10.0 + pulse as f64 * period + jitter());
+        }
+        assert!((follower.tempo().unwrap() - 128.0).abs() < 0.1);
+        // 32 beats in, half a pulse after the last one
+        let beat = follower
+            .beat_at(10.0 + (24.0 * 32.0 - 0.5) * period)
+            .unwrap();
+        assert!((beat - (32.0 - 1.0 / 48.0)).abs() < 0.01);
+
+        // a new song position and a much slower tempo are picked up straight away
+        follower.stop();
+        follower.locate(48);
+        assert_eq!(follower.beat_at(20.0), None);
+        let period = 60.0 / (90.0 * 24.0);
+        for pulse in 0..48 {
+            follower.pulse(20.0 + pulse as f64 * period + jitter());
+        }
+        assert!((follower.tempo().unwrap() - 90.0).abs() < 0.5);
+        assert!((follower.beat_at(20.0 + 47.0 * period).unwrap() - 3.958).abs() < 0.02);
+    }
+
+    #[test]
+    fn test_master() {
+        let _globals = crate::lock_globals();
+        // at the default 120bpm a pulse is 1/48s
+        let pulse = 60.0 / (tempo() * 24.0);
+        let mut master = ClockMaster::default();
+        let mut out = Vec::new();
+
+        master.step(0.0, true, false, &mut out);
+        assert_eq!(out, [MidiMessage::Start, MidiMessage::Clock]);
+        out.clear();
+        let next = master.step(0.5 * pulse, true, false, &mut out);
+        assert!(out.is_empty());
+        assert!((next - pulse).abs() < 1e-9);
+        master.step(1.5 * pulse, true, false, &mut out);
+        assert_eq!(out, [MidiMessage::Clock]);
+        out.clear();
+        master.step(3.5 * pulse, true, false, &mut out);
+        assert_eq!(out, [MidiMessage::Clock, MidiMessage::Clock]);
+        out.clear();
+
+        // a jump stops the followers and carries on from the next sixteenth
+        master.step(100.5 * pulse, true, false, &mut out);
+        assert_eq!(out, [MidiMessage::Stop]);
+        out.clear();
+        master.step(102.5 * pulse, true, false, &mut out);
+        assert_eq!(
+            out,
+            [
+                MidiMessage::SongPosition { sixteenths: 17 },
+                MidiMessage::Continue,
+                MidiMessage::Clock
+            ]
+        );
+        out.clear();
+        master.step(102.5 * pulse, false, false, &mut out);
+        assert_eq!(out, [MidiMessage::Stop]);
+        out.clear();
+
+        // timecode starts with a full frame, then quarter frames from the next piece 0
+        master.step(0.0, true, true, &mut out);
+        assert_eq!(out[2], MidiMessage::FullFrame(Timecode::from_frames(0)));
+        out.clear();
+        master.step(0.081, true, true, &mut out);
+        assert!(out.contains(&MidiMessage::QuarterFrame { piece: 0, value: 2 }));
+    }
+
+    #[test]
+    fn test_master_tempo_change() {
+        let _globals = crate::lock_globals();
+        let mut master = ClockMaster::default();
+        let mut out = Vec::new();
+        let mut run = |from: usize, to: usize, out: &mut Vec<_>| {
+            for ms in (from..to).step_by(10) {
+                master.step(ms as Float / 1000.0, true, false, out);
+            }
+        };
+        // 2 beats at 120bpm, then half a beat at 60bpm with no jump in between
+        run(0, 1_000, &mut out);
+        set_tempo_at(60.0, 1.0);
+        run(1_000, 1_510, &mut out);
+        set_timeline(Timeline::default());
+        assert!(!out.contains(&MidiMessage::Stop));
+        let clocks = out.iter().filter(|m| **m == MidiMessage::Clock).count();
+        assert_eq!(clocks, 2 * 24 + 12 + 1);
+    }
+
+    /// Listens to the virtual clock port like another program would, skipped when there's no MIDI
+    /// system
+    #[cfg(unix)]
+    #[test]
+    fn test_clock_port() {
+        use std::sync::{Arc, Mutex};
+
+        use midir::{Ignore, MidiInput};
+
+        let sender = match super::ClockSender::start("") {
+            Ok(sender) => sender,
+            Err(e) => {
+                eprintln!("skipping, no MIDI: {e}");
+                return;
+            }
+        };
+        let mut input = MidiInput::new("sonars test").unwrap();
+        input.ignore(Ignore::None);
+        let port = input
+            .ports()
+            .into_iter()
+            .find(|p| {
+                input
+                    .port_name(p)
+                    .is_ok_and(|n| n.contains(super::CLOCK_PORT_NAME))
+            })
+            .expect("clock port should be visible");
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let _connection = input
+            .connect(
+                &port,
+                "test",
+                |_, bytes, received: &mut Arc<Mutex<Vec<MidiMessage>>>| {
+                    received.lock().unwrap().extend(MidiMessage::parse(bytes));
+                },
+                received.clone(),
+            )
+            .unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        drop(sender);
+        std::thread::sleep(std::time::Duration::from_millis(50));
+
+        // the transport may or may not be running, but the followers are always left stopped
+        assert_eq!(received.lock().unwrap().last(), Some(&MidiMessage::Stop));
+    }
+}
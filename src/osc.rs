@@ -0,0 +1,477 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use bevy::{
+    app::Update,
+    ecs::{change_detection::DetectChanges, event::EventWriter},
+    log::{info, warn},
+    prelude::{Plugin, Res, ResMut, Resource},
+};
+use crossbeam_queue::SegQueue;
+
+use crate::{
+    lang::SubmitCode,
+    sound::{params::set_param, SoundControl},
+    visuals::{VisualsControls, VisualsField},
+};
+
+pub const DEFAULT_OSC_PORT: u16 = 7770;
+// how often the listener thread checks whether it should stop
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+pub struct OscPlugin;
+
+impl Plugin for OscPlugin {
+    fn build(&self, app: &mut bevy::prelude::App) {
+        app.init_resource::<OscSettings>()
+            .init_resource::<OscServer>()
+            .add_systems(Update, (update_server, apply_messages));
+    }
+}
+
+/// Changes restart the server
+#[derive(Resource, Clone, Debug, PartialEq)]
+pub struct OscSettings {
+    pub port: u16,
+    /// Listen on every interface instead of just localhost
+    pub allow_remote: bool,
+}
+
+impl Default for OscSettings {
+    fn default() -> Self {
+        Self {
+            port: DEFAULT_OSC_PORT,
+            allow_remote: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum OscArg {
+    Int(i32),
+    Long(i64),
+    Float(f32),
+    Double(f64),
+    Str(String),
+    Blob(Vec<u8>),
+    Bool(bool),
+    Nil,
+}
+
+impl OscArg {
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            OscArg::Int(x) => Some(*x as f64),
+            OscArg::Long(x) => Some(*x as f64),
+            OscArg::Float(x) => Some(*x as f64),
+            OscArg::Double(x) => Some(*x),
+            OscArg::Bool(x) => Some(*x as u8 as f64),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            OscArg::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn tag(&self) -> char {
+        match self {
+            OscArg::Int(_) => 'i',
+            OscArg::Long(_) => 'h',
+            OscArg::Float(_) => 'f',
+            OscArg::Double(_) => 'd',
+            OscArg::Str(_) => 's',
+            OscArg::Blob(_) => 'b',
+            OscArg::Bool(true) => 'T',
+            OscArg::Bool(false) => 'F',
+            OscArg::Nil => 'N',
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct OscMessage {
+    pub address: String,
+    pub args: Vec<OscArg>,
+}
+
+impl OscMessage {
+    pub fn new(address: impl Into<String>, args: Vec<OscArg>) -> Self {
+        Self {
+            address: address.into(),
+            args,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![];
+        write_str(&mut out, &self.address);
+        let tags: String = std::iter::once(',')
+            .chain(self.args.iter().map(OscArg::tag))
+            .collect();
+        write_str(&mut out, &tags);
+        for arg in &self.args {
+            match arg {
+                OscArg::Int(x) => out.extend(x.to_be_bytes()),
+                OscArg::Long(x) => out.extend(x.to_be_bytes()),
+                OscArg::Float(x) => out.extend(x.to_be_bytes()),
+                OscArg::Double(x) => out.extend(x.to_be_bytes()),
+                OscArg::Str(s) => write_str(&mut out, s),
+                OscArg::Blob(b) => {
+                    out.extend((b.len() as i32).to_be_bytes());
+                    out.extend(b);
+                    pad(&mut out);
+                }
+                OscArg::Bool(_) | OscArg::Nil => {}
+            }
+        }
+        out
+    }
+}
+
+fn pad(out: &mut Vec<u8>) {
+    out.resize(out.len().next_multiple_of(4), 0);
+}
+
+fn write_str(out: &mut Vec<u8>, s: &str) {
+    out.extend(s.as_bytes());
+    out.push(0);
+    pad(out);
+}
+
+/// Reads the 4 byte aligned fields of a packet
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> anyhow::Result<&'a [u8]> {
+        if n > self.bytes.len() {
+            bail!("packet ended early");
+        }
+        let (taken, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn array<const N: usize>(&mut self) -> anyhow::Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn string(&mut self) -> anyhow::Result<String> {
+        let len = self
+            .bytes
+            .iter()
+            .position(|b| *b == 0)
+            .context("string isn't terminated")?;
+        let s = std::str::from_utf8(&self.bytes[..len])?.to_string();
+        self.take((len + 1).next_multiple_of(4))?;
+        Ok(s)
+    }
+
+    fn blob(&mut self) -> anyhow::Result<Vec<u8>> {
+        let len = i32::from_be_bytes(self.array()?).max(0) as usize;
+        let blob = self.take(len)?.to_vec();
+        self.take(len.next_multiple_of(4) - len)?;
+        Ok(blob)
+    }
+}
+
+/// Decode a packet, flattening bundles into their messages. Time tags are ignored, everything applies immediately
+pub fn decode_packet(bytes: &[u8]) -> anyhow::Result<Vec<OscMessage>> {
+    let mut reader = Reader { bytes };
+    if bytes.starts_with(b"#bundle\0") {
+        reader.take(16)?; // "#bundle" and the time tag
+        let mut messages = vec![];
+        while !reader.bytes.is_empty() {
+            let element = reader.blob()?;
+            messages.extend(decode_packet(&element)?);
+        }
+        return Ok(messages);
+    }
+
+    let address = reader.string()?;
+    if !address.starts_with('/') {
+        bail!("{address:?} isn't an OSC address");
+    }
+    // some old senders leave out the type tags when there are no arguments
+    let tags = if reader.bytes.is_empty() {
+        ",".to_string()
+    } else {
+        reader.string()?
+    };
+    let args = tags
+        .chars()
+        .skip(1)
+        .map(|tag| {
+            Ok(match tag {
+                'i' => OscArg::Int(i32::from_be_bytes(reader.array()?)),
+                'h' => OscArg::Long(i64::from_be_bytes(reader.array()?)),
+                'f' => OscArg::Float(f32::from_be_bytes(reader.array()?)),
+                'd' => OscArg::Double(f64::from_be_bytes(reader.array()?)),
+                's' | 'S' => OscArg::Str(reader.string()?),
+                'b' => OscArg::Blob(reader.blob()?),
+                'T' => OscArg::Bool(true),
+                'F' => OscArg::Bool(false),
+                'N' | 'I' => OscArg::Nil,
+                x => bail!("unsupported OSC type tag {x:?}"),
+            })
+        })
+        .collect::<anyhow::Result<_>>()?;
+    Ok(vec![OscMessage { address, args }])
+}
+
+/// A UDP socket read on its own thread, stopped when dropped
+pub struct Listener {
+    addr: SocketAddr,
+    queue: Arc<SegQueue<OscMessage>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Listener {
+    pub fn start(settings: &OscSettings) -> anyhow::Result<Self> {
+        let ip = if settings.allow_remote {
+            Ipv4Addr::UNSPECIFIED
+        } else {
+            Ipv4Addr::LOCALHOST
+        };
+        let socket = UdpSocket::bind((ip, settings.port))
+            .with_context(|| format!("binding OSC port {}", settings.port))?;
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+        let addr = socket.local_addr()?;
+        let queue: Arc<SegQueue<OscMessage>> = Default::default();
+        let stop: Arc<AtomicBool> = Default::default();
+
+        let thread = std::thread::spawn({
+            let queue = queue.clone();
+            let stop = stop.clone();
+            move || {
+                let mut buf = [0; 65_536];
+                while !stop.load(Ordering::Relaxed) {
+                    let Ok((len, from)) = socket.recv_from(&mut buf) else {
+                        continue;
+                    };
+                    match decode_packet(&buf[..len]) {
+                        Ok(messages) => messages.into_iter().for_each(|m| queue.push(m)),
+                        Err(e) => warn!("Bad OSC packet from {from}: {e:#}"),
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            addr,
+            queue,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn pop(&self) -> Option<OscMessage> {
+        self.queue.pop()
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        // wait for the socket to close, so the port can be bound again straight away
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct OscServer {
+    pub listener: Option<Listener>,
+    pub error: Option<String>,
+}
+
+fn update_server(settings: Res<OscSettings>, mut server: ResMut<OscServer>) {
+    if !settings.is_changed() {
+        return;
+    }
+    server.listener = None;
+    match Listener::start(&settings) {
+        Ok(listener) => {
+            info!("Listening for OSC on {}", listener.addr());
+            server.listener = Some(listener);
+            server.error = None;
+        }
+        Err(e) => {
+            warn!("OSC unavailable: {e:#}");
+            server.error = Some(format!("{e:#}"));
+        }
+    }
+}
+
+fn apply_messages(
+    server: Res<OscServer>,
+    mut sound_control: ResMut<SoundControl>,
+    mut controls: ResMut<VisualsControls>,
+    mut code: EventWriter<SubmitCode>,
+) {
+    let Some(listener) = &server.listener else {
+        return;
+    };
+    while let Some(message) = listener.pop() {
+        let path: Vec<&str> = message.address[1..].split('/').collect();
+        let first = message.args.first();
+        match (path.as_slice(), first) {
+            (["play"], _) => sound_control.play(),
+            (["pause"], _) => sound_control.pause(),
+            (["seek" | "bar"], Some(arg)) => match arg.as_f64() {
+                Some(time) if path[0] == "seek" => sound_control.seek(time),
+                Some(bar) => sound_control.seek_bar(bar),
+                None => warn!("Bad OSC message {message:?}"),
+            },
+            // a start and an end, or nothing to stop looping
+            (["loop"] | ["loop", "bars"], _) => {
+                let bounds: Option<Vec<f64>> = message.args.iter().map(OscArg::as_f64).collect();
+                let region = match bounds.as_deref() {
+                    Some([]) => None,
+                    Some(&[start, end]) => Some((start, end)),
+                    _ => {
+                        warn!("Bad OSC message {message:?}");
+                        continue;
+                    }
+                };
+                match path.len() {
+                    1 => sound_control.set_loop(region),
+                    _ => sound_control.set_loop_bars(region),
+                }
+            }
+            // evaluated by the language, errors end up in `LangStatus` like any other submission
+            (["code"], Some(OscArg::Str(source))) => {
+                code.send(SubmitCode::new(source.clone()));
+            }
+            (["code", track], Some(OscArg::Str(source))) => {
+                code.send(SubmitCode {
+                    source: source.clone(),
+                    track: track.to_string(),
+                });
+            }
+            (["track" | "bus", name, control], Some(arg)) => {
+                let tracks = sound_control.tracks();
+                let strip = match path[0] {
+                    "track" => tracks.get(name).map(|track| &track.strip),
+                    _ => tracks.bus(name).map(|bus| &bus.strip),
+                };
+                match (strip, arg.as_f64()) {
+                    (Some(strip), Some(value)) => match *control {
+                        "gain" => strip.set_gain(value),
+                        "pan" => strip.set_pan(value),
+                        "mute" => strip.set_muted(value != 0.0),
+                        "solo" => strip.set_soloed(value != 0.0),
+                        _ => warn!("Unknown OSC message {message:?}"),
+                    },
+                    _ => warn!("Bad OSC message {message:?}"),
+                }
+            }
+            (["send" | "feedback", from, to], Some(arg)) => match arg.as_f64() {
+                Some(level) => {
+                    let feedback = path[0] == "feedback";
+                    if let Err(e) = sound_control.set_send(from, to, level, feedback) {
+                        warn!("Bad OSC message {message:?}: {e}");
+                    }
+                }
+                None => warn!("Bad OSC message {message:?}"),
+            },
+            (["visuals", name], Some(arg)) => match (VisualsField::from_name(name), arg.as_f64()) {
+                (Some(field), Some(value)) => controls.set(field, value),
+                _ => warn!("Bad OSC message {message:?}"),
+            },
+            (["param", name], Some(arg)) => match arg.as_f64() {
+                Some(value) if set_param(name, value) => {}
+                _ => warn!("Bad OSC message {message:?}"),
+            },
+            _ => warn!("Unknown OSC message {message:?}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::UdpSocket, time::Instant};
+
+    use super::{decode_packet, Listener, OscArg, OscMessage, OscSettings};
+    use crate::lang::evaluate;
+
+    #[test]
+    fn test_encode_decode() {
+        let message = OscMessage::new(
+            "/visuals/wave_height_scale",
+            vec![
+                OscArg::Float(0.5),
+                OscArg::Int(-3),
+                OscArg::Str("abcd".into()),
+                OscArg::Blob(vec![1, 2, 3]),
+                OscArg::Double(2.0),
+                OscArg::Bool(true),
+            ],
+        );
+        let bytes = message.encode();
+        assert_eq!(bytes.len() % 4, 0);
+        assert_eq!(&bytes[..4], b"/vis");
+        assert_eq!(decode_packet(&bytes).unwrap(), vec![message]);
+
+        // "/play" with no type tags at all
+        assert_eq!(
+            decode_packet(b"/play\0\0\0").unwrap(),
+            vec![OscMessage::new("/play", vec![])]
+        );
+        assert!(decode_packet(b"play\0\0\0\0").is_err());
+        assert!(decode_packet(b"/x\0\0,f\0\0\0\0").is_err());
+    }
+
+    #[test]
+    fn test_udp_client() {
+        let listener = Listener::start(&OscSettings {
+            port: 0,
+            allow_remote: false,
+        })
+        .unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        let pause = OscMessage::new("/pause", vec![]);
+        let code = OscMessage::new("/code", vec![OscArg::Str("sin(440 * t)".into())]);
+        client.send_to(&pause.encode(), listener.addr()).unwrap();
+
+        // a bundle with a time tag of "immediately"
+        let mut bundle = b"#bundle\0".to_vec();
+        bundle.extend(1u64.to_be_bytes());
+        let element = code.encode();
+        bundle.extend((element.len() as i32).to_be_bytes());
+        bundle.extend(element);
+        client.send_to(&bundle, listener.addr()).unwrap();
+
+        let mut received = vec![];
+        let start = Instant::now();
+        while received.len() < 2 && start.elapsed().as_secs() < 2 {
+            received.extend(listener.pop());
+        }
+        assert_eq!(received, vec![pause, code]);
+        // and the code it carried plays
+        let Some(OscArg::Str(source)) = received[1].args.first() else {
+            panic!()
+        };
+        assert!(evaluate(source).is_ok());
+    }
+}
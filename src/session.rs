@@ -0,0 +1,514 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use bevy::{
+    app::Update,
+    ecs::event::{Event, EventReader, EventWriter},
+    log::{info, warn},
+    prelude::{Plugin, Res, ResMut, Resource},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    lang::{evaluate, LangStatus, SubmitCode},
+    sound::{
+        beats_per_bar,
+        effects::EffectSpec,
+        mixer::{AuxSend, Strip, MAIN_TRACK},
+        params::{params, register_param, ParamSpec},
+        set_beats_per_bar, set_tempo, tempo, Float, SoundControl, DEFAULT_BEATS_PER_BAR,
+        DEFAULT_TEMPO,
+    },
+    visuals::VisualsControls,
+};
+
+pub const DEFAULT_SESSION_PATH: &str = "session.ron";
+
+pub struct SessionPlugin;
+
+impl Plugin for SessionPlugin {
+    fn build(&self, app: &mut bevy::prelude::App) {
+        app.add_event::<SaveSession>()
+            .add_event::<LoadSession>()
+            .init_resource::<SessionFile>()
+            .add_systems(Update, (save_sessions, load_sessions));
+    }
+}
+
+/// Everything needed to pick up where we left off, stored as RON
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Session {
+    pub code: String,
+    pub params: BTreeMap<String, SavedParam>,
+    pub tempo: Float,
+    pub beats_per_bar: u32,
+    /// Transport position in seconds
+    pub position: Float,
+    /// Loop start and end in seconds
+    pub loop_region: Option<(Float, Float)>,
+    pub paused: bool,
+    pub visuals: VisualsControls,
+    /// Every track in mixing order, the main track's code is [`Self::code`]
+    pub tracks: Vec<SavedTrack>,
+    /// Aux buses in the order they were added
+    pub buses: Vec<SavedBus>,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self {
+            code: Default::default(),
+            params: Default::default(),
+            tempo: DEFAULT_TEMPO,
+            beats_per_bar: DEFAULT_BEATS_PER_BAR,
+            position: 0.0,
+            loop_region: None,
+            paused: false,
+            visuals: Default::default(),
+            tracks: vec![],
+            buses: vec![],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SavedParam {
+    pub spec: ParamSpec,
+    pub value: Float,
+}
+
+/// A track or bus's mixer strip
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SavedStrip {
+    pub gain: Float,
+    pub pan: Float,
+    pub muted: bool,
+    pub soloed: bool,
+}
+
+impl Default for SavedStrip {
+    fn default() -> Self {
+        Self {
+            gain: 1.0,
+            pan: 0.0,
+            muted: false,
+            soloed: false,
+        }
+    }
+}
+
+impl SavedStrip {
+    fn capture(strip: &Strip) -> Self {
+        Self {
+            gain: strip.gain(),
+            pan: strip.pan(),
+            muted: strip.muted(),
+            soloed: strip.soloed(),
+        }
+    }
+
+    fn apply(&self, strip: &Strip) {
+        strip.set_gain(self.gain);
+        strip.set_pan(self.pan);
+        strip.set_muted(self.muted);
+        strip.set_soloed(self.soloed);
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SavedSend {
+    pub to: String,
+    pub level: Float,
+    #[serde(default)]
+    pub feedback: bool,
+}
+
+impl SavedSend {
+    fn capture(sends: &[AuxSend]) -> Vec<Self> {
+        sends
+            .iter()
+            .map(|send| Self {
+                to: send.to.clone(),
+                level: send.level.get(),
+                feedback: send.feedback,
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SavedTrack {
+    pub name: String,
+    /// The last code submitted to the track
+    #[serde(default)]
+    pub code: String,
+    #[serde(default)]
+    pub strip: SavedStrip,
+    #[serde(default)]
+    pub sends: Vec<SavedSend>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SavedBus {
+    pub name: String,
+    pub effect: EffectSpec,
+    #[serde(default)]
+    pub strip: SavedStrip,
+    #[serde(default)]
+    pub sends: Vec<SavedSend>,
+}
+
+impl Session {
+    pub fn capture(sound: &SoundControl, visuals: &VisualsControls, lang: &LangStatus) -> Self {
+        let tracks = sound.tracks();
+        Self {
+            code: lang.source.clone(),
+            params: params()
+                .into_iter()
+                .map(|(name, param)| {
+                    let saved = SavedParam {
+                        spec: param.spec(),
+                        value: param.target(),
+                    };
+                    (name, saved)
+                })
+                .collect(),
+            tempo: tempo(),
+            beats_per_bar: beats_per_bar(),
+            position: sound.time(),
+            loop_region: sound.loop_region(),
+            paused: sound.is_paused(),
+            visuals: visuals.clone(),
+            tracks: tracks
+                .iter()
+                .map(|track| SavedTrack {
+                    name: track.name.clone(),
+                    code: lang
+                        .track_sources
+                        .get(&track.name)
+                        .cloned()
+                        .unwrap_or_default(),
+                    strip: SavedStrip::capture(&track.strip),
+                    sends: SavedSend::capture(&track.sends),
+                })
+                .collect(),
+            buses: tracks
+                .buses()
+                .filter_map(|bus| match bus.effect() {
+                    Some(effect) => Some(SavedBus {
+                        name: bus.name.clone(),
+                        effect,
+                        strip: SavedStrip::capture(&bus.strip),
+                        sends: SavedSend::capture(&bus.sends),
+                    }),
+                    None => {
+                        warn!("Bus {:?} has an effect that can't be saved", bus.name);
+                        None
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    /// Each track's code, starting with the main track's
+    pub fn sources(&self) -> impl Iterator<Item = (&str, &str)> {
+        let others = self.tracks.iter().filter(|track| track.name != MAIN_TRACK);
+        std::iter::once((MAIN_TRACK, self.code.as_str()))
+            .chain(others.map(|track| (track.name.as_str(), track.code.as_str())))
+            .filter(|(_, code)| !code.is_empty())
+    }
+
+    /// Restores the parameters, tempo and metre, which don't need any bevy resources
+    pub fn apply_globals(&self) {
+        for (name, saved) in &self.params {
+            register_param(name, saved.spec).set(saved.value);
+        }
+        set_tempo(self.tempo);
+        set_beats_per_bar(self.beats_per_bar);
+    }
+
+    /// Restores everything except the code, which goes through the language like any other submission
+    pub fn apply(&self, sound: &mut SoundControl, visuals: &mut VisualsControls) {
+        self.apply_globals();
+        sound.seek(self.position);
+        sound.set_loop(self.loop_region);
+        if self.paused {
+            sound.pause();
+        } else {
+            sound.play();
+        }
+        *visuals = self.visuals.clone();
+        self.apply_mix(sound);
+    }
+
+    /// Sets up the tracks and buses as they were saved, without their code, and removes any
+    /// others. A session saved before tracks were leaves them alone
+    fn apply_mix(&self, sound: &mut SoundControl) {
+        if self.tracks.is_empty() {
+            return;
+        }
+        let list = sound.tracks();
+        let extra_buses: Vec<_> = list
+            .buses()
+            .map(|bus| bus.name.clone())
+            .filter(|name| self.buses.iter().all(|bus| &bus.name != name))
+            .collect();
+        let extra_tracks: Vec<_> = list
+            .iter()
+            .map(|track| track.name.clone())
+            .filter(|name| self.tracks.iter().all(|track| &track.name != name))
+            .collect();
+        // cleared first, so a saved send can't make a loop with one that's going
+        let sends: Vec<_> = (list.iter().map(|track| (&track.name, &track.sends)))
+            .chain(list.buses().map(|bus| (&bus.name, &bus.sends)))
+            .flat_map(|(from, sends)| sends.iter().map(|send| (from.clone(), send.to.clone())))
+            .collect();
+        for (from, to) in sends {
+            sound.remove_send(&from, &to);
+        }
+        for name in extra_buses {
+            sound.remove_bus(&name);
+        }
+        for name in extra_tracks {
+            sound.remove_track(name);
+        }
+
+        for bus in &self.buses {
+            if let Err(e) = sound.add_bus(&bus.name, bus.effect.build()) {
+                warn!("Couldn't restore bus {:?}: {e}", bus.name);
+            }
+        }
+        for track in &self.tracks {
+            if let Err(e) = sound.add_track(&track.name) {
+                warn!("Couldn't restore track {:?}: {e}", track.name);
+            }
+        }
+        let list = sound.tracks();
+        for track in &self.tracks {
+            if let Some(restored) = list.get(&track.name) {
+                track.strip.apply(&restored.strip);
+            }
+        }
+        for bus in &self.buses {
+            if let Some(restored) = list.bus(&bus.name) {
+                bus.strip.apply(&restored.strip);
+            }
+        }
+        let senders = (self.tracks.iter().map(|track| (&track.name, &track.sends)))
+            .chain(self.buses.iter().map(|bus| (&bus.name, &bus.sends)));
+        for (from, sends) in senders {
+            for send in sends {
+                if let Err(e) = sound.set_send(from, &send.to, send.level, send.feedback) {
+                    warn!(
+                        "Couldn't restore the send from {from:?} to {:?}: {e}",
+                        send.to
+                    );
+                }
+            }
+        }
+    }
+
+    pub fn to_ron(&self) -> anyhow::Result<String> {
+        Ok(ron::ser::to_string_pretty(self, Default::default())?)
+    }
+
+    pub fn from_ron(text: &str) -> anyhow::Result<Self> {
+        Ok(ron::from_str(text)?)
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        std::fs::write(path, self.to_ron()?).with_context(|| format!("writing {path:?}"))
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("reading {path:?}"))?;
+        Self::from_ron(&text).with_context(|| format!("parsing {path:?}"))
+    }
+}
+
+#[derive(Event, Clone, Debug)]
+pub struct SaveSession(pub PathBuf);
+
+#[derive(Event, Clone, Debug)]
+pub struct LoadSession {
+    pub path: PathBuf,
+    /// Overrides the saved transport state, e.g. for `--paused`
+    pub paused: Option<bool>,
+}
+
+impl LoadSession {
+    /// Load everything as it was saved
+    pub fn new(path: PathBuf) -> Self {
+        Self { path, paused: None }
+    }
+}
+
+/// The session file the UI saves to and loads from
+#[derive(Resource, Debug)]
+pub struct SessionFile {
+    pub path: String,
+    /// Result of the last save or load
+    pub status: Option<String>,
+}
+
+impl Default for SessionFile {
+    fn default() -> Self {
+        Self {
+            path: DEFAULT_SESSION_PATH.into(),
+            status: None,
+        }
+    }
+}
+
+fn save_sessions(
+    mut events: EventReader<SaveSession>,
+    mut file: ResMut<SessionFile>,
+    sound: Res<SoundControl>,
+    visuals: Res<VisualsControls>,
+    lang: Res<LangStatus>,
+) {
+    for SaveSession(path) in events.read() {
+        let result = Session::capture(&sound, &visuals, &lang).save(path);
+        file.status = Some(match result {
+            Ok(()) => format!("Saved {path:?}"),
+            Err(e) => format!("{e:#}"),
+        });
+    }
+}
+
+fn load_sessions(
+    mut events: EventReader<LoadSession>,
+    mut file: ResMut<SessionFile>,
+    mut sound: ResMut<SoundControl>,
+    mut visuals: ResMut<VisualsControls>,
+    mut code: EventWriter<SubmitCode>,
+) {
+    for LoadSession { path, paused } in events.read() {
+        match Session::load(path) {
+            Ok(mut session) => {
+                session.paused = paused.unwrap_or(session.paused);
+                session.apply(&mut sound, &mut visuals);
+                // code that doesn't evaluate would only replace the playing sound's status with an error
+                let mut restored = Ok(());
+                for (track, source) in session.sources() {
+                    match evaluate(source) {
+                        Ok(_) => {
+                            code.send(SubmitCode {
+                                source: source.into(),
+                                track: track.into(),
+                            });
+                        }
+                        Err(e) => restored = Err(e.context(format!("the code for {track:?}"))),
+                    }
+                }
+                info!("Loaded session {path:?}");
+                file.status = Some(match restored {
+                    Ok(()) => format!("Loaded {path:?}"),
+                    Err(e) => {
+                        warn!("Didn't restore all of the session's code: {e:#}");
+                        format!("Loaded {path:?} without code that didn't evaluate: {e:#}")
+                    }
+                });
+            }
+            Err(e) => {
+                warn!("Couldn't load session: {e:#}");
+                file.status = Some(format!("{e:#}"));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SavedParam, Session};
+    use crate::{
+        lang::LangStatus,
+        sound::{
+            effects::{EffectSpec, Reverb, Thru},
+            params::{ParamSpec, Smoothing},
+            SoundControl,
+        },
+    };
+
+    #[test]
+    fn test_round_trip() {
+        let mut session = Session {
+            code: "sin(440 * t)".into(),
+            tempo: 96.0,
+            position: 12.5,
+            loop_region: Some((4.0, 8.0)),
+            paused: true,
+            ..Default::default()
+        };
+        session.visuals.wave_samples = 512;
+        session.params.insert(
+            "cutoff".into(),
+            SavedParam {
+                spec: ParamSpec::new(20.0, 20_000.0, 1000.0).smoothing(Smoothing::Linear(0.1)),
+                value: 440.0,
+            },
+        );
+
+        let text = session.to_ron().unwrap();
+        assert_eq!(Session::from_ron(&text).unwrap(), session);
+
+        // missing fields fall back to their defaults, so old sessions keep loading
+        let old = Session::from_ron("(tempo: 140.0, visuals: (wave_samples: 64))").unwrap();
+        assert_eq!(old.tempo, 140.0);
+        assert_eq!(old.visuals.wave_samples, 64);
+        assert_eq!(
+            old.visuals.wave_line_width,
+            Session::default().visuals.wave_line_width
+        );
+    }
+
+    #[test]
+    fn test_mix_round_trip() {
+        let _globals = crate::lock_globals();
+        let mut sound = SoundControl::default();
+        sound.add_bus("verb", Reverb::new(0.5, 0.2)).unwrap();
+        sound.add_track("drums").unwrap();
+        sound.tracks().get("drums").unwrap().strip.set_gain(0.5);
+        sound.tracks().bus("verb").unwrap().strip.set_muted(true);
+        sound.set_send("drums", "verb", 0.3, false).unwrap();
+        sound.set_send("verb", "verb", 0.2, true).unwrap();
+        let lang = LangStatus {
+            source: "t".into(),
+            track_sources: [("drums".into(), "sin(t)".into())].into(),
+            ..Default::default()
+        };
+        let session = Session::capture(&sound, &Default::default(), &lang);
+        let session = Session::from_ron(&session.to_ron().unwrap()).unwrap();
+        let sources: Vec<_> = session.sources().collect();
+        assert_eq!(sources, [("main", "t"), ("drums", "sin(t)")]);
+
+        let mut fresh = SoundControl::default();
+        fresh.add_bus("other", Thru).unwrap();
+        session.apply_mix(&mut fresh);
+        let tracks = fresh.tracks();
+        assert!(tracks.bus("other").is_none());
+        let verb = tracks.bus("verb").unwrap();
+        let reverb = EffectSpec::Reverb {
+            room_size: 0.5,
+            damping: 0.2,
+        };
+        assert_eq!(verb.effect(), Some(reverb));
+        assert!(verb.strip.muted());
+        assert!(verb.sends[0].feedback);
+        let drums = tracks.get("drums").unwrap();
+        assert_eq!(drums.strip.gain(), 0.5);
+        assert_eq!(
+            (drums.sends[0].to.as_str(), drums.sends[0].level.get()),
+            ("verb", 0.3)
+        );
+
+        // sessions from before tracks were saved leave the mix alone
+        Session::default().apply_mix(&mut fresh);
+        assert!(fresh.tracks().bus("verb").is_some());
+    }
+}
@@ -3,37 +3,366 @@ use bevy::prelude::{Plugin, Resource};
 cfg_if::cfg_if! {
     if #[cfg(target_arch = "wasm32")] {
         pub mod wasm;
-        use crate::wasm::*;
+        pub use crate::sound::wasm::{output_devices, SoundResources};
         use web_sys::{AudioBufferSourceNode, AudioContext, GainNode};
     } else {
+        pub mod dylib;
         pub mod native;
-        use crate::sound::native::SoundResources;
+        pub use crate::sound::native::{output_devices, SoundResources};
     }
 }
 
+use arc_swap::ArcSwap;
 use bevy::{
-    app::Update,
+    app::{Startup, Update},
     ecs::system::Commands,
-    log::info,
+    log::{info, warn},
     prelude::{Res, ResMut},
-    time::Time,
 };
 use crossbeam_queue::SegQueue;
 use dyn_clone::DynClone;
 use once_cell::sync::Lazy;
+use std::any::Any;
+use std::fmt::Display;
 use std::sync::Mutex;
-use std::sync::{atomic::AtomicUsize, Arc};
+use std::sync::{
+    atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+    Arc,
+};
+
+pub mod block;
+pub mod clock;
+pub mod effects;
+pub mod events;
+pub mod load;
+pub mod mixer;
+pub mod offline;
+pub mod params;
+pub mod safety;
+pub mod samples;
+pub mod voices;
 
-pub const SAMPLE_RATE: u32 = 48_000;
-pub const INV_SAMPLE_RATE: Float = 1.0 / (SAMPLE_RATE as Float);
-const TIME_DIFFERENCE_THRESHOLD: Float = 200.0 / 1000.0;
+pub const DEFAULT_SAMPLE_RATE: u32 = 48_000;
+/// Sample rates offered in the UI, the device may still negotiate something else
+pub const SAMPLE_RATES: [u32; 3] = [44_100, 48_000, 96_000];
+// how far ahead `SoundControl::shift` lands, so the audio thread hears of it in time
+const SHIFT_LEAD: Float = 0.1;
+// how far back the smoothed clock can go before it counts as a jump rather than jitter
+const CLOCK_WOBBLE: Float = 0.05;
 static SAMPLE_INDEX: AtomicUsize = AtomicUsize::new(0);
+static SAMPLE_RATE: AtomicU32 = AtomicU32::new(DEFAULT_SAMPLE_RATE);
+static PAUSED: AtomicBool = AtomicBool::new(false);
+pub const DEFAULT_TEMPO: Float = 120.0;
+static TIMELINE: Lazy<ArcSwap<Timeline>> = Lazy::new(Default::default);
+pub const DEFAULT_BEATS_PER_BAR: u32 = 4;
+static BEATS_PER_BAR: AtomicU32 = AtomicU32::new(DEFAULT_BEATS_PER_BAR);
+
+/// Sample rate of the running audio context
+pub fn sample_rate() -> u32 {
+    SAMPLE_RATE.load(Ordering::Relaxed)
+}
+
+pub fn inv_sample_rate() -> Float {
+    1.0 / sample_rate() as Float
+}
+
+/// Time of the sample the audio thread will render next, in the same units as the `t` sound functions get
+pub fn audio_time() -> Float {
+    SAMPLE_INDEX.load(Ordering::Relaxed) as Float * inv_sample_rate()
+}
+
+/// Where the beats fall in audio time: `tempo` on from `beat` at `time`. A tempo change starts
+/// from the beat it lands on, so the beats carry on without a jump
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Timeline {
+    /// Beats per minute
+    pub tempo: Float,
+    pub beat: Float,
+    pub time: Float,
+}
+
+impl Default for Timeline {
+    fn default() -> Self {
+        Self {
+            tempo: DEFAULT_TEMPO,
+            beat: 0.0,
+            time: 0.0,
+        }
+    }
+}
+
+impl Timeline {
+    /// Number of beats at audio time `t`
+    pub fn beats(&self, t: Float) -> Float {
+        self.beat + (t - self.time) * self.tempo / 60.0
+    }
+
+    /// Audio time of `beat`, the inverse of [`Self::beats`]
+    pub fn time_at(&self, beat: Float) -> Float {
+        self.time + (beat - self.beat) * 60.0 / self.tempo
+    }
+}
+
+pub fn timeline() -> Timeline {
+    **TIMELINE.load()
+}
+
+/// Tempo in beats per minute
+pub fn tempo() -> Float {
+    timeline().tempo
+}
+
+/// Change the tempo from the current audio time
+pub fn set_tempo(bpm: Float) {
+    set_tempo_at(bpm, audio_time());
+}
+
+/// Change the tempo from audio time `time`, keeping the beat there
+pub fn set_tempo_at(bpm: Float, time: Float) {
+    TIMELINE.rcu(|timeline| Timeline {
+        tempo: bpm.max(1.0),
+        beat: timeline.beats(time),
+        time,
+    });
+}
+
+/// Move every beat by `beats`, so tempo-synced sounds catch up with an outside clock without
+/// the transport jumping
+pub fn shift_beats(beats: Float) {
+    TIMELINE.rcu(|timeline| Timeline {
+        beat: timeline.beat + beats,
+        ..**timeline
+    });
+}
+
+pub fn set_timeline(timeline: Timeline) {
+    TIMELINE.store(Arc::new(timeline));
+}
+
+/// Where the downbeats fall for [`events::When::Bar`]
+pub fn beats_per_bar() -> u32 {
+    BEATS_PER_BAR.load(Ordering::Relaxed)
+}
+
+pub fn set_beats_per_bar(beats: u32) {
+    BEATS_PER_BAR.store(beats.max(1), Ordering::Relaxed);
+}
+
+/// Number of beats at time `t`, see [`Timeline`]
+pub fn beats(t: Float) -> Float {
+    timeline().beats(t)
+}
+
+/// Number of bars at time `t`
+pub fn bars(t: Float) -> Float {
+    beats(t) / beats_per_bar() as Float
+}
+
+/// How far `beat` is ahead of the transport's beat at `time`, wrapped to within half a bar either
+/// way. It's what the transport has to make up to follow an outside clock
+pub fn beat_error(beat: Float, time: Float) -> Float {
+    let bar = beats_per_bar() as Float;
+    (beat - beats(time) + bar / 2.0).rem_euclid(bar) - bar / 2.0
+}
+
+/// Time at which `bars` bars have gone by, the inverse of [`bars`]
+pub fn bar_time(bars: Float) -> Float {
+    timeline().time_at(bars * beats_per_bar() as Float)
+}
+
+/// While paused the backends output silence and audio time stands still
+pub fn paused() -> bool {
+    PAUSED.load(Ordering::Relaxed)
+}
+
+/// Called by the backends once the device has told us its rate,
+/// rescales SAMPLE_INDEX so that audio time carries on from where it was
+fn set_sample_rate(new_rate: u32) {
+    let old_rate = SAMPLE_RATE.swap(new_rate, Ordering::Relaxed);
+    if old_rate != new_rate {
+        let time = SAMPLE_INDEX.load(Ordering::Relaxed) as Float / old_rate as Float;
+        SAMPLE_INDEX.store((time * new_rate as Float) as usize, Ordering::Relaxed);
+    }
+}
 
 pub struct SoundPlugin;
 
-pub type SoundFn = Box<dyn SoundFnTrait>;
-pub trait SoundFnTrait: Fn(Float) -> [Float; 2] + Send + Sync + DynClone {}
-impl<T> SoundFnTrait for T where T: Fn(f64) -> [Float; 2] + Clone + Send + Sync {}
+pub type SoundFn<const N: usize = 2> = Box<dyn SoundFnTrait<N>>;
+pub trait SoundFnTrait<const N: usize = 2>:
+    Fn(Float) -> [Float; N] + Send + Sync + DynClone
+{
+}
+impl<T, const N: usize> SoundFnTrait<N> for T where T: Fn(f64) -> [Float; N] + Clone + Send + Sync {}
+
+impl<const N: usize> Clone for SoundFn<N> {
+    fn clone(&self) -> Self {
+        dyn_clone::clone_box(&**self)
+    }
+}
+
+/// Most devices top out well before this, it is also the web audio limit
+pub const MAX_CHANNELS: usize = 32;
+
+// renders interleaved frames with the given number of channels, from time t0 in steps of dt
+type RenderFn = dyn Fn(Float, Float, &mut [Float], usize) + Send + Sync;
+
+/// A [`SoundFn`] with its channel count only known at runtime, this is what the backends render
+#[derive(Clone)]
+pub struct OutputFn {
+    channels: usize,
+    render: Arc<RenderFn>,
+}
+
+impl OutputFn {
+    pub fn new<const N: usize>(sound_fn: SoundFn<N>) -> Self {
+        assert!(
+            N <= MAX_CHANNELS,
+            "sound functions can have at most {MAX_CHANNELS} channels"
+        );
+        Self {
+            channels: N,
+            render: Arc::new(move |t0, dt, frames: &mut [Float], channels| {
+                let n = channels.min(N);
+                for (i, out) in frames.chunks_exact_mut(channels).enumerate() {
+                    let frame = sound_fn(t0 + i as Float * dt);
+                    out[..n].copy_from_slice(&frame[..n]);
+                }
+            }),
+        }
+    }
+
+    /// A function with a channel count only known at runtime, writing one frame of that many values
+    pub fn from_frame_fn(
+        channels: usize,
+        frame_fn: impl Fn(Float, &mut [Float]) + Send + Sync + 'static,
+    ) -> Self {
+        assert!(
+            channels <= MAX_CHANNELS,
+            "sound functions can have at most {MAX_CHANNELS} channels"
+        );
+        Self {
+            channels,
+            render: Arc::new(move |t0, dt, frames: &mut [Float], out_channels| {
+                let n = out_channels.min(channels);
+                let mut frame = [0.0; MAX_CHANNELS];
+                for (i, out) in frames.chunks_exact_mut(out_channels).enumerate() {
+                    frame_fn(t0 + i as Float * dt, &mut frame[..channels]);
+                    out[..n].copy_from_slice(&frame[..n]);
+                }
+            }),
+        }
+    }
+
+    /// A stereo function that renders [`block::BLOCK_LEN`] frames per call
+    pub fn from_block(block_fn: impl block::BlockFn + 'static) -> Self {
+        Self {
+            channels: 2,
+            render: Arc::new(move |t0, dt, frames: &mut [Float], channels| {
+                let n = channels.min(2);
+                let mut block = [[0.0; 2]; block::BLOCK_LEN];
+                for (b, chunk) in frames.chunks_mut(block::BLOCK_LEN * channels).enumerate() {
+                    let block = &mut block[..chunk.len() / channels];
+                    block_fn.render(t0 + (b * block::BLOCK_LEN) as Float * dt, dt, block);
+                    for (out, frame) in chunk.chunks_exact_mut(channels).zip(block.iter()) {
+                        out[..n].copy_from_slice(&frame[..n]);
+                    }
+                }
+            }),
+        }
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    /// Write one frame into `out`, channels beyond the function's own are left untouched
+    pub fn render(&self, t: Float, out: &mut [Float]) {
+        self.render_block(t, 0.0, out, out.len())
+    }
+
+    /// Write interleaved frames into `frames`, at times `t0`, `t0 + dt`, ...
+    pub fn render_block(&self, t0: Float, dt: Float, frames: &mut [Float], channels: usize) {
+        if channels > 0 {
+            (self.render)(t0, dt, frames, channels)
+        }
+    }
+
+    /// Equal-power fade from one function to another over `duration` seconds from `start`
+    pub fn crossfade(from: OutputFn, to: OutputFn, start: Float, duration: Float) -> Self {
+        Self::crossfade_cued(from, to, Arc::new(events::Cue::at(start)), duration)
+    }
+
+    /// Like [`Self::crossfade`] from whenever `cue` fires, with no fade when `duration` is 0
+    pub fn crossfade_cued(
+        from: OutputFn,
+        to: OutputFn,
+        cue: Arc<events::Cue>,
+        duration: Float,
+    ) -> Self {
+        // frames per chunk while fading, small enough to keep the scratch buffer on the stack
+        const CHUNK: usize = 16;
+        Self {
+            channels: from.channels.max(to.channels),
+            render: Arc::new(move |t0, dt, frames: &mut [Float], channels| {
+                let start = cue.time();
+                if t0 >= start + duration {
+                    return to.render_block(t0, dt, frames, channels);
+                }
+                let end = t0 + (frames.len() / channels) as Float * dt;
+                if end <= start {
+                    return from.render_block(t0, dt, frames, channels);
+                }
+                let n = channels.min(MAX_CHANNELS);
+                let mut a = [0.0; CHUNK * MAX_CHANNELS];
+                for (c, chunk) in frames.chunks_mut(CHUNK * channels).enumerate() {
+                    let t0 = t0 + (c * CHUNK) as Float * dt;
+                    let a = &mut a[..chunk.len()];
+                    a.fill(0.0);
+                    chunk.fill(0.0);
+                    from.render_block(t0, dt, a, channels);
+                    to.render_block(t0, dt, chunk, channels);
+                    for (i, (out, a)) in chunk
+                        .chunks_exact_mut(channels)
+                        .zip(a.chunks_exact(channels))
+                        .enumerate()
+                    {
+                        let t = t0 + i as Float * dt;
+                        let x = if duration > 0.0 {
+                            ((t - start) / duration).clamp(0.0, 1.0)
+                        } else if t >= start {
+                            1.0
+                        } else {
+                            0.0
+                        };
+                        let angle = x * std::f64::consts::FRAC_PI_2 as Float;
+                        for (out, a) in out[..n].iter_mut().zip(a) {
+                            *out = a * angle.cos() + *out * angle.sin();
+                        }
+                    }
+                }
+            }),
+        }
+    }
+
+    pub fn channel(&self, t: Float, channel: usize) -> Float {
+        let mut frame = [0.0; MAX_CHANNELS];
+        self.render(t, &mut frame);
+        frame[channel]
+    }
+}
+
+// Track name and panic message from the audio thread, reported by SoundControl
+static TRACK_PANICS: SegQueue<(String, String)> = SegQueue::new();
+
+pub fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".into()
+    }
+}
 
 // We may want to use different types for computing and outputting sounds
 // e.g. we may want f64 for precision when calculating things, but wasm only accepts f32 as output
@@ -44,31 +373,43 @@ pub fn empty_sound_fn() -> SoundFn {
     Box::new(|_| [0.0, 0.0])
 }
 
+pub fn empty_output_fn() -> OutputFn {
+    OutputFn::new(empty_sound_fn())
+}
+
 impl Plugin for SoundPlugin {
     fn build(&self, app: &mut bevy::prelude::App) {
         app.init_resource::<SoundControl>();
-        app.add_systems(Update, update);
+        app.init_resource::<OutputSettings>();
+        app.init_resource::<load::DspLoad>();
+        app.add_systems(Startup, load_default_samples);
+        app.add_systems(Update, (update, load::update));
+    }
+}
+
+fn load_default_samples() {
+    if std::path::Path::new(samples::DEFAULT_SAMPLE_DIR).is_dir() {
+        samples::load_dir(samples::DEFAULT_SAMPLE_DIR);
     }
 }
 
-fn update(mut commands: Commands, mut sound_control: ResMut<SoundControl>, time: Res<Time>) {
+fn update(
+    mut commands: Commands,
+    mut sound_control: ResMut<SoundControl>,
+    output_settings: Res<OutputSettings>,
+) {
     match sound_control.state {
         State::Starting => {
-            commands.init_resource::<SoundResources>();
-            info!("Sound init!");
+            // inserting over an existing SoundResources drops (and closes) the old audio context
+            commands.insert_resource(SoundResources::new(&output_settings));
+            info!("Sound init! {:?}", *output_settings);
+            // the new backend starts with a fresh scheduler
+            let region = sound_control.loop_region();
+            sound_control.set_loop(region);
             sound_control.state = State::Running
         }
         State::Running => {
-            sound_control.update(time.elapsed_seconds_f64());
-
-            let sample_index = SAMPLE_INDEX.load(std::sync::atomic::Ordering::Relaxed);
-            let audio_time = sample_index as Float * INV_SAMPLE_RATE;
-            let app_time = sound_control.time() as Float;
-
-            if (audio_time - app_time).abs() > TIME_DIFFERENCE_THRESHOLD {
-                let new_index = (app_time * SAMPLE_RATE as Float) as usize;
-                SAMPLE_INDEX.store(new_index, std::sync::atomic::Ordering::Relaxed);
-            }
+            sound_control.update();
         }
         State::Stopped => {}
     }
@@ -76,11 +417,13 @@ fn update(mut commands: Commands, mut sound_control: ResMut<SoundControl>, time:
 
 #[derive(Resource)]
 pub struct SoundControl {
-    queue: SegQueue<SoundFn>,
-    next_fn: SoundFn,
-    sound_fn_changed: bool,
-    start_time: f64,
-    elapsed_time: f64,
+    // track name with its new function, crossfade time and when to start, None removes the track
+    queue: SegQueue<(String, Option<OutputFn>, Float, events::When)>,
+    tracks: mixer::TrackList,
+    last_panic: Option<String>,
+    // audio time by the smoothed clock, as of this frame
+    time: Float,
+    loop_region: Option<(Float, Float)>,
     state: State,
 }
 
@@ -95,46 +438,248 @@ impl Default for SoundControl {
     fn default() -> Self {
         Self {
             queue: Default::default(),
-            next_fn: empty_sound_fn(),
-            sound_fn_changed: true,
-            start_time: 0.0,
-            elapsed_time: 0.0,
+            tracks: Default::default(),
+            last_panic: None,
+            time: 0.0,
+            loop_region: None,
             state: State::Stopped,
         }
     }
 }
 
 impl SoundControl {
+    /// Play `new_fn` on the main track
     pub fn push_soundfn(&self, new_fn: SoundFn) {
-        self.queue.push(new_fn);
+        self.push(mixer::MAIN_TRACK, OutputFn::new(new_fn), 0.0);
+    }
+
+    /// Like [`Self::push_soundfn`], fading from the current sound over `seconds`
+    pub fn push_soundfn_crossfade(&self, new_fn: SoundFn, seconds: Float) {
+        self.push(mixer::MAIN_TRACK, OutputFn::new(new_fn), seconds);
+    }
+
+    /// Like [`Self::push_soundfn_crossfade`] for a function with any number of channels
+    pub fn push_output_fn_crossfade(&self, new_fn: OutputFn, seconds: Float) {
+        self.push(mixer::MAIN_TRACK, new_fn, seconds);
+    }
+
+    /// Like [`Self::push_soundfn`] for a function that renders a block at a time
+    pub fn push_block_fn(&self, new_fn: impl block::BlockFn + 'static) {
+        self.push(mixer::MAIN_TRACK, OutputFn::from_block(new_fn), 0.0);
+    }
+
+    /// Like [`Self::push_soundfn`] for any number of channels, e.g. `SoundFn<8>` for an 8 speaker ring.
+    /// Channels are routed to the device in order, see [`OutputSettings::channels`]
+    pub fn push_multichannel_soundfn<const N: usize>(&self, new_fn: SoundFn<N>) {
+        self.push(mixer::MAIN_TRACK, OutputFn::new(new_fn), 0.0);
+    }
+
+    /// Play `new_fn` on the named track, adding the track if it doesn't exist yet.
+    /// Tracks render in parallel, see [`mixer`]
+    pub fn push_track(&self, name: impl Into<String>, new_fn: SoundFn) {
+        self.push(name, OutputFn::new(new_fn), 0.0);
+    }
+
+    /// Like [`Self::push_track`], fading from what the track was playing over `seconds`.
+    /// The other tracks carry on as they were
+    pub fn push_track_crossfade(&self, name: impl Into<String>, new_fn: SoundFn, seconds: Float) {
+        self.push(name, OutputFn::new(new_fn), seconds);
+    }
+
+    /// Like [`Self::push_track_crossfade`], starting the fade `when` the audio thread gets there,
+    /// e.g. on the next bar so a code change lands on the downbeat
+    pub fn push_track_at(
+        &self,
+        name: impl Into<String>,
+        new_fn: SoundFn,
+        seconds: Float,
+        when: events::When,
+    ) {
+        self.queue
+            .push((name.into(), Some(OutputFn::new(new_fn)), seconds, when));
+    }
+
+    /// The main track is silenced rather than removed
+    pub fn remove_track(&self, name: impl Into<String>) {
+        self.queue.push((name.into(), None, 0.0, events::When::Now));
     }
 
-    fn update(&mut self, time: Float) {
-        while !self.queue.is_empty() {
-            self.next_fn = self.queue.pop().unwrap();
-            self.sound_fn_changed = true;
+    /// Add a silent track straight away rather than with its first sound, so it can be mixed
+    /// and sent from before then. Does nothing if the track is already there
+    pub fn add_track(&mut self, name: &str) -> anyhow::Result<()> {
+        if self.tracks.get(name).is_none() {
+            self.tracks.push(name, empty_output_fn(), 0.0, 0.0)?;
+            set_mix(self.tracks.mix());
         }
-        self.elapsed_time = time - self.start_time;
+        Ok(())
+    }
+
+    fn push(&self, name: impl Into<String>, new_fn: OutputFn, seconds: Float) {
+        self.queue
+            .push((name.into(), Some(new_fn), seconds, events::When::Now));
+    }
+
+    /// Have the audio thread do something on exactly the right sample, see [`events`]
+    pub fn schedule(&self, when: events::When, action: events::Action) {
+        events::schedule(when, action);
+    }
+
+    /// Every track and bus with its mixer strip, tracks in the order they are mixed
+    pub fn tracks(&self) -> &mixer::TrackList {
+        &self.tracks
+    }
+
+    /// Add an aux bus running `effect`, or swap the effect on an existing bus.
+    /// Fails if a track already has the name
+    pub fn add_bus(
+        &mut self,
+        name: &str,
+        effect: impl effects::Effect + 'static,
+    ) -> anyhow::Result<()> {
+        self.tracks.add_bus(name, Box::new(effect))?;
+        set_mix(self.tracks.mix());
+        Ok(())
+    }
+
+    /// Removes the bus along with every send to it
+    pub fn remove_bus(&mut self, name: &str) {
+        self.tracks.remove_bus(name);
+        set_mix(self.tracks.mix());
+    }
 
-        if self.sound_fn_changed {
-            let new_sound_fn = dyn_clone::clone_box(&*self.next_fn);
-            set_sound(new_sound_fn);
-            self.sound_fn_changed = false;
+    /// Send a track or bus to a bus at `level`, see [`mixer::TrackList::set_send`]
+    pub fn set_send(
+        &mut self,
+        from: &str,
+        to: &str,
+        level: Float,
+        feedback: bool,
+    ) -> anyhow::Result<()> {
+        self.tracks.set_send(from, to, level, feedback)?;
+        set_mix(self.tracks.mix());
+        Ok(())
+    }
+
+    pub fn remove_send(&mut self, from: &str, to: &str) {
+        self.tracks.remove_send(from, to);
+        set_mix(self.tracks.mix());
+    }
+
+    fn update(&mut self) {
+        let now = audio_time();
+        let mut tracks_changed = false;
+        // the audio thread has already silenced these
+        while let Some((name, message)) = TRACK_PANICS.pop() {
+            warn!("Sound function or effect on {name:?} panicked: {message}");
+            self.tracks.panicked(&name);
+            self.last_panic = Some(match name.as_str() {
+                mixer::MAIN_TRACK => message,
+                _ => format!("{name}: {message}"),
+            });
+            tracks_changed = true;
+        }
+        while let Some((name, new_fn, fade, when)) = self.queue.pop() {
+            let result = match (new_fn, when) {
+                (Some(new_fn), events::When::Now) => self.tracks.push(&name, new_fn, fade, now),
+                (Some(new_fn), when) => {
+                    // published straight away, but nothing changes until the cue fires
+                    let cue = Arc::new(events::Cue::default());
+                    self.tracks
+                        .push_cued(&name, new_fn, fade, cue.clone())
+                        .map(|()| events::schedule(when, events::Action::Cue(cue)))
+                }
+                (None, _) => {
+                    self.tracks.remove(&name);
+                    Ok(())
+                }
+            };
+            if let Err(e) = result {
+                warn!("Couldn't play on track {name:?}: {e}");
+            }
+            tracks_changed = true;
+        }
+        events::collect_retired();
+        // stop rendering the old sounds once they have faded out
+        tracks_changed |= self.tracks.finish_fades(now);
+        if tracks_changed {
+            set_mix(self.tracks.mix());
+        }
+        let time = clock::now();
+        // a jitter of the estimate around a new block shouldn't run the visuals backwards,
+        // a seek should
+        if time > self.time || self.time - time > CLOCK_WOBBLE {
+            self.time = time;
         }
     }
 
-    pub fn _set_time(&mut self, new_elapsed_time: Float) {
-        let current_time = self.start_time + self.elapsed_time;
-        self.elapsed_time = new_elapsed_time;
-        self.start_time = current_time - self.elapsed_time;
+    /// Jump the transport to `time` seconds. The audio thread owns the sample counter,
+    /// so this is the only way to move it
+    pub fn seek(&mut self, time: Float) {
+        events::schedule(events::When::Now, events::Action::Seek(time));
+        self.time = time.max(0.0);
     }
 
+    /// Move the transport on by `seconds` a moment from now, on an exact sample. Unlike
+    /// [`Self::seek`] it doesn't matter how long the event takes to reach the audio thread, so
+    /// it's how an outside clock is followed
+    pub fn shift(&mut self, seconds: Float) {
+        let rate = sample_rate() as Float;
+        let sample = ((clock::now() + SHIFT_LEAD) * rate).round() as usize;
+        let to = sample as Float / rate + seconds;
+        events::schedule(events::When::Sample(sample), events::Action::Seek(to));
+    }
+
+    /// Jump the transport to the start of a bar, counting from 0
+    pub fn seek_bar(&mut self, bar: Float) {
+        self.seek(bar_time(bar));
+    }
+
+    /// Loop audio time from `end` back to `start`, in seconds, whenever playback reaches `end`.
+    /// None stops looping
+    pub fn set_loop(&mut self, region: Option<(Float, Float)>) {
+        let region = region.filter(|(start, end)| start < end);
+        events::schedule(events::When::Now, events::Action::Loop(region));
+        self.loop_region = region;
+    }
+
+    /// Like [`Self::set_loop`] with the region in bars
+    pub fn set_loop_bars(&mut self, region: Option<(Float, Float)>) {
+        self.set_loop(region.map(|(start, end)| (bar_time(start), bar_time(end))));
+    }
+
+    pub fn loop_region(&self) -> Option<(Float, Float)> {
+        self.loop_region
+    }
+
+    /// Audio time as of this frame, what the app and visuals should follow
     pub fn time(&self) -> Float {
-        self.elapsed_time
+        self.time
+    }
+
+    pub fn play(&mut self) {
+        PAUSED.store(false, Ordering::Relaxed);
     }
 
-    pub fn current_soundfn(&self) -> &SoundFn {
-        &self.next_fn
+    pub fn pause(&mut self) {
+        PAUSED.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        paused()
+    }
+
+    /// Message of the last panic in a sound function
+    pub fn last_panic(&self) -> Option<&str> {
+        self.last_panic.as_deref()
+    }
+
+    pub fn clear_panic(&mut self) {
+        self.last_panic = None;
+    }
+
+    /// What the main track is playing
+    pub fn current_soundfn(&self) -> &OutputFn {
+        self.tracks.main().sound_fn()
     }
 
     pub fn start(&mut self) {
@@ -143,11 +688,120 @@ impl SoundControl {
             x => panic! {"Sound is in state {:?}, you can only start sound when it's stopped!", x},
         }
     }
+
+    /// Tear down the audio context and build a new one from the current [`OutputSettings`]
+    pub fn restart(&mut self) {
+        match &self.state {
+            State::Running => self.state = State::Starting,
+            x => info!("Sound is in state {:?}, ignoring restart", x),
+        }
+    }
 }
 
-fn set_sound(new_fn: SoundFn) {
-    *CURRENT_SOUND_FN.lock().unwrap() = Arc::new(new_fn);
+/// Where and how the audio context should output sound, changes are applied on [`SoundControl::restart`]
+#[derive(Resource, Clone, Debug)]
+pub struct OutputSettings {
+    /// Id from [`output_devices`], an empty id is the system default device
+    pub sink_id: String,
+    pub latency: Latency,
+    /// None lets the device pick its preferred rate
+    pub sample_rate: Option<u32>,
+    /// Sound function channels are sent to the device channels in order, extra channels are silent
+    pub channels: usize,
 }
 
-static CURRENT_SOUND_FN: Lazy<Mutex<Arc<SoundFn>>> =
-    Lazy::new(|| Mutex::new(Arc::new(empty_sound_fn())));
+impl Default for OutputSettings {
+    fn default() -> Self {
+        Self {
+            sink_id: "".into(),
+            latency: Default::default(),
+            sample_rate: None,
+            channels: 2,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Latency {
+    Interactive,
+    #[default]
+    Balanced,
+    Playback,
+    /// Explicit buffer size in frames, the backend may round this up
+    BufferSize(usize),
+}
+
+impl Display for Latency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Latency::Interactive => write!(f, "Interactive"),
+            Latency::Balanced => write!(f, "Balanced"),
+            Latency::Playback => write!(f, "Playback"),
+            Latency::BufferSize(frames) => write!(f, "{frames} frames"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutputDevice {
+    pub id: String,
+    pub label: String,
+}
+
+fn set_mix(mix: Arc<mixer::Mix>) {
+    let old = CURRENT_MIX.swap(mix);
+    // held until the backends let go, so the mix and its sound functions are dropped here
+    let mut retired = RETIRED_MIXES.lock().unwrap();
+    retired.push(old);
+    retired.retain(|mix| Arc::strong_count(mix) > 1);
+}
+
+// What the backends render
+static CURRENT_MIX: Lazy<ArcSwap<mixer::Mix>> = Lazy::new(Default::default);
+// Only ever locked by the control thread
+static RETIRED_MIXES: Mutex<Vec<Arc<mixer::Mix>>> = Mutex::new(vec![]);
+
+#[cfg(test)]
+mod tests {
+    use super::{OutputFn, SoundFn, Timeline};
+
+    #[test]
+    fn test_crossfade() {
+        let up: SoundFn = Box::new(|_| [1.0, 1.0]);
+        let down: SoundFn<3> = Box::new(|_| [-1.0, -1.0, -1.0]);
+        let fade = OutputFn::crossfade(OutputFn::new(up), OutputFn::new(down), 1.0, 2.0);
+        assert_eq!(fade.channels(), 3);
+        assert_eq!(fade.channel(0.0, 0), 1.0);
+        assert!(fade.channel(2.0, 1).abs() < 1e-12);
+        assert_eq!(fade.channel(3.0, 0), -1.0);
+        assert!(fade.channel(2.0, 2) < -0.7);
+    }
+
+    #[test]
+    fn test_block_output() {
+        let sound_fn = |t: f64| [t, -t];
+        let per_sample = OutputFn::new(Box::new(sound_fn));
+        let block = OutputFn::from_block(sound_fn);
+        // not a whole number of blocks, and a third channel neither function writes to
+        let mut a = vec![7.0; 3 * 100];
+        let mut b = a.clone();
+        per_sample.render_block(1.0, 0.01, &mut a, 3);
+        block.render_block(1.0, 0.01, &mut b, 3);
+        assert!(a.iter().zip(&b).all(|(a, b)| (a - b).abs() < 1e-12));
+        assert_eq!(b[299], 7.0);
+        assert!((b[297] - 1.99).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_timeline() {
+        let timeline = Timeline {
+            tempo: 60.0,
+            beat: 4.0,
+            time: 2.0,
+        };
+        assert_eq!(timeline.beats(3.5), 5.5);
+        assert_eq!(timeline.time_at(5.5), 3.5);
+        // before the origin it runs backwards at the same tempo
+        assert_eq!(timeline.beats(0.0), 2.0);
+    }
+}
@@ -0,0 +1,138 @@
+//! Rendering a whole buffer of frames per call, instead of calling a [`SoundFn`](super::SoundFn)
+//! once per sample through a `dyn Fn`
+
+use super::Float;
+
+/// Frames rendered per call when the backends render a block function.
+/// Buffers this long go on the stack, so the audio thread never allocates
+pub const BLOCK_LEN: usize = 64;
+
+/// A stereo sound function that renders a block at a time
+pub trait BlockFn: Send + Sync {
+    /// Fill `out` with the frames at times `t0`, `t0 + dt`, `t0 + 2 * dt`, ...
+    fn render(&self, t0: Float, dt: Float, out: &mut [[Float; 2]]);
+}
+
+/// Any per-sample sound function is a block function, it is just called once per frame
+impl<F> BlockFn for F
+where
+    F: Fn(Float) -> [Float; 2] + Send + Sync,
+{
+    fn render(&self, t0: Float, dt: Float, out: &mut [[Float; 2]]) {
+        for (i, frame) in out.iter_mut().enumerate() {
+            *frame = self(t0 + i as Float * dt);
+        }
+    }
+}
+
+impl BlockFn for Box<dyn BlockFn> {
+    fn render(&self, t0: Float, dt: Float, out: &mut [[Float; 2]]) {
+        (**self).render(t0, dt, out)
+    }
+}
+
+/// Block version of `seq!`, plays each part for one second in turn, with time starting from 0 for each
+pub struct Seq(pub Vec<Box<dyn BlockFn>>);
+
+impl BlockFn for Seq {
+    fn render(&self, t0: Float, dt: Float, out: &mut [[Float; 2]]) {
+        let len = self.0.len() as Float;
+        let step = |i: usize| ((t0 + i as Float * dt) % len) as usize;
+        let mut start = 0;
+        while start < out.len() {
+            // frames until the next part starts
+            let current = step(start);
+            let end = (start + 1..out.len())
+                .find(|&i| step(i) != current)
+                .unwrap_or(out.len());
+            let t = (t0 + start as Float * dt) % 1.0;
+            self.0[current].render(t, dt, &mut out[start..end]);
+            start = end;
+        }
+    }
+}
+
+/// Block version of `avg!`, the mean of every part
+pub struct Avg(pub Vec<Box<dyn BlockFn>>);
+
+impl BlockFn for Avg {
+    fn render(&self, t0: Float, dt: Float, out: &mut [[Float; 2]]) {
+        let scale = 1.0 / self.0.len() as Float;
+        let mut part = [[0.0; 2]; BLOCK_LEN];
+        for (n, chunk) in out.chunks_mut(BLOCK_LEN).enumerate() {
+            let t0 = t0 + (n * BLOCK_LEN) as Float * dt;
+            chunk.fill([0.0; 2]);
+            for sound_fn in &self.0 {
+                let part = &mut part[..chunk.len()];
+                sound_fn.render(t0, dt, part);
+                for (frame, [l, r]) in chunk.iter_mut().zip(part.iter()) {
+                    frame[0] += l * scale;
+                    frame[1] += r * scale;
+                }
+            }
+        }
+    }
+}
+
+/// Block version of `seq!`: `seq_block![a, b]` where each part is a [`BlockFn`]
+#[macro_export]
+macro_rules! seq_block {
+    ($($e:expr),*) => {
+        $crate::sound::block::Seq(vec![$(Box::new($e) as Box<dyn $crate::sound::block::BlockFn>),*])
+    };
+}
+
+/// Block version of `avg!`: `avg_block![a, b]` where each part is a [`BlockFn`]
+#[macro_export]
+macro_rules! avg_block {
+    ($($e:expr),*) => {
+        $crate::sound::block::Avg(vec![$(Box::new($e) as Box<dyn $crate::sound::block::BlockFn>),*])
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BlockFn, BLOCK_LEN};
+    use crate::sound::Float;
+
+    fn per_sample(sound_fn: &impl BlockFn, t0: Float, dt: Float, len: usize) -> Vec<[Float; 2]> {
+        (0..len)
+            .map(|i| {
+                let mut frame = [[0.0; 2]];
+                sound_fn.render(t0 + i as Float * dt, dt, &mut frame);
+                frame[0]
+            })
+            .collect()
+    }
+
+    fn assert_close(a: &[[Float; 2]], b: &[[Float; 2]]) {
+        assert_eq!(a.len(), b.len());
+        for (x, y) in a.iter().zip(b) {
+            assert!(
+                (x[0] - y[0]).abs() < 1e-9 && (x[1] - y[1]).abs() < 1e-9,
+                "{x:?} {y:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_combinators() {
+        let a = |t: Float| [t, 1.0];
+        let b = |t: Float| [-t, 2.0];
+        let dt = 0.01;
+        let len = BLOCK_LEN * 5 + 3;
+
+        let seq = seq_block![a, b];
+        let mut out = vec![[0.0; 2]; len];
+        seq.render(0.5, dt, &mut out);
+        assert_close(&out, &per_sample(&seq, 0.5, dt, len));
+        // the first half second is `a`, then a second of `b`, then `a` again
+        assert_eq!(out[0][1], 1.0);
+        assert_eq!(out[60][1], 2.0);
+        assert_eq!(out[160][1], 1.0);
+
+        let avg = avg_block![a, b, seq];
+        avg.render(0.5, dt, &mut out);
+        assert_close(&out, &per_sample(&avg, 0.5, dt, len));
+    }
+}
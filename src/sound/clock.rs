@@ -0,0 +1,221 @@
+//! The audio thread's sample counter is the one master clock, only the backends move it.
+//! Everything else follows it through a delay-locked loop, after Fons Adriaensen's
+//! "Using a DLL to filter time", which smooths out the jitter in when render callbacks actually
+//! run. The app can then tell where audio time is between callbacks without touching the counter
+
+use std::sync::atomic::{fence, AtomicU32, AtomicU64, Ordering};
+
+use super::{audio_time, load, sample_rate, Float};
+
+/// Loop bandwidth in Hz, lower is smoother but slower to follow a drifting device clock
+pub const BANDWIDTH: Float = 1.0;
+// how far past the last callback the estimate runs on, so it doesn't run away if the audio thread stalls
+const MAX_EXTRAPOLATION: Float = 0.1;
+// a callback further than this from where the loop expected it, e.g. after an xrun, starts it again
+const MAX_ERROR: Float = 0.05;
+// the device's rate is never this far off what it says it is, jitter alone can't push the loop further
+const MAX_DRIFT: Float = 0.01;
+
+/// Wall clock time against sample index, a straight line through the render callbacks
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Line {
+    /// Wall clock time of `sample`, in seconds
+    pub time: Float,
+    pub sample: usize,
+    /// Seconds per sample
+    pub period: Float,
+}
+
+impl Line {
+    /// Fractional sample index being rendered at wall clock time `wall`
+    pub fn sample_at(&self, wall: Float) -> Float {
+        let elapsed = (wall - self.time).clamp(0.0, MAX_EXTRAPOLATION);
+        self.sample as Float + elapsed / self.period
+    }
+}
+
+/// Second order delay-locked loop, fed the wall clock time each block starts rendering at
+#[derive(Clone, Debug, Default)]
+pub struct Dll {
+    line: Option<Line>,
+}
+
+impl Dll {
+    /// The block starting on `sample` started rendering at wall clock time `wall`.
+    /// A jump in the samples, like a seek, starts the loop again from there
+    pub fn update(&mut self, sample: usize, wall: Float, sample_rate: u32) -> Line {
+        let nominal = 1.0 / sample_rate as Float;
+        let fresh = Line {
+            time: wall,
+            sample,
+            period: nominal,
+        };
+        let line = match self.line {
+            Some(line) if sample > line.sample => {
+                let frames = (sample - line.sample) as Float;
+                let predicted = line.time + frames * line.period;
+                let error = wall - predicted;
+                // critically damped, with the loop period being this block
+                let omega = 2.0 * std::f64::consts::PI as Float * BANDWIDTH * frames * line.period;
+                let b = std::f64::consts::SQRT_2 as Float * omega;
+                let c = omega * omega;
+                let period = line.period + c * error / frames;
+                if error.abs() > MAX_ERROR || (period / nominal - 1.0).abs() > MAX_DRIFT {
+                    fresh
+                } else {
+                    Line {
+                        time: predicted + b * error,
+                        sample,
+                        period,
+                    }
+                }
+            }
+            _ => fresh,
+        };
+        self.line = Some(line);
+        line
+    }
+
+    /// Forget the line, e.g. while the transport is stopped and the samples don't move
+    pub fn reset(&mut self) {
+        self.line = None;
+    }
+}
+
+// the audio thread's latest line, behind a seqlock like the params. A period of 0 means stopped
+static SEQ: AtomicU32 = AtomicU32::new(0);
+static TIME: AtomicU64 = AtomicU64::new(0);
+static SAMPLE: AtomicU64 = AtomicU64::new(0);
+static PERIOD: AtomicU64 = AtomicU64::new(0);
+
+fn publish(line: Option<Line>) {
+    let line = line.unwrap_or(Line {
+        time: 0.0,
+        sample: 0,
+        period: 0.0,
+    });
+    let seq = SEQ.load(Ordering::Relaxed);
+    SEQ.store(seq.wrapping_add(1), Ordering::Relaxed);
+    fence(Ordering::Release);
+    TIME.store(line.time.to_bits(), Ordering::Relaxed);
+    SAMPLE.store(line.sample as u64, Ordering::Relaxed);
+    PERIOD.store(line.period.to_bits(), Ordering::Relaxed);
+    SEQ.store(seq.wrapping_add(2), Ordering::Release);
+}
+
+/// The line the audio thread last published, None while it is stopped
+pub fn latest() -> Option<Line> {
+    loop {
+        let seq = SEQ.load(Ordering::Acquire);
+        if seq % 2 == 1 {
+            std::hint::spin_loop();
+            continue;
+        }
+        let line = Line {
+            time: Float::from_bits(TIME.load(Ordering::Relaxed)),
+            sample: SAMPLE.load(Ordering::Relaxed) as usize,
+            period: Float::from_bits(PERIOD.load(Ordering::Relaxed)),
+        };
+        fence(Ordering::Acquire);
+        if SEQ.load(Ordering::Relaxed) == seq {
+            return (line.period > 0.0).then_some(line);
+        }
+    }
+}
+
+/// Where audio time is right now by the smoothed clock, in seconds. Unlike [`audio_time`] it
+/// moves smoothly between render callbacks, so it's what the app and visuals should follow
+pub fn now() -> Float {
+    match latest() {
+        Some(line) => line.sample_at(load::now()) / sample_rate() as Float,
+        None => audio_time(),
+    }
+}
+
+/// The audio thread's side of the clock, one per backend
+#[derive(Default)]
+pub struct AudioClock {
+    dll: Dll,
+}
+
+impl AudioClock {
+    /// Call once a block with the sample it starts on and the wall clock time the callback
+    /// started at, or None for the sample while nothing is playing
+    pub fn tick(&mut self, sample: Option<usize>, wall: Float, sample_rate: u32) {
+        match sample {
+            Some(sample) => publish(Some(self.dll.update(sample, wall, sample_rate))),
+            None => {
+                self.dll.reset();
+                publish(None);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Dll;
+
+    // a render callback that runs late by up to `jitter` seconds, on a device whose clock is
+    // `drift` off. Returns the loop's estimate against the true sample between each callback
+    fn simulate(jitter: f64, drift: f64, seconds: f64) -> Vec<(f64, f64)> {
+        let (sample_rate, block) = (48_000, 128);
+        let period = (1.0 + drift) / sample_rate as f64;
+        // a small linear congruential generator, so the jitter is the same every run
+        let mut seed: u64 = 1;
+        let mut random = move || {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 11) as f64 / (1u64 << 53) as f64
+        };
+        let mut dll = Dll::default();
+        let blocks = (seconds * sample_rate as f64 / block as f64) as usize;
+        (0..blocks)
+            .map(|i| {
+                let sample = i * block;
+                let due = sample as f64 * period;
+                let line = dll.update(sample, due + random() * jitter, sample_rate);
+                // halfway to the next callback
+                let wall = due + block as f64 * period * 0.5;
+                (line.sample_at(wall), wall / period)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_follows_jittery_callbacks() {
+        // a millisecond of jitter is 48 samples either way
+        let run = simulate(0.001, 0.0, 10.0);
+        let settled = &run[run.len() / 2..];
+        let worst = settled
+            .iter()
+            .map(|(estimate, actual)| (estimate - actual).abs())
+            .fold(0.0, f64::max);
+        // the mean lateness stays in, it's part of when the callbacks run
+        assert!(worst < 40.0, "worst error {worst} samples");
+        // and it never steps backwards
+        assert!(run.windows(2).all(|w| w[1].0 > w[0].0));
+    }
+
+    #[test]
+    fn test_follows_drifting_device() {
+        // 200ppm fast, well beyond a real device
+        let run = simulate(0.0005, 0.0002, 20.0);
+        let (estimate, actual) = run[run.len() - 1];
+        assert!((estimate - actual).abs() < 30.0);
+    }
+
+    #[test]
+    fn test_jumps_restart_the_loop() {
+        let mut dll = Dll::default();
+        dll.update(0, 10.0, 1_000);
+        dll.update(100, 10.1, 1_000);
+        // seeking back, then forward, starts again from the callback
+        let line = dll.update(50, 10.2, 1_000);
+        assert_eq!((line.sample, line.time), (50, 10.2));
+        let line = dll.update(10_000, 10.3, 1_000);
+        assert_eq!((line.sample, line.time), (10_000, 10.3));
+        assert_eq!(line.period, 0.001);
+    }
+}
@@ -0,0 +1,256 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use bevy::{
+    app::Update,
+    log::{info, warn},
+    prelude::{Plugin, Res, ResMut, Resource},
+};
+use libloading::Library;
+use sonars_plugin::{EntryPoint, PluginSoundFn, ABI_VERSION, ENTRY_POINT};
+
+use super::{OutputFn, SoundControl, MAX_CHANNELS};
+use crate::{
+    lang::{LangStatus, CODE_CROSSFADE},
+    watch::ScriptWatcher,
+};
+
+// cargo writes the library in several steps, wait for it to settle before loading
+const SETTLE_TIME: Duration = Duration::from_millis(300);
+static LOAD_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+pub struct DylibPlugin;
+
+impl Plugin for DylibPlugin {
+    fn build(&self, app: &mut bevy::prelude::App) {
+        app.add_systems(Update, reload);
+    }
+}
+
+struct Loaded {
+    plugin: PluginSoundFn,
+    panicked: AtomicBool,
+    // declared last so the library is unloaded after the closure is dropped
+    _lib: Option<Library>,
+}
+
+impl Drop for Loaded {
+    fn drop(&mut self) {
+        unsafe { (self.plugin.drop)(self.plugin.state) }
+    }
+}
+
+/// Handle to a loaded sound function, to check on it from outside the audio thread
+#[derive(Clone)]
+pub struct LoadedSoundFn(Arc<Loaded>);
+
+impl LoadedSoundFn {
+    /// A plugin that panics is silenced for good, until it is reloaded
+    pub fn panicked(&self) -> bool {
+        self.0.panicked.load(Ordering::Relaxed)
+    }
+
+    // nothing else holds the library, so dropping this unloads it
+    fn released(&self) -> bool {
+        Arc::strong_count(&self.0) == 1
+    }
+}
+
+fn wrap(plugin: PluginSoundFn, lib: Option<Library>) -> anyhow::Result<(OutputFn, LoadedSoundFn)> {
+    if plugin.abi_version != ABI_VERSION {
+        // the rest of the struct can't be trusted, so it is leaked rather than dropped
+        std::mem::forget(lib);
+        bail!(
+            "plugin was built for ABI version {}, sonars needs {ABI_VERSION}",
+            plugin.abi_version
+        );
+    }
+    let channels = plugin.channels as usize;
+    let loaded = Arc::new(Loaded {
+        plugin,
+        panicked: AtomicBool::new(false),
+        _lib: lib,
+    });
+    if !(1..=MAX_CHANNELS).contains(&channels) {
+        bail!("plugins can have 1 to {MAX_CHANNELS} channels, this one has {channels}");
+    }
+
+    let handle = LoadedSoundFn(loaded.clone());
+    // mono plays on both sides, like any other mono source
+    let sound_fn = OutputFn::from_frame_fn(channels.max(2), move |t, out| {
+        if loaded.panicked.load(Ordering::Relaxed) {
+            return out.fill(0.0);
+        }
+        let plugin = &loaded.plugin;
+        if !unsafe { (plugin.render)(plugin.state, t, out.as_mut_ptr()) } {
+            loaded.panicked.store(true, Ordering::Relaxed);
+            return out.fill(0.0);
+        }
+        if channels == 1 {
+            out[1] = out[0];
+        }
+    });
+    Ok((sound_fn, handle))
+}
+
+/// Load a sound function from a library built with `sonars_plugin::export_sound_fn!`
+pub fn load(path: &Path) -> anyhow::Result<(OutputFn, LoadedSoundFn)> {
+    // the OS hands back the already loaded library for a path it has seen, and a rebuild
+    // overwriting a loaded library can crash, so every load gets its own copy
+    let copy = std::env::temp_dir().join(format!(
+        "sonars_plugin_{}_{}{}",
+        std::process::id(),
+        LOAD_COUNT.fetch_add(1, Ordering::Relaxed),
+        std::env::consts::DLL_SUFFIX
+    ));
+    std::fs::copy(path, &copy).with_context(|| format!("copying {path:?}"))?;
+    let lib = unsafe { Library::new(&copy) }.with_context(|| format!("loading {path:?}"));
+    // already mapped, so the copy isn't needed any more (windows won't allow this, which is fine)
+    let _ = std::fs::remove_file(&copy);
+    let lib = lib?;
+
+    let plugin = unsafe {
+        let entry = lib
+            .get::<EntryPoint>(ENTRY_POINT)
+            .with_context(|| format!("{path:?} doesn't export a sound function"))?;
+        entry()
+    };
+    wrap(plugin, Some(lib))
+}
+
+/// Reloads a sound function library whenever it is rebuilt, insert it as a resource to start watching
+#[derive(Resource)]
+pub struct DylibWatcher {
+    watcher: ScriptWatcher,
+    changed_at: Option<Instant>,
+    loaded: Option<LoadedSoundFn>,
+    // replaced libraries, held until the audio thread lets go so they are unloaded here instead
+    retired: Vec<LoadedSoundFn>,
+}
+
+impl DylibWatcher {
+    pub fn new(path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        Ok(Self {
+            watcher: ScriptWatcher::new(path)?,
+            changed_at: None,
+            loaded: None,
+            retired: vec![],
+        })
+    }
+}
+
+fn report(status: &mut LangStatus, error: String) {
+    warn!("{error}");
+    status.error = Some(error);
+}
+
+fn reload(
+    watcher: Option<ResMut<DylibWatcher>>,
+    sound_control: Res<SoundControl>,
+    mut status: ResMut<LangStatus>,
+) {
+    let Some(mut watcher) = watcher else { return };
+    if watcher.watcher.take_changed() {
+        watcher.changed_at = Some(Instant::now());
+    }
+    if watcher
+        .changed_at
+        .is_some_and(|t| t.elapsed() > SETTLE_TIME)
+    {
+        watcher.changed_at = None;
+        match load(watcher.watcher.path()) {
+            Ok((sound_fn, loaded)) => {
+                info!("Loaded {:?}", watcher.watcher.path());
+                sound_control.push_output_fn_crossfade(sound_fn, CODE_CROSSFADE);
+                let previous = watcher.loaded.replace(loaded);
+                watcher.retired.extend(previous);
+                status.error = None;
+            }
+            Err(e) => report(&mut status, format!("{e:#}")),
+        }
+    }
+    if watcher.loaded.as_ref().is_some_and(LoadedSoundFn::panicked) {
+        let silenced = watcher.loaded.take();
+        watcher.retired.extend(silenced);
+        report(
+            &mut status,
+            "the sound plugin panicked and was silenced".into(),
+        );
+    }
+    watcher.retired.retain(|loaded| !loaded.released());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{load, wrap};
+
+    #[test]
+    fn test_panic_silences() {
+        let plugin = sonars_plugin::export(|t: f64| {
+            assert!(t < 1.0, "too late");
+            [t]
+        });
+        let (sound_fn, handle) = wrap(plugin, None).unwrap();
+        let frame = |t| {
+            let mut out = [0.0; 2];
+            sound_fn.render(t, &mut out);
+            out
+        };
+        assert_eq!(frame(0.5), [0.5, 0.5]);
+        assert!(!handle.panicked() && !handle.released());
+        assert_eq!(frame(2.0), [0.0, 0.0]);
+        assert!(handle.panicked());
+        assert_eq!(frame(0.5), [0.0, 0.0]);
+        drop(sound_fn);
+        assert!(handle.released());
+
+        let mut bad = sonars_plugin::export(|_| [0.0; 2]);
+        bad.abi_version += 1;
+        assert!(wrap(bad, None).is_err());
+        assert!(wrap(sonars_plugin::export(|_| [0.0; 33]), None).is_err());
+    }
+
+    #[test]
+    fn test_multichannel() {
+        let (sound_fn, _) = wrap(sonars_plugin::export(|_| [1.0, 2.0, 3.0, 4.0]), None).unwrap();
+        assert_eq!(sound_fn.channels(), 4);
+        let mut out = [0.0; 4];
+        sound_fn.render(0.0, &mut out);
+        assert_eq!(out, [1.0, 2.0, 3.0, 4.0]);
+    }
+
+    /// Loads the example plugin, which `cargo test --workspace` builds
+    #[test]
+    fn test_load_example() {
+        let path: PathBuf = [
+            env!("CARGO_MANIFEST_DIR"),
+            "target",
+            "debug",
+            "examples",
+            &format!(
+                "{}sine_plugin{}",
+                std::env::consts::DLL_PREFIX,
+                std::env::consts::DLL_SUFFIX
+            ),
+        ]
+        .iter()
+        .collect();
+        if !path.exists() {
+            eprintln!("skipping, {path:?} hasn't been built");
+            return;
+        }
+        let (sound_fn, _) = load(&path).unwrap();
+        let [l, r] = [sound_fn.channel(0.3, 0), sound_fn.channel(0.3, 1)];
+        assert_eq!(l, r);
+        assert!(l != 0.0 && l.abs() <= 0.1);
+    }
+}
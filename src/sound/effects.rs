@@ -0,0 +1,290 @@
+//! Stateful effects for aux buses. Unlike sound functions they see the signal itself rather than
+//! the time, so they run on the audio thread in order, a block at a time
+
+use serde::{Deserialize, Serialize};
+
+use super::{sample_rate, Float};
+
+/// Processes a bus in place, state like delay lines carries over from one block to the next
+pub trait Effect: Send {
+    /// `frames` are interleaved with `channels` channels, `dt` seconds apart
+    fn process(&mut self, frames: &mut [Float], channels: usize, dt: Float);
+
+    /// Enough to build the effect again, e.g. for a saved session. None if it can't be saved
+    fn spec(&self) -> Option<EffectSpec> {
+        None
+    }
+}
+
+impl Effect for Box<dyn Effect> {
+    fn process(&mut self, frames: &mut [Float], channels: usize, dt: Float) {
+        (**self).process(frames, channels, dt);
+    }
+
+    fn spec(&self) -> Option<EffectSpec> {
+        (**self).spec()
+    }
+}
+
+/// One of the built in effects and its settings
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum EffectSpec {
+    Thru,
+    Delay { time: Float, feedback: Float },
+    Reverb { room_size: Float, damping: Float },
+}
+
+impl EffectSpec {
+    pub fn build(&self) -> Box<dyn Effect> {
+        match *self {
+            Self::Thru => Box::new(Thru),
+            Self::Delay { time, feedback } => Box::new(Delay::new(time, feedback)),
+            Self::Reverb { room_size, damping } => Box::new(Reverb::new(room_size, damping)),
+        }
+    }
+}
+
+/// Passes the bus through untouched, for a plain submix
+pub struct Thru;
+
+impl Effect for Thru {
+    fn process(&mut self, _frames: &mut [Float], _channels: usize, _dt: Float) {}
+
+    fn spec(&self) -> Option<EffectSpec> {
+        Some(EffectSpec::Thru)
+    }
+}
+
+/// Longest delay time, in seconds
+pub const MAX_DELAY: Float = 4.0;
+
+/// Echoes, the output is only the delayed signal.
+/// The line is allocated up front for [`MAX_DELAY`] in stereo at the current sample rate, with
+/// more channels or a higher rate the longest delay is shorter
+pub struct Delay {
+    /// In seconds, up to [`MAX_DELAY`]
+    pub time: Float,
+    /// How much of each echo is fed back into the line, keep it below 1
+    pub feedback: Float,
+    // interleaved like the frames, never reallocated so the audio thread doesn't have to
+    line: Vec<Float>,
+    // frame the next sample is written to
+    pos: usize,
+    channels: usize,
+    dt: Float,
+}
+
+impl Delay {
+    pub fn new(time: Float, feedback: Float) -> Self {
+        Self {
+            time,
+            feedback,
+            line: vec![0.0; 2 * ((MAX_DELAY * sample_rate() as Float).ceil() as usize + 1)],
+            pos: 0,
+            channels: 0,
+            dt: 0.0,
+        }
+    }
+}
+
+impl Default for Delay {
+    fn default() -> Self {
+        Self::new(0.375, 0.4)
+    }
+}
+
+impl Effect for Delay {
+    fn process(&mut self, frames: &mut [Float], channels: usize, dt: Float) {
+        if channels != self.channels || dt != self.dt {
+            self.line.fill(0.0);
+            self.pos = 0;
+            self.channels = channels;
+            self.dt = dt;
+        }
+        let len = self.line.len() / channels;
+        let delay = ((self.time.clamp(0.0, MAX_DELAY) / dt).round() as usize).clamp(1, len - 1);
+        for frame in frames.chunks_exact_mut(channels) {
+            let read = (self.pos + len - delay) % len;
+            for (c, x) in frame.iter_mut().enumerate() {
+                let echo = self.line[read * channels + c];
+                self.line[self.pos * channels + c] = *x + echo * self.feedback;
+                *x = echo;
+            }
+            self.pos = (self.pos + 1) % len;
+        }
+    }
+
+    fn spec(&self) -> Option<EffectSpec> {
+        Some(EffectSpec::Delay {
+            time: self.time,
+            feedback: self.feedback,
+        })
+    }
+}
+
+// Freeverb's tunings, in samples at 44.1kHz
+const COMB_TUNINGS: [usize; 4] = [1116, 1188, 1277, 1356];
+const ALLPASS_TUNINGS: [usize; 2] = [556, 441];
+// added to the tunings for every other channel, so left and right decorrelate
+const STEREO_SPREAD: usize = 23;
+
+struct Comb {
+    buffer: Vec<Float>,
+    pos: usize,
+    // one pole lowpass in the feedback path
+    store: Float,
+}
+
+impl Comb {
+    fn process(&mut self, x: Float, feedback: Float, damping: Float) -> Float {
+        let out = self.buffer[self.pos];
+        self.store = out * (1.0 - damping) + self.store * damping;
+        self.buffer[self.pos] = x + self.store * feedback;
+        self.pos = (self.pos + 1) % self.buffer.len();
+        out
+    }
+}
+
+struct Allpass {
+    buffer: Vec<Float>,
+    pos: usize,
+}
+
+impl Allpass {
+    fn process(&mut self, x: Float) -> Float {
+        let delayed = self.buffer[self.pos];
+        self.buffer[self.pos] = x + delayed * 0.5;
+        self.pos = (self.pos + 1) % self.buffer.len();
+        delayed - x
+    }
+}
+
+/// A small Schroeder reverb after Freeverb, the output is only the reverb tail
+pub struct Reverb {
+    /// 0 to 1, bigger rooms ring for longer
+    pub room_size: Float,
+    /// 0 to 1, how quickly the high end dies away
+    pub damping: Float,
+    // per channel
+    combs: Vec<[Comb; 4]>,
+    allpasses: Vec<[Allpass; 2]>,
+    dt: Float,
+}
+
+impl Reverb {
+    pub fn new(room_size: Float, damping: Float) -> Self {
+        Self {
+            room_size,
+            damping,
+            combs: vec![],
+            allpasses: vec![],
+            dt: 0.0,
+        }
+    }
+
+    fn build(&mut self, channels: usize, dt: Float) {
+        let scale = 1.0 / (44_100.0 * dt);
+        let samples = |tuning: usize, c: usize| {
+            (((tuning + (c % 2) * STEREO_SPREAD) as Float * scale) as usize).max(1)
+        };
+        self.combs = (0..channels)
+            .map(|c| {
+                COMB_TUNINGS.map(|tuning| Comb {
+                    buffer: vec![0.0; samples(tuning, c)],
+                    pos: 0,
+                    store: 0.0,
+                })
+            })
+            .collect();
+        self.allpasses = (0..channels)
+            .map(|c| {
+                ALLPASS_TUNINGS.map(|tuning| Allpass {
+                    buffer: vec![0.0; samples(tuning, c)],
+                    pos: 0,
+                })
+            })
+            .collect();
+        self.dt = dt;
+    }
+}
+
+impl Default for Reverb {
+    fn default() -> Self {
+        Self::new(0.7, 0.5)
+    }
+}
+
+impl Effect for Reverb {
+    fn process(&mut self, frames: &mut [Float], channels: usize, dt: Float) {
+        if channels != self.combs.len() || dt != self.dt {
+            // only allocates when the output format changes
+            self.build(channels, dt);
+        }
+        let feedback = self.room_size.clamp(0.0, 1.0) * 0.28 + 0.7;
+        let damping = self.damping.clamp(0.0, 1.0) * 0.4;
+        for frame in frames.chunks_exact_mut(channels) {
+            for (c, x) in frame.iter_mut().enumerate() {
+                // the combs are summed, so scale down going in
+                let input = *x * 0.015;
+                let mut out: Float = self.combs[c]
+                    .iter_mut()
+                    .map(|comb| comb.process(input, feedback, damping))
+                    .sum();
+                for allpass in &mut self.allpasses[c] {
+                    out = allpass.process(out);
+                }
+                *x = out;
+            }
+        }
+    }
+
+    fn spec(&self) -> Option<EffectSpec> {
+        Some(EffectSpec::Reverb {
+            room_size: self.room_size,
+            damping: self.damping,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Delay, Effect, Reverb};
+
+    #[test]
+    fn test_delay() {
+        let mut delay = Delay::new(0.01, 0.5);
+        // an impulse on the left, at 1kHz the echo is 10 frames later
+        let mut frames = vec![0.0; 2 * 25];
+        frames[0] = 1.0;
+        let line = delay.line.as_ptr();
+        delay.process(&mut frames[..16], 2, 0.001);
+        delay.process(&mut frames[16..], 2, 0.001);
+        // the line was allocated up front
+        assert_eq!(delay.line.as_ptr(), line);
+        let left: Vec<_> = frames.iter().step_by(2).copied().collect();
+        assert_eq!(left[0], 0.0);
+        assert_eq!(left[10], 1.0);
+        assert_eq!(left[20], 0.5);
+        assert_eq!(left.iter().sum::<f64>(), 1.5);
+        assert!(frames.iter().skip(1).step_by(2).all(|x| *x == 0.0));
+    }
+
+    #[test]
+    fn test_reverb_tail() {
+        let mut reverb = Reverb::default();
+        let dt = 1.0 / 48_000.0;
+        let mut frames = vec![0.0; 2 * 48_000];
+        frames[0] = 1.0;
+        frames[1] = 1.0;
+        for block in frames.chunks_mut(2 * 128) {
+            reverb.process(block, 2, dt);
+        }
+        let energy = |frames: &[f64]| frames.iter().map(|x| x * x).sum::<f64>();
+        let (early, late) = frames.split_at(frames.len() / 2);
+        assert!(frames.iter().all(|x| x.is_finite()));
+        assert!(energy(early) > 0.0);
+        assert!(energy(late) < energy(early));
+        // the spread keeps the two sides from being identical
+        assert!(frames.chunks(2).any(|frame| frame[0] != frame[1]));
+    }
+}
@@ -0,0 +1,538 @@
+//! Changes timed by the audio thread rather than the frame clock. Events can be queued from any
+//! thread, the backend gives each one a time at the start of the next block it renders, and a bar
+//! or beat is the next one from there. Parameters and sound functions are functions of time, so
+//! once an event has a time they change on exactly that sample, whichever block it falls in.
+//! The audio thread's side never allocates: the queues have a fixed capacity, and what it is done
+//! with goes back to the control thread to be dropped, see [`collect_retired`]
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use bevy::log::warn;
+use crossbeam_queue::ArrayQueue;
+use once_cell::sync::Lazy;
+
+use super::{
+    beats_per_bar,
+    params::{settle_params, Param},
+    paused, timeline, Float, PAUSED,
+};
+
+/// How many events can wait for the audio thread, and how many of each kind the scheduler keeps
+pub const QUEUE_LEN: usize = 1024;
+static EVENTS: Lazy<ArrayQueue<(When, Action)>> = Lazy::new(|| ArrayQueue::new(QUEUE_LEN));
+// parameters and cues the audio thread has finished with
+static RETIRED: Lazy<ArrayQueue<Due>> = Lazy::new(|| ArrayQueue::new(QUEUE_LEN));
+
+/// When an event happens, in audio time
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum When {
+    /// At the start of the next block
+    #[default]
+    Now,
+    /// On this sample, or the next block if it has already gone
+    Sample(usize),
+    /// On the next beat of the [`super::Timeline`]
+    Beat,
+    /// On the next downbeat, see [`beats_per_bar`]
+    Bar,
+}
+
+impl When {
+    /// Audio time of the event, for a block starting at `now`
+    pub fn resolve(self, now: Float, sample_rate: u32) -> Float {
+        // a beat or bar that starts right on `now` counts as the next one
+        let next = |every: Float| {
+            let timeline = timeline();
+            timeline.time_at((timeline.beats(now) / every).ceil() * every)
+        };
+        match self {
+            When::Now => now,
+            When::Sample(sample) => (sample as Float / sample_rate as Float).max(now),
+            When::Beat => next(1.0),
+            When::Bar => next(beats_per_bar() as Float),
+        }
+    }
+}
+
+pub enum Action {
+    /// Move a registered parameter to a value, smoothed from the event's time. Changes wait in
+    /// the scheduler until playback gets there, so several can be lined up for one parameter
+    SetParam(Arc<Param>, Float),
+    /// Fire a [`Cue`], whatever waits on it starts from the event's time
+    Cue(Arc<Cue>),
+    /// Start the transport. Audio time stands still while paused,
+    /// so this is always at the start of the next block
+    Play,
+    /// Stop the transport, the rest of the block it lands in is silent
+    Stop,
+    /// Jump audio time to this many seconds, part way through a block if need be
+    Seek(Float),
+    /// Wrap audio time from the end of this region, in seconds, back to its start every time
+    /// playback reaches it. None plays straight on. Beats and bars past the end land in the
+    /// region, and parameter ramps and crossfades finish on every wrap
+    Loop(Option<(Float, Float)>),
+}
+
+/// Queue an action for the audio thread, it is dropped if [`QUEUE_LEN`] are already waiting
+pub fn schedule(when: When, action: Action) {
+    if EVENTS.push((when, action)).is_err() {
+        warn!("Too many events waiting for the audio thread, dropped one");
+    }
+}
+
+/// Drop what the audio thread has finished with, called regularly by the control thread
+pub fn collect_retired() {
+    while RETIRED.pop().is_some() {}
+}
+
+// hand over to the control thread to drop. If it has stopped collecting, dropping here is all
+// that's left
+fn retire(due: Due) {
+    let _ = RETIRED.push(due);
+}
+
+// add to a list without growing it, handing back what doesn't fit
+fn push_bounded<T>(list: &mut Vec<T>, item: T) -> Result<(), T> {
+    if list.len() == list.capacity() {
+        return Err(item);
+    }
+    list.push(item);
+    Ok(())
+}
+
+/// An audio time that isn't known until the audio thread fires its event, e.g. the start of a
+/// quantised crossfade. It is infinitely far off until then
+pub struct Cue(AtomicU64);
+
+impl Default for Cue {
+    fn default() -> Self {
+        Self(AtomicU64::new(Float::INFINITY.to_bits()))
+    }
+}
+
+impl Cue {
+    /// A cue that has already fired
+    pub fn at(time: Float) -> Self {
+        Self(AtomicU64::new(time.to_bits()))
+    }
+
+    pub fn time(&self) -> Float {
+        Float::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    /// Usually done by the audio thread through [`Action::Cue`]
+    pub fn fire(&self, time: Float) {
+        self.0.store(time.to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// A run of consecutive samples to render, blocks are split where the transport jumps
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Segment {
+    pub start: usize,
+    pub frames: usize,
+}
+
+// the first sample at or after `time`, give or take rounding
+fn sample_at(time: Float, sample_rate: u32) -> usize {
+    (time * sample_rate as Float - 1e-6).ceil().max(0.0) as usize
+}
+
+// what's left of a parameter change or cue once it has a time
+enum Due {
+    Param(Arc<Param>, Float),
+    Cue(Arc<Cue>),
+}
+
+/// The audio thread's side of the queue, one per backend
+pub struct Scheduler {
+    // audio times of stops and seeks that haven't happened yet, with where a seek goes to
+    jumps: Vec<(Float, Option<Float>)>,
+    looping: Option<(Float, Float)>,
+    // parameter changes and cues that playback hasn't reached yet, in time order
+    pending: Vec<(Float, Due)>,
+    // changes that came due while another thread was writing to the parameter
+    overdue: Vec<(Float, Arc<Param>, Float)>,
+    // cues that have fired, finished when the transport jumps so their crossfades don't go back
+    fired: Vec<Arc<Cue>>,
+    segments: Vec<Segment>,
+    next: usize,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self {
+            jumps: Vec::with_capacity(QUEUE_LEN),
+            looping: None,
+            pending: Vec::with_capacity(QUEUE_LEN),
+            overdue: Vec::with_capacity(QUEUE_LEN),
+            fired: Vec::with_capacity(QUEUE_LEN),
+            segments: Vec::with_capacity(QUEUE_LEN),
+            next: 0,
+        }
+    }
+}
+
+impl Scheduler {
+    /// Fire the events queued since the last block and work out what to render for a block of
+    /// `len` frames from sample `start`, in the order it goes out. The segments cover fewer than
+    /// `len` frames when a stop lands in the block, and none while paused
+    pub fn begin_block(&mut self, start: usize, len: usize, sample_rate: u32) -> &[Segment] {
+        let now = start as Float / sample_rate as Float;
+        let mut i = 0;
+        while let Some((time, param, value)) = self.overdue.get(i) {
+            if param.try_set_at(*value, *time) {
+                let (_, param, value) = self.overdue.remove(i);
+                retire(Due::Param(param, value));
+            } else {
+                i += 1;
+            }
+        }
+        let mut i = 0;
+        while let Some(cue) = self.fired.get(i) {
+            if Arc::strong_count(cue) == 1 {
+                retire(Due::Cue(self.fired.remove(i)));
+            } else {
+                i += 1;
+            }
+        }
+        while let Some((when, action)) = EVENTS.pop() {
+            let time = self.resolve(when, now, sample_rate);
+            let due = match action {
+                Action::SetParam(param, value) => Due::Param(param, value),
+                Action::Cue(cue) => Due::Cue(cue),
+                Action::Play => {
+                    PAUSED.store(false, Ordering::Relaxed);
+                    continue;
+                }
+                Action::Stop => {
+                    let _ = push_bounded(&mut self.jumps, (time, None));
+                    continue;
+                }
+                Action::Seek(to) => {
+                    let _ = push_bounded(&mut self.jumps, (time, Some(to)));
+                    continue;
+                }
+                Action::Loop(region) => {
+                    self.looping = region;
+                    continue;
+                }
+            };
+            if self.pending.len() == self.pending.capacity() {
+                retire(due);
+                continue;
+            }
+            let i = self.pending.partition_point(|(t, _)| *t <= time);
+            self.pending.insert(i, (time, due));
+        }
+        self.segments.clear();
+        let target = |to: Float| (to.max(0.0) * sample_rate as Float).round() as usize;
+        let mut pos = start;
+        if paused() {
+            self.fire(pos, pos + 1, sample_rate);
+            // time stands still, so only seeks that are due happen. A stop left over from before
+            // would cut the next start short
+            let mut seek = None;
+            self.jumps.retain(|&(time, to)| match to {
+                Some(to) if sample_at(time, sample_rate) <= start => {
+                    seek = Some(target(to));
+                    false
+                }
+                Some(_) => true,
+                None => false,
+            });
+            if let Some(to) = seek {
+                self.jump(pos, to, sample_rate);
+                pos = to;
+            }
+            self.next = pos;
+            return &self.segments;
+        }
+        // an empty or backwards region would never get anywhere
+        let looping = self
+            .looping
+            .map(|(start, end)| (target(start), target(end)))
+            .filter(|(start, end)| start < end);
+        let mut left = len;
+        while !paused() && left > 0 {
+            // the earliest jump still ahead, one before it waits for a wrap or seek to get there
+            let jump = self
+                .jumps
+                .iter()
+                .enumerate()
+                .map(|(i, (time, _))| (Some(i), sample_at(*time, sample_rate)))
+                .filter(|(_, at)| *at >= pos)
+                .min_by_key(|(_, at)| *at);
+            // the loop only wraps when playback runs into its end, not after a seek past it
+            let wrap = looping
+                .filter(|(_, end)| pos <= *end)
+                .map(|(_, end)| (None, end));
+            // a jump on the same sample as the wrap goes first
+            let next = match (jump, wrap) {
+                (Some(jump), Some(wrap)) if wrap.1 < jump.1 => Some(wrap),
+                (jump, wrap) => jump.or(wrap),
+            }
+            .filter(|(_, at)| *at < pos + left)
+            // a loop so short it fills the segments plays on to the end of the block
+            .filter(|_| self.segments.len() + 1 < self.segments.capacity());
+            let Some((i, at)) = next else {
+                self.segments.push(Segment {
+                    start: pos,
+                    frames: left,
+                });
+                self.fire(pos, pos + left, sample_rate);
+                pos += left;
+                break;
+            };
+            if at > pos {
+                let frames = at - pos;
+                self.segments.push(Segment { start: pos, frames });
+                self.fire(pos, at, sample_rate);
+                left -= frames;
+            }
+            let to = match i.map(|i| self.jumps.swap_remove(i).1) {
+                Some(Some(to)) => target(to),
+                Some(None) => {
+                    pos = at;
+                    PAUSED.store(true, Ordering::Relaxed);
+                    continue;
+                }
+                None => looping.map_or(at, |(start, _)| start),
+            };
+            self.jump(at, to, sample_rate);
+            pos = to;
+        }
+        self.next = pos;
+        &self.segments
+    }
+
+    // bars and beats past the end of the loop come round again once it wraps
+    fn resolve(&self, when: When, now: Float, sample_rate: u32) -> Float {
+        let time = when.resolve(now, sample_rate);
+        match (when, self.looping) {
+            (When::Beat | When::Bar, Some((start, end))) if start < end && now <= end => {
+                if time < end {
+                    return time;
+                }
+                // a loop shorter than a bar has its start as the next downbeat
+                Some(when.resolve(start, sample_rate))
+                    .filter(|&time| time < end)
+                    .unwrap_or(start)
+            }
+            _ => time,
+        }
+    }
+
+    // make the parameter changes and fire the cues from sample `from` up to `until`, in order
+    fn fire(&mut self, from: usize, until: usize, sample_rate: u32) {
+        let mut i = 0;
+        while let Some((time, _)) = self.pending.get(i) {
+            let sample = sample_at(*time, sample_rate);
+            if sample >= until {
+                break;
+            }
+            if sample < from {
+                i += 1;
+                continue;
+            }
+            match self.pending.remove(i) {
+                (time, Due::Param(param, value)) => {
+                    if param.try_set_at(value, time) {
+                        retire(Due::Param(param, value));
+                    } else if let Err((_, param, value)) =
+                        push_bounded(&mut self.overdue, (time, param, value))
+                    {
+                        retire(Due::Param(param, value));
+                    }
+                }
+                (time, Due::Cue(cue)) => {
+                    cue.fire(time);
+                    if let Err(cue) = push_bounded(&mut self.fired, cue) {
+                        retire(Due::Cue(cue));
+                    }
+                }
+            }
+        }
+    }
+
+    // ramps and crossfades are functions of time and would go back to before they started,
+    // so they finish on a jump. A seek forward passes over everything up to where it lands
+    fn jump(&mut self, from: usize, to: usize, sample_rate: u32) {
+        settle_params();
+        for cue in self.fired.drain(..) {
+            cue.fire(Float::NEG_INFINITY);
+            retire(Due::Cue(cue));
+        }
+        let landed = to as Float / sample_rate as Float;
+        for (time, _) in &mut self.jumps {
+            if (from..to).contains(&sample_at(*time, sample_rate)) {
+                *time = landed;
+            }
+        }
+        self.fire(from, to, sample_rate);
+    }
+
+    /// Where the next block starts, after [`Self::begin_block`]
+    pub fn next_sample(&self) -> usize {
+        self.next
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::sound::{
+        params::{param, register_param, ParamSpec, Smoothing},
+        paused,
+    };
+
+    use super::{collect_retired, schedule, Action, Cue, Scheduler, Segment, When};
+
+    #[test]
+    fn test_resolve() {
+        let _globals = crate::lock_globals();
+        // at the default 120bpm
+        assert_eq!(When::Now.resolve(1.2, 1_000), 1.2);
+        assert_eq!(When::Sample(1_500).resolve(1.2, 1_000), 1.5);
+        assert_eq!(When::Sample(1_000).resolve(1.2, 1_000), 1.2);
+        assert_eq!(When::Beat.resolve(1.2, 1_000), 1.5);
+        assert_eq!(When::Beat.resolve(1.5, 1_000), 1.5);
+        // 4 beats to the bar
+        assert_eq!(When::Bar.resolve(1.2, 1_000), 2.0);
+    }
+
+    #[test]
+    fn test_scheduler() {
+        let _globals = crate::lock_globals();
+        let spec = ParamSpec::new(0.0, 1.0, 0.0).smoothing(Smoothing::None);
+        let scheduled = register_param("test_scheduled", spec);
+        let cue = Arc::new(Cue::default());
+        let mut scheduler = Scheduler::default();
+
+        schedule(
+            When::Sample(1_010),
+            Action::SetParam(scheduled.clone(), 1.0),
+        );
+        schedule(When::Sample(1_100), Action::Cue(cue.clone()));
+        schedule(When::Sample(1_250), Action::Stop);
+        assert_eq!(cue.time(), f64::INFINITY);
+        let segment = |start, frames| Segment { start, frames };
+        assert_eq!(
+            scheduler.begin_block(1_000, 128, 1_000),
+            [segment(1_000, 128)]
+        );
+        assert_eq!(scheduler.next_sample(), 1_128);
+        // on the sample, whichever block it is in
+        assert_eq!(param("test_scheduled")(1.009), 0.0);
+        assert_eq!(param("test_scheduled")(1.010), 1.0);
+        assert_eq!(cue.time(), 1.1);
+        assert!(!paused());
+
+        assert_eq!(
+            scheduler.begin_block(1_128, 128, 1_000),
+            [segment(1_128, 122)]
+        );
+        assert!(paused());
+        assert!(scheduler.begin_block(1_250, 128, 1_000).is_empty());
+        assert_eq!(scheduler.next_sample(), 1_250);
+
+        // seeking while paused moves the transport without playing
+        schedule(When::Now, Action::Seek(2.0));
+        assert!(scheduler.begin_block(1_250, 128, 1_000).is_empty());
+        assert_eq!(scheduler.next_sample(), 2_000);
+
+        // a seek part way through splits the block
+        schedule(When::Now, Action::Play);
+        schedule(When::Sample(2_050), Action::Seek(1.0));
+        assert_eq!(
+            scheduler.begin_block(2_000, 128, 1_000),
+            [segment(2_000, 50), segment(1_000, 78)]
+        );
+        assert_eq!(scheduler.next_sample(), 1_078);
+        assert!(!paused());
+
+        // a loop wraps as often as it fits in the block
+        schedule(When::Now, Action::Loop(Some((1.0, 1.1))));
+        assert_eq!(
+            scheduler.begin_block(1_078, 128, 1_000),
+            [segment(1_078, 22), segment(1_000, 100), segment(1_000, 6)]
+        );
+        // but not once a seek has gone past the end
+        schedule(When::Now, Action::Seek(1.5));
+        assert_eq!(
+            scheduler.begin_block(1_006, 128, 1_000),
+            [segment(1_500, 128)]
+        );
+        schedule(When::Now, Action::Loop(None));
+        assert_eq!(
+            scheduler.begin_block(1_628, 128, 1_000),
+            [segment(1_628, 128)]
+        );
+    }
+
+    #[test]
+    fn test_param_events() {
+        let _globals = crate::lock_globals();
+        let spec = ParamSpec::new(0.0, 1.0, 0.0).smoothing(Smoothing::None);
+        let lined_up = register_param("test_lined_up", spec);
+        let mut scheduler = Scheduler::default();
+        let set = |sample, value| {
+            schedule(
+                When::Sample(sample),
+                Action::SetParam(lined_up.clone(), value),
+            )
+        };
+        // queued out of order, both far enough ahead to land in later blocks
+        set(300, 0.75);
+        set(200, 0.5);
+        scheduler.begin_block(0, 128, 1_000);
+        // a change made meanwhile doesn't cancel the ones waiting
+        lined_up.set_at(0.25, 0.1);
+        assert_eq!(param("test_lined_up")(0.15), 0.25);
+        scheduler.begin_block(128, 128, 1_000);
+        assert_eq!(param("test_lined_up")(0.199), 0.25);
+        assert_eq!(param("test_lined_up")(0.2), 0.5);
+        scheduler.begin_block(256, 128, 1_000);
+        assert_eq!(param("test_lined_up")(0.35), 0.75);
+        // the events' handles go back to be dropped off the audio thread
+        assert!(Arc::strong_count(&lined_up) > 2);
+        collect_retired();
+        assert_eq!(Arc::strong_count(&lined_up), 2);
+    }
+
+    #[test]
+    fn test_loop_events() {
+        let _globals = crate::lock_globals();
+        let spec = ParamSpec::new(0.0, 1.0, 0.0).smoothing(Smoothing::Linear(1.0));
+        let looped = register_param("test_looped", spec);
+        let cue = Arc::new(Cue::default());
+        let mut scheduler = Scheduler::default();
+        let segment = |start, frames| Segment { start, frames };
+
+        // at 120bpm the next beat and bar are after the end, so they land on the loop's start
+        schedule(When::Now, Action::Play);
+        schedule(When::Now, Action::Loop(Some((1.0, 1.8))));
+        schedule(When::Bar, Action::Cue(cue.clone()));
+        schedule(When::Beat, Action::SetParam(looped, 1.0));
+        scheduler.begin_block(1_600, 128, 1_000);
+        assert_eq!(cue.time(), f64::INFINITY);
+        assert_eq!(
+            scheduler.begin_block(1_728, 128, 1_000),
+            [segment(1_728, 72), segment(1_000, 56)]
+        );
+        assert_eq!(cue.time(), 1.0);
+        assert_eq!(param("test_looped")(1.5), 0.5);
+
+        // the ramp and the crossfade finish on the next wrap instead of starting over
+        assert_eq!(
+            scheduler.begin_block(1_056, 1_000, 1_000),
+            [segment(1_056, 744), segment(1_000, 256)]
+        );
+        assert_eq!(param("test_looped")(1.0), 1.0);
+        assert_eq!(cue.time(), f64::NEG_INFINITY);
+    }
+}
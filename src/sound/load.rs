@@ -0,0 +1,136 @@
+use std::{
+    collections::VecDeque,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use bevy::prelude::{ResMut, Resource};
+
+use super::Float;
+
+/// How many frames of load the history graph keeps
+pub const HISTORY_LEN: usize = 240;
+
+// written by the audio thread, and taken by `update` once a frame. times are in nanoseconds
+static BUSY: AtomicU64 = AtomicU64::new(0);
+static AVAILABLE: AtomicU64 = AtomicU64::new(0);
+static WORST_BLOCK: AtomicU64 = AtomicU64::new(0);
+static DEADLINE: AtomicU64 = AtomicU64::new(0);
+static XRUNS: AtomicU64 = AtomicU64::new(0);
+
+cfg_if::cfg_if! {
+    if #[cfg(target_arch = "wasm32")] {
+        // todo_major: performance.now() isn't available in a worklet, and Date only has millisecond resolution
+        /// Seconds on a wall clock that reads the same on every thread
+        pub fn now() -> f64 {
+            js_sys::Date::now() / 1000.0
+        }
+    } else {
+        /// Seconds on a wall clock that reads the same on every thread
+        pub fn now() -> f64 {
+            static EPOCH: once_cell::sync::Lazy<std::time::Instant> =
+                once_cell::sync::Lazy::new(std::time::Instant::now);
+            EPOCH.elapsed().as_secs_f64()
+        }
+    }
+}
+
+/// Call at the start of a render callback, and hand the result to [`end_block`]
+pub fn start_block() -> f64 {
+    now()
+}
+
+/// Record how long a block of `frames` took to render against how long it had
+pub fn end_block(start: f64, frames: usize, sample_rate: u32) {
+    let took = ((now() - start) * 1e9) as u64;
+    let deadline = (frames as f64 / sample_rate as f64 * 1e9) as u64;
+    BUSY.fetch_add(took, Ordering::Relaxed);
+    AVAILABLE.fetch_add(deadline, Ordering::Relaxed);
+    WORST_BLOCK.fetch_max(took, Ordering::Relaxed);
+    DEADLINE.store(deadline, Ordering::Relaxed);
+    // the device ran dry waiting for this block
+    if took > deadline {
+        XRUNS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// How hard the audio thread is working
+#[derive(Resource, Default)]
+pub struct DspLoad {
+    /// Time spent rendering over the time available, for the last frame (1.0 is 100%)
+    pub load: Float,
+    /// Longest block since the last reset, in seconds
+    pub worst_block: Float,
+    /// Time each block has to render in, in seconds
+    pub deadline: Float,
+    /// Blocks that missed their deadline since the last reset
+    pub xruns: u64,
+    /// Load for the last [`HISTORY_LEN`] frames, oldest first
+    pub history: VecDeque<Float>,
+}
+
+impl DspLoad {
+    pub fn reset(&mut self) {
+        self.worst_block = 0.0;
+        self.xruns = 0;
+        WORST_BLOCK.store(0, Ordering::Relaxed);
+        XRUNS.store(0, Ordering::Relaxed);
+    }
+
+    fn collect(&mut self) {
+        let busy = BUSY.swap(0, Ordering::Relaxed);
+        let available = AVAILABLE.swap(0, Ordering::Relaxed);
+        self.load = if available > 0 {
+            busy as Float / available as Float
+        } else {
+            0.0
+        };
+        self.worst_block = self
+            .worst_block
+            .max(WORST_BLOCK.swap(0, Ordering::Relaxed) as Float * 1e-9);
+        self.deadline = DEADLINE.load(Ordering::Relaxed) as Float * 1e-9;
+        self.xruns += XRUNS.swap(0, Ordering::Relaxed);
+
+        if self.history.len() == HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back(self.load);
+    }
+}
+
+pub fn update(mut load: ResMut<DspLoad>) {
+    load.collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{end_block, now, DspLoad, HISTORY_LEN};
+
+    #[test]
+    fn test_load() {
+        let _globals = crate::lock_globals();
+        let mut load = DspLoad::default();
+        load.collect();
+        load.reset();
+
+        // 128 frames at 48kHz have 2.7ms to render, these take 10ms
+        end_block(now() - 0.01, 128, 48_000);
+        end_block(now() - 0.01, 128, 48_000);
+        load.collect();
+        assert!(load.load > 3.0, "{}", load.load);
+        assert!(load.worst_block >= 0.01);
+        assert!((load.deadline - 128.0 / 48_000.0).abs() < 1e-6);
+        assert_eq!(load.xruns, 2);
+
+        // nothing rendered, e.g. while paused
+        load.collect();
+        assert_eq!(load.load, 0.0);
+        assert_eq!(load.xruns, 2);
+        load.reset();
+        assert_eq!(load.xruns, 0);
+
+        for _ in 0..HISTORY_LEN * 2 {
+            load.collect();
+        }
+        assert_eq!(load.history.len(), HISTORY_LEN);
+    }
+}
@@ -0,0 +1,1244 @@
+//! Named tracks, each with its own sound function and a strip of gain, pan, mute and solo.
+//! Each track renders into its own buffer on a pool of worker threads, then the buffers are
+//! summed in track order, so the result is the same however the tracks were shared out.
+//! Tracks can send to aux buses running an [`Effect`], which render after them in dependency order
+
+use std::{
+    panic::AssertUnwindSafe,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+use anyhow::bail;
+
+use crate::math::{cos, sin, slice};
+
+use super::{
+    effects::{Effect, EffectSpec},
+    empty_output_fn,
+    events::Cue,
+    load, panic_message, Float, OutputFn, MAX_CHANNELS, TRACK_PANICS,
+};
+
+/// More workers than this just spend their time waking up
+const MAX_WORKERS: usize = 8;
+/// The share of a block's playing time the audio thread waits for workers, the rest is left for
+/// mixing and the backend
+const WORKER_WAIT: Float = 0.5;
+
+/// Frames mixed at a time, the render quantum of both backends. Longer calls are split up, and
+/// so are blocks with more than [`MAX_CHANNELS`] channels
+pub const MAX_BLOCK: usize = 128;
+// samples in a track or bus buffer, enough for a block at any channel count
+const SCRATCH_LEN: usize = MAX_BLOCK * MAX_CHANNELS;
+
+// A buffer allocated on the control thread, so the audio thread never has to
+type Scratch = Arc<Mutex<Vec<Float>>>;
+
+fn scratch() -> Scratch {
+    Arc::new(Mutex::new(vec![0.0; SCRATCH_LEN]))
+}
+
+// Handed out to every block any mixer renders, so a buffer can say which block it belongs to
+static NEXT_BLOCK: AtomicU64 = AtomicU64::new(1);
+
+// What feedback sends sent a bus, for the block after `block`
+struct Feedback {
+    buffer: Mutex<Vec<Float>>,
+    block: AtomicU64,
+}
+
+impl Feedback {
+    fn new() -> Arc<Self> {
+        Arc::new(Self {
+            buffer: Mutex::new(vec![0.0; SCRATCH_LEN]),
+            block: AtomicU64::new(0),
+        })
+    }
+}
+
+/// Where `push_soundfn` and code without a track name go, it is always the first track
+pub const MAIN_TRACK: &str = "main";
+
+/// Mixer controls for a track, shared between the UI and the audio thread
+pub struct Strip {
+    gain: AtomicU64,
+    pan: AtomicU64,
+    muted: AtomicBool,
+    soloed: AtomicBool,
+    // loudest sample since the meter was last read, non-negative so the bits order like the floats
+    peak: AtomicU64,
+    // gain and pan at the end of the last block, changes are ramped over a block so they don't click
+    applied_gain: AtomicU64,
+    applied_pan: AtomicU64,
+    // 1 while the strip can be heard and 0 while it is muted or soloed out, ramped the same way
+    applied_gate: AtomicU64,
+}
+
+impl Default for Strip {
+    fn default() -> Self {
+        Self {
+            gain: AtomicU64::new(Float::to_bits(1.0)),
+            pan: AtomicU64::new(Float::to_bits(0.0)),
+            muted: AtomicBool::new(false),
+            soloed: AtomicBool::new(false),
+            peak: AtomicU64::new(Float::to_bits(0.0)),
+            applied_gain: AtomicU64::new(Float::to_bits(1.0)),
+            applied_pan: AtomicU64::new(Float::to_bits(0.0)),
+            applied_gate: AtomicU64::new(Float::to_bits(1.0)),
+        }
+    }
+}
+
+impl Strip {
+    /// Linear gain, 1 leaves the track alone
+    pub fn gain(&self) -> Float {
+        Float::from_bits(self.gain.load(Ordering::Relaxed))
+    }
+
+    pub fn set_gain(&self, gain: Float) {
+        self.gain.store(gain.max(0.0).to_bits(), Ordering::Relaxed);
+    }
+
+    /// From -1 (left) to 1 (right), only the first two output channels are panned
+    pub fn pan(&self) -> Float {
+        Float::from_bits(self.pan.load(Ordering::Relaxed))
+    }
+
+    pub fn set_pan(&self, pan: Float) {
+        self.pan
+            .store(pan.clamp(-1.0, 1.0).to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn muted(&self) -> bool {
+        self.muted.load(Ordering::Relaxed)
+    }
+
+    pub fn set_muted(&self, muted: bool) {
+        self.muted.store(muted, Ordering::Relaxed);
+    }
+
+    /// While any track or bus is soloed, only soloed ones play, along with whatever sends to a
+    /// soloed bus so it has something to play
+    pub fn soloed(&self) -> bool {
+        self.soloed.load(Ordering::Relaxed)
+    }
+
+    pub fn set_soloed(&self, soloed: bool) {
+        self.soloed.store(soloed, Ordering::Relaxed);
+    }
+
+    /// Loudest sample since the last call, after gain and pan
+    pub fn take_peak(&self) -> Float {
+        Float::from_bits(self.peak.swap(Float::to_bits(0.0), Ordering::Relaxed))
+    }
+
+    /// Per channel gains, for the first two channels and then the rest
+    fn gains(gain: Float, pan: Float) -> [Float; 3] {
+        if pan == 0.0 {
+            // so a centred track is exactly unity, the law below is a rounding error off
+            return [gain; 3];
+        }
+        // the equal power curve scaled so the louder side stays at unity, so panning only ever
+        // turns the other side down, and a hard panned track is as loud as a centred one
+        let p = (pan + 1.0) * 0.5;
+        let (left, right) = (cos(p * 0.25), sin(p * 0.25));
+        let louder = left.max(right);
+        [gain * left / louder, gain * right / louder, gain]
+    }
+
+    // false once a strip that can't be heard has faded out, so there's no need to render it
+    fn active(&self, audible: bool) -> bool {
+        audible || Float::from_bits(self.applied_gate.load(Ordering::Relaxed)) > 0.0
+    }
+
+    /// Apply gain and pan to interleaved frames and meter them, fading out if it isn't `audible`
+    fn process(&self, frames: &mut [Float], channels: usize, audible: bool) {
+        let (gain, pan) = (self.gain(), self.pan());
+        let gate: Float = if audible { 1.0 } else { 0.0 };
+        let from_gate = Float::from_bits(self.applied_gate.swap(gate.to_bits(), Ordering::Relaxed));
+        let from = Self::gains(
+            Float::from_bits(self.applied_gain.swap(gain.to_bits(), Ordering::Relaxed)) * from_gate,
+            Float::from_bits(self.applied_pan.swap(pan.to_bits(), Ordering::Relaxed)),
+        );
+        let to = Self::gains(gain * gate, pan);
+        // a mono output isn't panned
+        let which: &[usize] = if channels == 1 { &[2] } else { &[0, 1] };
+        let len = (frames.len() / channels).max(1) as Float;
+        let mut peak: Float = 0.0;
+        for (i, frame) in frames.chunks_exact_mut(channels).enumerate() {
+            let x = (i + 1) as Float / len;
+            for (c, sample) in frame.iter_mut().enumerate() {
+                let g = which.get(c).copied().unwrap_or(2);
+                *sample *= from[g] + (to[g] - from[g]) * x;
+                peak = peak.max(sample.abs());
+            }
+        }
+        self.peak.fetch_max(peak.to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// Level of a send, shared between the UI and the audio thread
+#[derive(Default)]
+pub struct SendLevel(AtomicU64);
+
+impl SendLevel {
+    pub fn get(&self) -> Float {
+        Float::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    pub fn set(&self, level: Float) {
+        self.0.store(level.max(0.0).to_bits(), Ordering::Relaxed);
+    }
+}
+
+// A send with the bus it goes to resolved to its index in the mix
+struct Route {
+    to: usize,
+    level: Arc<SendLevel>,
+    feedback: bool,
+}
+
+pub struct Track {
+    pub name: String,
+    pub sound_fn: OutputFn,
+    pub strip: Arc<Strip>,
+    routes: Vec<Route>,
+    // set after a panic, so the track stays silent until SoundControl replaces it
+    failed: AtomicBool,
+    buffer: Scratch,
+    // the block `buffer` was last rendered for
+    rendered: AtomicU64,
+}
+
+impl Track {
+    pub fn new(name: impl Into<String>, sound_fn: OutputFn, strip: Arc<Strip>) -> Self {
+        Self::with_buffer(name.into(), sound_fn, strip, scratch())
+    }
+
+    fn with_buffer(name: String, sound_fn: OutputFn, strip: Arc<Strip>, buffer: Scratch) -> Self {
+        Self {
+            name,
+            sound_fn,
+            strip,
+            routes: vec![],
+            failed: AtomicBool::new(false),
+            buffer,
+            rendered: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Shared so that rebuilding the mix doesn't lose the effect's state, only the audio thread locks it
+pub type SharedEffect = Arc<Mutex<Box<dyn Effect>>>;
+
+pub struct Bus {
+    pub name: String,
+    pub strip: Arc<Strip>,
+    effect: SharedEffect,
+    routes: Vec<Route>,
+    // what was sent to the bus this block, and what feedback sends have sent it for the next.
+    // Both carry over from one mix to the next, so a tail isn't cut off
+    input: Scratch,
+    feedback: Arc<Feedback>,
+    // soloed, or sends on to a bus that is, worked out at the start of each block
+    leads_to_solo: AtomicBool,
+}
+
+/// Everything the audio thread renders, swapped as a whole so it only ever clones the Arc.
+/// Buses are in dependency order, each only sends on to the buses after it, apart from feedback sends
+#[derive(Default)]
+pub struct Mix {
+    pub tracks: Vec<Track>,
+    pub buses: Vec<Bus>,
+}
+
+impl Mix {
+    /// Tracks without any buses
+    pub fn from_tracks(tracks: Vec<Track>) -> Self {
+        Self {
+            tracks,
+            buses: vec![],
+        }
+    }
+}
+
+/// Worker threads to render tracks on, leaving a core for the audio thread and one for the app
+pub fn default_workers() -> usize {
+    thread::available_parallelism()
+        .map_or(0, |n| n.get().saturating_sub(2))
+        .min(MAX_WORKERS)
+}
+
+#[derive(Clone, Copy)]
+struct Block {
+    // from NEXT_BLOCK
+    id: u64,
+    t0: Float,
+    dt: Float,
+    // interleaved samples, not frames
+    len: usize,
+    channels: usize,
+    any_soloed: bool,
+}
+
+// Tracks are dealt out round robin, lane 0 is the calling thread and lane n is worker n - 1
+struct Job {
+    mix: Arc<Mix>,
+    lane: usize,
+    lanes: usize,
+    block: Block,
+}
+
+// Whether a track or bus with these routes should be heard, see `Strip::soloed`
+fn audible(strip: &Strip, routes: &[Route], buses: &[Bus], any_soloed: bool) -> bool {
+    let feeds_solo = || {
+        routes
+            .iter()
+            .any(|route| !route.feedback && buses[route.to].leads_to_solo.load(Ordering::Relaxed))
+    };
+    !strip.muted() && (!any_soloed || strip.soloed() || feeds_solo())
+}
+
+/// Render every `lanes`th track starting from `lane` into the track's own buffer
+fn render_lane(mix: &Mix, lane: usize, lanes: usize, block: Block) {
+    for track in mix.tracks.iter().skip(lane).step_by(lanes) {
+        // still held by a worker that missed an earlier deadline, the track sits this block out
+        let Ok(mut buffer) = track.buffer.try_lock() else {
+            continue;
+        };
+        let buffer = &mut buffer[..block.len];
+        buffer.fill(0.0);
+        let strip = &track.strip;
+        let audible = audible(strip, &track.routes, &mix.buses, block.any_soloed);
+        if strip.active(audible) && !track.failed.load(Ordering::Relaxed) {
+            let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                track
+                    .sound_fn
+                    .render_block(block.t0, block.dt, &mut buffer[..], block.channels)
+            }));
+            if let Err(payload) = result {
+                buffer.fill(0.0);
+                track.failed.store(true, Ordering::Relaxed);
+                TRACK_PANICS.push((track.name.clone(), panic_message(&*payload)));
+            }
+        }
+        // even when silent, so the strip doesn't ramp from stale values when it comes back
+        strip.process(buffer, block.channels, audible);
+        track.rendered.store(block.id, Ordering::Release);
+    }
+}
+
+// What a worker is doing, it starts out idle at 0 and is only handed a job once it is done with
+// the last one
+const RENDERING: u8 = 1;
+const DONE: u8 = 2;
+
+#[derive(Default)]
+struct Lane {
+    state: AtomicU8,
+    // only locked to hand the job over, and never while the worker is rendering
+    job: Mutex<Option<Job>>,
+    stop: AtomicBool,
+}
+
+struct Worker {
+    lane: Arc<Lane>,
+    thread: thread::Thread,
+}
+
+impl Worker {
+    fn spawn() -> Self {
+        let lane = Arc::new(Lane::default());
+        let shared = lane.clone();
+        let handle = thread::Builder::new()
+            .name("sonars track worker".into())
+            // ends when the Mixer, and with it the worker, is dropped
+            .spawn(move || {
+                while !shared.stop.load(Ordering::Relaxed) {
+                    if shared.state.load(Ordering::Acquire) != RENDERING {
+                        thread::park();
+                        continue;
+                    }
+                    let job = shared.job.lock().ok().and_then(|mut job| job.take());
+                    if let Some(Job {
+                        mix,
+                        lane,
+                        lanes,
+                        block,
+                    }) = job
+                    {
+                        render_lane(&mix, lane, lanes, block);
+                    }
+                    shared.state.store(DONE, Ordering::Release);
+                }
+            })
+            .expect("failed to spawn a track worker");
+        Self {
+            lane,
+            thread: handle.thread().clone(),
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        self.lane.stop.store(true, Ordering::Relaxed);
+        self.thread.unpark();
+    }
+}
+
+/// Renders a [`Mix`], a panicking track is silenced and reported to
+/// [`SoundControl`](super::SoundControl) so the rest keep playing.
+/// Every buffer is allocated up front, so rendering never allocates or blocks
+pub struct Mixer {
+    workers: Vec<Worker>,
+    // give up on workers well before the block should have gone out
+    realtime: bool,
+    // the last block rendered, feedback sent in it comes back in the next
+    last_block: u64,
+}
+
+impl Mixer {
+    /// Spawns `workers` threads, up to [`MAX_WORKERS`], to share the tracks with the calling
+    /// thread, with none every track renders on the calling thread. It waits for the workers
+    /// however long they take, which is what offline rendering wants
+    pub fn new(workers: usize) -> Self {
+        Self {
+            workers: (0..workers.min(MAX_WORKERS))
+                .map(|_| Worker::spawn())
+                .collect(),
+            realtime: false,
+            last_block: 0,
+        }
+    }
+
+    /// Like [`Self::new`] for the audio thread: a worker that hasn't finished within
+    /// [`WORKER_WAIT`] of the block's playing time is left to it, and its tracks are silent for
+    /// that block
+    pub fn realtime(workers: usize) -> Self {
+        Self {
+            realtime: true,
+            ..Self::new(workers)
+        }
+    }
+
+    /// Add every track and bus into the interleaved `frames`, at times `t0`, `t0 + dt`, ...
+    pub fn render(
+        &mut self,
+        mix: &Arc<Mix>,
+        frames: &mut [Float],
+        channels: usize,
+        t0: Float,
+        dt: Float,
+    ) {
+        if channels == 0 {
+            return;
+        }
+        // the scratch buffers only fit a full block up to MAX_CHANNELS
+        let block_frames = MAX_BLOCK.min(SCRATCH_LEN / channels).max(1);
+        for (b, block) in frames.chunks_mut(block_frames * channels).enumerate() {
+            let t0 = t0 + (b * block_frames) as Float * dt;
+            self.render_block(mix, block, channels, t0, dt);
+        }
+    }
+
+    fn render_block(
+        &mut self,
+        mix: &Arc<Mix>,
+        frames: &mut [Float],
+        channels: usize,
+        t0: Float,
+        dt: Float,
+    ) {
+        let started = load::now();
+        let id = NEXT_BLOCK.fetch_add(1, Ordering::Relaxed);
+        let previous = std::mem::replace(&mut self.last_block, id);
+        let tracks = &mix.tracks;
+        let len = frames.len();
+        let block = Block {
+            id,
+            t0,
+            dt,
+            len,
+            channels,
+            any_soloed: tracks.iter().any(|track| track.strip.soloed())
+                || mix.buses.iter().any(|bus| bus.strip.soloed()),
+        };
+        // buses only send on to the buses after them, so this sees where each route leads first
+        for (b, bus) in mix.buses.iter().enumerate().rev() {
+            let leads_to_solo = bus.strip.soloed()
+                || bus.routes.iter().any(|route| {
+                    !route.feedback
+                        && route.to > b
+                        && mix.buses[route.to].leads_to_solo.load(Ordering::Relaxed)
+                });
+            bus.leads_to_solo.store(leads_to_solo, Ordering::Relaxed);
+        }
+        // no point waking more workers than there are tracks
+        let lanes = (self.workers.len() + 1).min(tracks.len()).max(1);
+        let workers = &self.workers[..lanes - 1];
+        let mut sent = [false; MAX_WORKERS];
+        for (i, worker) in workers.iter().enumerate() {
+            let lane = &worker.lane;
+            // a worker still on a block it missed leaves its tracks out of this one
+            if lane.state.load(Ordering::Acquire) == RENDERING {
+                continue;
+            }
+            let Ok(mut job) = lane.job.try_lock() else {
+                continue;
+            };
+            *job = Some(Job {
+                mix: mix.clone(),
+                lane: i + 1,
+                lanes,
+                block,
+            });
+            drop(job);
+            lane.state.store(RENDERING, Ordering::Release);
+            worker.thread.unpark();
+            sent[i] = true;
+        }
+        render_lane(mix, 0, lanes, block);
+        let deadline = started + WORKER_WAIT * (len / channels) as Float * dt;
+        for (worker, _) in workers.iter().zip(sent).filter(|(_, sent)| *sent) {
+            while worker.lane.state.load(Ordering::Acquire) == RENDERING
+                && !(self.realtime && load::now() > deadline)
+            {
+                thread::yield_now();
+            }
+        }
+
+        // feedback this mixer sent last block goes in first
+        for bus in &mix.buses {
+            let feedback = &bus.feedback;
+            if let (Ok(mut input), Ok(mut sent)) =
+                (bus.input.try_lock(), feedback.buffer.try_lock())
+            {
+                match feedback.block.swap(id, Ordering::Relaxed) == previous {
+                    true => input[..len].copy_from_slice(&sent[..len]),
+                    false => input[..len].fill(0.0),
+                }
+                sent.fill(0.0);
+            }
+        }
+
+        for track in tracks {
+            if track.rendered.load(Ordering::Acquire) != block.id {
+                continue;
+            }
+            let Ok(buffer) = track.buffer.try_lock() else {
+                continue;
+            };
+            let buffer = &buffer[..len];
+            slice::add(frames, buffer);
+            for route in &track.routes {
+                if let Ok(mut input) = mix.buses[route.to].input.try_lock() {
+                    slice::add_scaled(&mut input[..len], buffer, route.level.get());
+                }
+            }
+        }
+
+        for (b, bus) in mix.buses.iter().enumerate() {
+            let Ok(mut input) = bus.input.try_lock() else {
+                continue;
+            };
+            let buffer = &mut input[..len];
+            let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                match bus.effect.try_lock() {
+                    Ok(mut effect) => {
+                        effect.process(&mut buffer[..], channels, dt);
+                        true
+                    }
+                    // a panic poisons the effect, the bus stays silent until it gets a new one
+                    Err(_) => false,
+                }
+            }));
+            let processed = match result {
+                Ok(processed) => processed,
+                Err(payload) => {
+                    TRACK_PANICS.push((bus.name.clone(), panic_message(&*payload)));
+                    false
+                }
+            };
+            if !processed {
+                buffer.fill(0.0);
+            }
+            let audible = audible(&bus.strip, &bus.routes, &mix.buses, block.any_soloed);
+            bus.strip.process(buffer, channels, audible);
+            slice::add(frames, buffer);
+            for route in &bus.routes {
+                let to = &mix.buses[route.to];
+                let target = match route.feedback {
+                    true => &to.feedback.buffer,
+                    false if route.to > b => &to.input,
+                    false => continue,
+                };
+                if let Ok(mut target) = target.try_lock() {
+                    slice::add_scaled(&mut target[..len], buffer, route.level.get());
+                }
+            }
+        }
+    }
+}
+
+/// A send from a track or bus to a bus, after the sender's gain and pan
+pub struct AuxSend {
+    pub to: String,
+    pub level: Arc<SendLevel>,
+    /// Arrives a block late, the only way a bus can send back to a bus that feeds it
+    pub feedback: bool,
+}
+
+/// The control side of a track
+pub struct TrackState {
+    pub name: String,
+    pub strip: Arc<Strip>,
+    pub sends: Vec<AuxSend>,
+    // what the track is playing, or fading to
+    next_fn: OutputFn,
+    // what the audio thread renders, a crossfade into next_fn while fading
+    playing: OutputFn,
+    // when the crossfade starts and how long it takes
+    fade: Option<(Arc<Cue>, Float)>,
+    // what played before the last push, to fall back to after a panic
+    previous: OutputFn,
+    buffer: Scratch,
+}
+
+impl TrackState {
+    fn new(name: String) -> Self {
+        Self {
+            name,
+            strip: Default::default(),
+            sends: vec![],
+            next_fn: empty_output_fn(),
+            playing: empty_output_fn(),
+            fade: None,
+            previous: empty_output_fn(),
+            buffer: scratch(),
+        }
+    }
+
+    /// The function the track is playing, or fading to
+    pub fn sound_fn(&self) -> &OutputFn {
+        &self.next_fn
+    }
+}
+
+/// The control side of a bus
+pub struct BusState {
+    pub name: String,
+    pub strip: Arc<Strip>,
+    pub sends: Vec<AuxSend>,
+    effect: SharedEffect,
+    spec: Option<EffectSpec>,
+    input: Scratch,
+    feedback: Arc<Feedback>,
+}
+
+impl BusState {
+    /// The bus's effect, if it can be saved
+    pub fn effect(&self) -> Option<EffectSpec> {
+        self.spec
+    }
+}
+
+/// Every track and bus [`SoundControl`](super::SoundControl) knows about.
+/// Tracks are in mixing order with the main track always first, buses in the order they were added
+pub struct TrackList {
+    tracks: Vec<TrackState>,
+    buses: Vec<BusState>,
+}
+
+impl Default for TrackList {
+    fn default() -> Self {
+        Self {
+            tracks: vec![TrackState::new(MAIN_TRACK.into())],
+            buses: vec![],
+        }
+    }
+}
+
+impl TrackList {
+    pub fn iter(&self) -> impl Iterator<Item = &TrackState> {
+        self.tracks.iter()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&TrackState> {
+        self.tracks.iter().find(|track| track.name == name)
+    }
+
+    pub fn main(&self) -> &TrackState {
+        &self.tracks[0]
+    }
+
+    pub fn buses(&self) -> impl Iterator<Item = &BusState> {
+        self.buses.iter()
+    }
+
+    pub fn bus(&self, name: &str) -> Option<&BusState> {
+        self.buses.iter().find(|bus| bus.name == name)
+    }
+
+    /// Add a bus, or give an existing one a new effect keeping its strip and sends.
+    /// Tracks and buses share names with sends, panics and meters, so a track's name is taken
+    pub fn add_bus(&mut self, name: &str, effect: Box<dyn Effect>) -> anyhow::Result<()> {
+        if self.get(name).is_some() {
+            bail!("there is already a track called {name:?}");
+        }
+        let spec = effect.spec();
+        let effect = Arc::new(Mutex::new(effect));
+        match self.buses.iter_mut().find(|bus| bus.name == name) {
+            Some(bus) => {
+                bus.effect = effect;
+                bus.spec = spec;
+            }
+            None => self.buses.push(BusState {
+                name: name.into(),
+                strip: Default::default(),
+                sends: vec![],
+                effect,
+                spec,
+                input: scratch(),
+                feedback: Feedback::new(),
+            }),
+        }
+        Ok(())
+    }
+
+    /// Removes the bus and every send to it
+    pub fn remove_bus(&mut self, name: &str) {
+        self.buses.retain(|bus| bus.name != name);
+        let sends = self.tracks.iter_mut().map(|track| &mut track.sends);
+        for sends in sends.chain(self.buses.iter_mut().map(|bus| &mut bus.sends)) {
+            sends.retain(|send| send.to != name);
+        }
+    }
+
+    /// Send `from`, a track or bus, to the bus `to`, or change the level of an existing send.
+    /// Only feedback sends may close a loop between buses
+    pub fn set_send(
+        &mut self,
+        from: &str,
+        to: &str,
+        level: Float,
+        feedback: bool,
+    ) -> anyhow::Result<()> {
+        if self.bus(to).is_none() {
+            bail!("there is no bus {to:?}");
+        }
+        let from_bus = self.bus(from).is_some();
+        if !from_bus && self.get(from).is_none() {
+            bail!("there is no track or bus {from:?}");
+        }
+        if feedback && !from_bus {
+            bail!("only buses have feedback sends, tracks always render before the buses");
+        }
+        if from_bus && !feedback && self.feeds(to, from) {
+            bail!("sending {from:?} to {to:?} would make a loop, it has to be a feedback send");
+        }
+        let sends = self.sends_mut(from).expect("checked above");
+        match sends.iter_mut().find(|send| send.to == to) {
+            Some(send) => {
+                send.level.set(level);
+                send.feedback = feedback;
+            }
+            None => {
+                let send = AuxSend {
+                    to: to.into(),
+                    level: Default::default(),
+                    feedback,
+                };
+                send.level.set(level);
+                sends.push(send);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn remove_send(&mut self, from: &str, to: &str) {
+        if let Some(sends) = self.sends_mut(from) {
+            sends.retain(|send| send.to != to);
+        }
+    }
+
+    fn sends_mut(&mut self, from: &str) -> Option<&mut Vec<AuxSend>> {
+        match self.buses.iter_mut().find(|bus| bus.name == from) {
+            Some(bus) => Some(&mut bus.sends),
+            None => self
+                .tracks
+                .iter_mut()
+                .find(|track| track.name == from)
+                .map(|track| &mut track.sends),
+        }
+    }
+
+    /// Whether the bus `from` reaches the bus `to` without going through a feedback send
+    fn feeds(&self, from: &str, to: &str) -> bool {
+        let mut stack = vec![from];
+        let mut seen = vec![];
+        while let Some(name) = stack.pop() {
+            if name == to {
+                return true;
+            }
+            if seen.contains(&name) {
+                continue;
+            }
+            seen.push(name);
+            if let Some(bus) = self.bus(name) {
+                let next = bus.sends.iter().filter(|send| !send.feedback);
+                stack.extend(next.map(|send| send.to.as_str()));
+            }
+        }
+        false
+    }
+
+    /// Bus indexes with every bus after the buses that send to it, otherwise in the order they were added
+    pub fn bus_order(&self) -> Vec<usize> {
+        let index = |name: &str| self.buses.iter().position(|bus| bus.name == name);
+        let mut senders = vec![0; self.buses.len()];
+        for bus in &self.buses {
+            for send in bus.sends.iter().filter(|send| !send.feedback) {
+                if let Some(to) = index(&send.to) {
+                    senders[to] += 1;
+                }
+            }
+        }
+        let mut order = vec![];
+        while let Some(next) = (0..self.buses.len()).find(|b| senders[*b] == 0) {
+            order.push(next);
+            // never picked again
+            senders[next] = usize::MAX;
+            for send in self.buses[next].sends.iter().filter(|send| !send.feedback) {
+                if let Some(to) = index(&send.to) {
+                    senders[to] -= 1;
+                }
+            }
+        }
+        order
+    }
+
+    /// Play `new_fn` on the track, fading from what it was playing over `fade` seconds from `now`.
+    /// A new track is added at the end, unless a bus already has its name
+    pub fn push(
+        &mut self,
+        name: &str,
+        new_fn: OutputFn,
+        fade: Float,
+        now: Float,
+    ) -> anyhow::Result<()> {
+        if fade > 0.0 {
+            return self.push_cued(name, new_fn, fade, Arc::new(Cue::at(now)));
+        }
+        let track = self.track_mut(name)?;
+        track.playing = new_fn.clone();
+        track.fade = None;
+        track.previous = std::mem::replace(&mut track.next_fn, new_fn);
+        Ok(())
+    }
+
+    /// Like [`Self::push`], but the fade, or the switch if `fade` is 0, waits for `cue` to fire
+    pub fn push_cued(
+        &mut self,
+        name: &str,
+        new_fn: OutputFn,
+        fade: Float,
+        cue: Arc<Cue>,
+    ) -> anyhow::Result<()> {
+        let track = self.track_mut(name)?;
+        track.playing =
+            OutputFn::crossfade_cued(track.playing.clone(), new_fn.clone(), cue.clone(), fade);
+        track.fade = Some((cue, fade));
+        track.previous = std::mem::replace(&mut track.next_fn, new_fn);
+        Ok(())
+    }
+
+    // adding it at the end if there's no such track
+    fn track_mut(&mut self, name: &str) -> anyhow::Result<&mut TrackState> {
+        let i = match self.tracks.iter().position(|track| track.name == name) {
+            Some(i) => i,
+            None if self.bus(name).is_some() => bail!("there is already a bus called {name:?}"),
+            None => {
+                self.tracks.push(TrackState::new(name.into()));
+                self.tracks.len() - 1
+            }
+        };
+        Ok(&mut self.tracks[i])
+    }
+
+    /// The main track can't be removed, it is silenced instead
+    pub fn remove(&mut self, name: &str) {
+        if name == MAIN_TRACK {
+            // there's always a main track, so this can't fail
+            let _ = self.push(MAIN_TRACK, empty_output_fn(), 0.0, 0.0);
+        } else {
+            self.tracks.retain(|track| track.name != name);
+        }
+    }
+
+    /// The audio thread has silenced the track, go back to what it played before.
+    /// If that panics too the track stays silent
+    pub fn panicked(&mut self, name: &str) {
+        if let Some(track) = self.tracks.iter_mut().find(|track| track.name == name) {
+            track.next_fn = std::mem::replace(&mut track.previous, empty_output_fn());
+            track.playing = track.next_fn.clone();
+            track.fade = None;
+        }
+    }
+
+    /// Stop rendering the old side of finished crossfades, true if any finished
+    pub fn finish_fades(&mut self, now: Float) -> bool {
+        let mut finished = false;
+        for track in &mut self.tracks {
+            if let Some((cue, fade)) = &track.fade {
+                // never for a cue that hasn't fired, its time is infinite
+                if now < cue.time() + fade {
+                    continue;
+                }
+                track.playing = track.next_fn.clone();
+                track.fade = None;
+                finished = true;
+            }
+        }
+        finished
+    }
+
+    /// What the audio thread should render
+    pub fn mix(&self) -> Arc<Mix> {
+        let order = self.bus_order();
+        let routes = |sends: &[AuxSend]| -> Vec<Route> {
+            sends
+                .iter()
+                .filter_map(|send| {
+                    let to = order.iter().position(|&b| self.buses[b].name == send.to)?;
+                    Some(Route {
+                        to,
+                        level: send.level.clone(),
+                        feedback: send.feedback,
+                    })
+                })
+                .collect()
+        };
+        let tracks = self
+            .tracks
+            .iter()
+            .map(|track| Track {
+                routes: routes(&track.sends),
+                ..Track::with_buffer(
+                    track.name.clone(),
+                    track.playing.clone(),
+                    track.strip.clone(),
+                    track.buffer.clone(),
+                )
+            })
+            .collect();
+        let buses = order
+            .iter()
+            .map(|&b| {
+                let bus = &self.buses[b];
+                Bus {
+                    name: bus.name.clone(),
+                    strip: bus.strip.clone(),
+                    effect: bus.effect.clone(),
+                    routes: routes(&bus.sends),
+                    input: bus.input.clone(),
+                    feedback: bus.feedback.clone(),
+                    leads_to_solo: AtomicBool::new(false),
+                }
+            })
+            .collect();
+        Arc::new(Mix { tracks, buses })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        thread,
+        time::Duration,
+    };
+
+    use crate::{
+        math::{saw, sin},
+        sound::{
+            effects::{Delay, Thru},
+            empty_output_fn,
+            events::Cue,
+            offline::render_mix_raw,
+            OutputFn, SoundFn, TRACK_PANICS,
+        },
+    };
+
+    use super::{Mix, Mixer, Strip, Track, TrackList, MAIN_TRACK};
+
+    fn track(name: &str, sound_fn: SoundFn) -> Track {
+        Track::new(name, OutputFn::new(sound_fn), Default::default())
+    }
+
+    #[test]
+    fn test_parallel_matches_sequential() {
+        // fresh strips each time, they remember their gain to ramp from
+        let mix = || {
+            let tracks: Vec<_> = (1..=7)
+                .map(|i| {
+                    let f = 55.0 * i as f64;
+                    track(
+                        &format!("{i}"),
+                        Box::new(move |t| [sin(f * t) / 7.0, saw(f * t) / 7.0]),
+                    )
+                })
+                .collect();
+            tracks[3].strip.set_pan(0.5);
+            tracks[5].strip.set_gain(0.5);
+            Arc::new(Mix::from_tracks(tracks))
+        };
+        let sequential = render_mix_raw(&mix(), 0, 48_000, 0.25, 4_800);
+        assert_eq!(sequential.len(), 4800);
+        // fewer workers than tracks, and more
+        for workers in [2, 10] {
+            assert_eq!(
+                render_mix_raw(&mix(), workers, 48_000, 0.25, 4_800),
+                sequential
+            );
+        }
+    }
+
+    #[test]
+    fn test_panicking_track() {
+        let _globals = crate::lock_globals();
+        let mix = Arc::new(Mix::from_tracks(vec![
+            track("steady", Box::new(|_| [0.5, 0.5])),
+            track(
+                "broken",
+                Box::new(|t| if t < 0.5 { [0.25; 2] } else { panic!("broken") }),
+            ),
+        ]));
+        let out = render_mix_raw(&mix, 1, 1_000, 0.0, 1_000);
+        assert_eq!(out[0], [0.75, 0.75]);
+        // silent from the block it panicked in, the other track plays on
+        assert_eq!(out[999], [0.5, 0.5]);
+        let (name, message) = TRACK_PANICS.pop().unwrap();
+        assert_eq!((name.as_str(), message.as_str()), ("broken", "broken"));
+        assert!(TRACK_PANICS.pop().is_none());
+    }
+
+    #[test]
+    fn test_late_worker() {
+        let slept = Arc::new(AtomicBool::new(false));
+        let mix = Arc::new(Mix::from_tracks(vec![
+            track("steady", Box::new(|_| [0.5, 0.5])),
+            // rendered by the worker, which takes far longer than a block the first time
+            track(
+                "slow",
+                Box::new(move |_| {
+                    if !slept.swap(true, Ordering::Relaxed) {
+                        thread::sleep(Duration::from_millis(50));
+                    }
+                    [0.25, 0.25]
+                }),
+            ),
+        ]));
+        let mut mixer = Mixer::realtime(1);
+        let mut frames = vec![0.0; 256];
+        mixer.render(&mix, &mut frames, 2, 0.0, 1.0 / 48_000.0);
+        assert!(frames.iter().all(|&x| x == 0.5));
+        // and it plays again once the worker has caught up
+        thread::sleep(Duration::from_millis(100));
+        frames.fill(0.0);
+        mixer.render(&mix, &mut frames, 2, 0.0, 1.0 / 48_000.0);
+        assert!(frames.iter().all(|&x| x == 0.75));
+    }
+
+    #[test]
+    fn test_strip() {
+        let mix = Arc::new(Mix::from_tracks(vec![
+            track("a", Box::new(|_| [1.0, 1.0])),
+            track("b", Box::new(|_| [0.25, 0.25])),
+        ]));
+        let tracks = &mix.tracks;
+        let last = || *render_mix_raw(&mix, 0, 1_000, 0.0, 500).last().unwrap();
+
+        assert_eq!(last(), [1.25, 1.25]);
+        assert_eq!(tracks[0].strip.take_peak(), 1.0);
+        assert_eq!(tracks[0].strip.take_peak(), 0.0);
+
+        tracks[0].strip.set_gain(0.5);
+        tracks[1].strip.set_pan(-1.0);
+        let [l, r] = last();
+        assert!((l - 0.75).abs() < 1e-12);
+        assert!((r - 0.5).abs() < 1e-12);
+        // part way over the near side stays at unity
+        tracks[1].strip.set_pan(0.5);
+        let [l, r] = last();
+        assert!((l - (0.5 + 0.25 * (std::f64::consts::SQRT_2 - 1.0))).abs() < 1e-12);
+        assert!((r - 0.75).abs() < 1e-12);
+
+        tracks[1].strip.set_pan(-1.0);
+        tracks[1].strip.set_soloed(true);
+        assert_eq!(last()[1], 0.0);
+        tracks[1].strip.set_muted(true);
+        assert_eq!(last(), [0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_gain_changes_are_ramped() {
+        let strip = Strip::default();
+        strip.set_gain(0.0);
+        let mut frames = vec![1.0; 8];
+        strip.process(&mut frames, 2, true);
+        assert_eq!(frames, [0.75, 0.75, 0.5, 0.5, 0.25, 0.25, 0.0, 0.0]);
+        let mut frames = vec![1.0; 8];
+        strip.process(&mut frames, 2, true);
+        assert_eq!(frames, vec![0.0; 8]);
+
+        // muting fades out the same way, and the gain change made while muted is kept
+        strip.set_gain(1.0);
+        let mut frames = vec![1.0; 8];
+        strip.process(&mut frames, 2, false);
+        assert_eq!(frames, vec![0.0; 8]);
+        assert!(!strip.active(false));
+        let mut frames = vec![1.0; 8];
+        strip.process(&mut frames, 2, true);
+        assert_eq!(frames, [0.25, 0.25, 0.5, 0.5, 0.75, 0.75, 1.0, 1.0]);
+        let mut frames = vec![1.0; 8];
+        strip.process(&mut frames, 2, false);
+        assert_eq!(frames, [0.75, 0.75, 0.5, 0.5, 0.25, 0.25, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_track_list() {
+        let one = || OutputFn::new(Box::new(|_| [1.0, 1.0]));
+        let two = || OutputFn::new(Box::new(|_| [2.0, 2.0]));
+        let mut list = TrackList::default();
+        list.push("drums", one(), 0.0, 0.0).unwrap();
+        list.push(MAIN_TRACK, one(), 0.0, 0.0).unwrap();
+        let names: Vec<_> = list.iter().map(|track| track.name.as_str()).collect();
+        assert_eq!(names, [MAIN_TRACK, "drums"]);
+
+        // only the pushed track fades
+        list.push("drums", two(), 2.0, 1.0).unwrap();
+        let tracks = &list.mix().tracks;
+        assert_eq!(tracks[0].sound_fn.channel(2.0, 0), 1.0);
+        let halfway = tracks[1].sound_fn.channel(2.0, 0);
+        assert!((halfway - 3.0 * std::f64::consts::FRAC_1_SQRT_2).abs() < 1e-12);
+        assert_eq!(list.get("drums").unwrap().sound_fn().channel(2.0, 0), 2.0);
+        assert!(!list.finish_fades(2.0));
+        assert!(list.finish_fades(3.0));
+        assert_eq!(list.mix().tracks[1].sound_fn.channel(2.0, 0), 2.0);
+
+        // the strip carries over to the new tracks
+        list.get("drums").unwrap().strip.set_gain(0.5);
+        assert_eq!(list.mix().tracks[1].strip.gain(), 0.5);
+        assert!(Arc::ptr_eq(
+            &list.mix().tracks[1].strip,
+            &list.get("drums").unwrap().strip
+        ));
+
+        // nothing changes until the cue fires, then exactly on its time
+        let cue = Arc::new(Cue::default());
+        list.push_cued("drums", one(), 0.0, cue.clone()).unwrap();
+        assert_eq!(list.mix().tracks[1].sound_fn.channel(100.0, 0), 2.0);
+        assert!(!list.finish_fades(100.0));
+        cue.fire(5.0);
+        let drums = list.mix();
+        assert_eq!(drums.tracks[1].sound_fn.channel(4.999, 0), 2.0);
+        assert_eq!(drums.tracks[1].sound_fn.channel(5.0, 0), 1.0);
+        assert!(list.finish_fades(5.0));
+
+        list.panicked("drums");
+        assert_eq!(list.mix().tracks[1].sound_fn.channel(0.0, 0), 2.0);
+        list.panicked("drums");
+        assert_eq!(list.mix().tracks[1].sound_fn.channel(0.0, 0), 0.0);
+
+        list.remove("drums");
+        list.remove(MAIN_TRACK);
+        assert_eq!(list.iter().count(), 1);
+        assert_eq!(list.main().sound_fn().channel(0.0, 0), 0.0);
+    }
+
+    #[test]
+    fn test_routing() {
+        let mut list = TrackList::default();
+        for bus in ["a", "b", "c"] {
+            list.add_bus(bus, Box::new(Thru)).unwrap();
+        }
+        // tracks and buses can't share a name
+        assert!(list.add_bus(MAIN_TRACK, Box::new(Thru)).is_err());
+        assert!(list.push("a", empty_output_fn(), 0.0, 0.0).is_err());
+        assert!(list.set_send("nowhere", "a", 1.0, false).is_err());
+        assert!(list.set_send(MAIN_TRACK, "nowhere", 1.0, false).is_err());
+        assert!(list.set_send(MAIN_TRACK, "a", 1.0, true).is_err());
+        list.set_send(MAIN_TRACK, "c", 1.0, false).unwrap();
+        list.set_send("c", "b", 1.0, false).unwrap();
+        list.set_send("b", "a", 1.0, false).unwrap();
+        assert_eq!(list.bus_order(), [2, 1, 0]);
+
+        // loops, including a bus to itself, have to go through a feedback send
+        assert!(list.set_send("a", "c", 1.0, false).is_err());
+        assert!(list.set_send("a", "a", 1.0, false).is_err());
+        list.set_send("a", "c", 0.5, true).unwrap();
+        assert_eq!(list.bus_order(), [2, 1, 0]);
+        let mix = list.mix();
+        let names: Vec<_> = mix.buses.iter().map(|bus| bus.name.as_str()).collect();
+        assert_eq!(names, ["c", "b", "a"]);
+
+        list.remove_bus("b");
+        assert!(list.bus("c").unwrap().sends.is_empty());
+        list.remove_send("a", "c");
+        assert!(list.bus("a").unwrap().sends.is_empty());
+        assert_eq!(list.main().sends.len(), 1);
+    }
+
+    #[test]
+    fn test_buses() {
+        let mut list = TrackList::default();
+        // an impulse, 1kHz so the 128 frame blocks are 0.128s long
+        let impulse = || OutputFn::new(Box::new(|t| if t == 0.0 { [1.0; 2] } else { [0.0; 2] }));
+        list.push(MAIN_TRACK, impulse(), 0.0, 0.0).unwrap();
+        list.add_bus("a", Box::new(Thru)).unwrap();
+        list.add_bus("b", Box::new(Thru)).unwrap();
+        list.set_send(MAIN_TRACK, "a", 1.0, false).unwrap();
+        list.set_send("a", "b", 1.0, false).unwrap();
+        list.set_send("b", "a", 0.5, true).unwrap();
+        let out = render_mix_raw(&list.mix(), 0, 1_000, 0.0, 500);
+        // dry, then through a, then through b
+        assert_eq!(out[0], [3.0, 3.0]);
+        assert_eq!(out[1], [0.0, 0.0]);
+        // b feeds back into a a block later, and round again
+        assert_eq!(out[128], [1.0, 1.0]);
+        assert_eq!(out[256], [0.5, 0.5]);
+
+        // a block for mute and solo changes to fade in or out
+        let settle = |list: &TrackList| render_mix_raw(&list.mix(), 0, 1_000, 0.0, 128);
+        list.bus("b").unwrap().strip.set_muted(true);
+        settle(&list);
+        let out = render_mix_raw(&list.mix(), 0, 1_000, 0.0, 500);
+        assert_eq!(out[0], [2.0, 2.0]);
+        assert_eq!(out[128], [0.0, 0.0]);
+        list.bus("b").unwrap().strip.set_muted(false);
+
+        // soloing a bus keeps what sends to it, the track's dry output through a, and silences b
+        list.bus("a").unwrap().strip.set_soloed(true);
+        settle(&list);
+        let out = render_mix_raw(&list.mix(), 0, 1_000, 0.0, 500);
+        assert_eq!(out[0], [2.0, 2.0]);
+        assert_eq!(out[128], [0.0, 0.0]);
+        list.bus("a").unwrap().strip.set_soloed(false);
+        settle(&list);
+
+        // a new effect, the sends stay
+        list.add_bus("a", Box::new(Delay::new(0.01, 0.0))).unwrap();
+        let out = render_mix_raw(&list.mix(), 0, 1_000, 0.0, 500);
+        assert_eq!(out[0], [1.0, 1.0]);
+        // through a, and on through b
+        assert_eq!(out[10], [2.0, 2.0]);
+    }
+}
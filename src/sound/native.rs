@@ -1,20 +1,34 @@
-use bevy::ecs::system::Resource;
-use itertools::izip;
+use bevy::{ecs::system::Resource, log::warn};
 use web_audio_api::{
-    context::{AudioContext, AudioContextOptions, AudioContextRegistration, BaseAudioContext},
-    node::{AudioNode, ChannelConfig},
+    context::{
+        AudioContext, AudioContextLatencyCategory, AudioContextOptions, AudioContextRegistration,
+        AudioContextRenderSizeCategory, BaseAudioContext,
+    },
+    enumerate_devices,
+    node::{
+        AudioNode, ChannelConfig, ChannelConfigOptions, ChannelCountMode, ChannelInterpretation,
+    },
     render::{AudioParamValues, AudioProcessor, AudioRenderQuantum, RenderScope},
+    MediaDeviceInfoKind,
 };
 
-use std::sync::Arc;
-
-use crate::sound::SAMPLE_RATE;
-
-use super::{empty_sound_fn, FloatOut, SoundFn, CURRENT_SOUND_FN, INV_SAMPLE_RATE, SAMPLE_INDEX};
+use super::{
+    clock::AudioClock,
+    events::Scheduler,
+    load,
+    mixer::{self, Mixer},
+    safety::SafetyStage,
+    sample_rate, set_sample_rate, Float, FloatOut, Latency, OutputDevice, OutputSettings,
+    CURRENT_MIX, SAMPLE_INDEX,
+};
 
-pub fn setup_worklet(context: &AudioContext) {
-    let noise = MyNode::new(context);
-    noise.connect(&context.destination());
+pub fn setup_worklet(context: &AudioContext, channels: usize) {
+    let destination = context.destination();
+    // discrete, so that e.g. an 8 speaker ring isn't up/down-mixed as if it were 7.1
+    destination.set_channel_interpretation(ChannelInterpretation::Discrete);
+    destination.set_channel_count(channels);
+    let noise = MyNode::new(context, channels);
+    noise.connect(&destination);
 }
 
 struct MyNode {
@@ -37,18 +51,23 @@ impl AudioNode for MyNode {
     }
 
     fn number_of_outputs(&self) -> usize {
-        2
+        1
     }
 }
 
 impl MyNode {
-    fn new<C: BaseAudioContext>(context: &C) -> Self {
+    fn new<C: BaseAudioContext>(context: &C, channels: usize) -> Self {
         context.register(move |registration| {
-            let render = MyProcessor::default();
+            let render = MyProcessor::new(channels);
 
             let node = MyNode {
                 registration,
-                channel_config: ChannelConfig::default(),
+                channel_config: ChannelConfigOptions {
+                    count: channels,
+                    count_mode: ChannelCountMode::Explicit,
+                    interpretation: ChannelInterpretation::Discrete,
+                }
+                .into(),
             };
 
             (node, Box::new(render))
@@ -57,13 +76,24 @@ impl MyNode {
 }
 
 struct MyProcessor {
-    _sound_fn: Arc<SoundFn>,
+    channels: usize,
+    // interleaved frames for one render quantum
+    frames: Vec<Float>,
+    mixer: Mixer,
+    safety: SafetyStage,
+    scheduler: Scheduler,
+    clock: AudioClock,
 }
 
-impl Default for MyProcessor {
-    fn default() -> Self {
+impl MyProcessor {
+    fn new(channels: usize) -> Self {
         Self {
-            _sound_fn: Arc::new(empty_sound_fn()),
+            channels,
+            frames: vec![0.0; channels * 128],
+            mixer: Mixer::realtime(mixer::default_workers()),
+            safety: SafetyStage::new(channels),
+            scheduler: Default::default(),
+            clock: Default::default(),
         }
     }
 }
@@ -74,34 +104,63 @@ impl AudioProcessor for MyProcessor {
         _inputs: &[AudioRenderQuantum],
         outputs: &mut [AudioRenderQuantum],
         _params: AudioParamValues,
-        _scope: &RenderScope,
+        scope: &RenderScope,
     ) -> bool {
+        let block_start = load::start_block();
+        let inv_sample_rate = 1.0 / scope.sample_rate as Float;
         let sample_idx = SAMPLE_INDEX.load(std::sync::atomic::Ordering::Relaxed);
         let output = &mut outputs[0];
-        output.set_number_of_channels(2);
-        // todo_major we should store a local copy of this and try lock instead, we don't want to be waiting on the lock while we should be processing audio
-        let sound_fn_guard = CURRENT_SOUND_FN.lock().unwrap();
-        let sound_fn = sound_fn_guard.as_ref();
-
-        let channels = output.channels_mut();
-        let (left, right) = channels.split_at_mut(1);
-        let channel_0 = &mut left[0];
-        let channel_1 = &mut right[0];
+        output.set_number_of_channels(self.channels);
+        let quantum_len = output.channel_data(0).len();
+        let sample_rate = scope.sample_rate as u32;
+        // all of it in one go, unless the transport stops or jumps part way through
+        let segments = self
+            .scheduler
+            .begin_block(sample_idx, quantum_len, sample_rate);
+        let first = segments.first().map(|segment| segment.start);
+        self.clock.tick(first, block_start, sample_rate);
+        if segments.is_empty() {
+            output.make_silent();
+            // a seek moves the counter even while stopped
+            SAMPLE_INDEX.store(
+                self.scheduler.next_sample(),
+                std::sync::atomic::Ordering::Relaxed,
+            );
+            return true;
+        }
+        let mix = CURRENT_MIX.load_full();
+
+        self.frames.resize(quantum_len * self.channels, 0.0);
+        self.frames.fill(0.0);
+        let mut offset = 0;
+        for segment in segments {
+            let end = offset + segment.frames;
+            self.mixer.render(
+                &mix,
+                &mut self.frames[offset * self.channels..end * self.channels],
+                self.channels,
+                segment.start as Float * inv_sample_rate,
+                inv_sample_rate,
+            );
+            offset = end;
+        }
+        self.safety.process(&mut self.frames, sample_rate);
 
-        izip!(channel_0.iter_mut(), channel_1.iter_mut())
+        output
+            .channels_mut()
+            .iter_mut()
             .enumerate()
-            .for_each(|(i, (f0, f1))| {
-                let idx = sample_idx + i;
-                let t = idx as f64 * INV_SAMPLE_RATE;
-                let [f0_n, f1_n] = sound_fn(t);
-                *f0 = f0_n as FloatOut;
-                *f1 = f1_n as FloatOut;
+            .for_each(|(c, channel)| {
+                channel.iter_mut().enumerate().for_each(|(i, f)| {
+                    *f = self.frames[i * self.channels + c] as FloatOut;
+                });
             });
 
         SAMPLE_INDEX.store(
-            sample_idx + channel_0.len(),
+            self.scheduler.next_sample(),
             std::sync::atomic::Ordering::Relaxed,
         );
+        load::end_block(block_start, quantum_len, sample_rate);
 
         true
     }
@@ -110,21 +169,82 @@ impl AudioProcessor for MyProcessor {
 #[derive(Resource)]
 pub struct SoundResources {
     pub ctx: AudioContext,
-    pub time_start: f64,
+    pub latency: Latency,
+    pub channels: usize,
+    pub max_channels: usize,
 }
 
-impl Default for SoundResources {
-    fn default() -> Self {
+impl SoundResources {
+    pub fn new(settings: &OutputSettings) -> Self {
+        let latency_hint = match settings.latency {
+            Latency::Interactive => AudioContextLatencyCategory::Interactive,
+            Latency::Balanced => AudioContextLatencyCategory::Balanced,
+            Latency::Playback => AudioContextLatencyCategory::Playback,
+            Latency::BufferSize(frames) => {
+                let rate = settings.sample_rate.unwrap_or_else(sample_rate);
+                AudioContextLatencyCategory::Custom(frames as f64 / rate as f64)
+            }
+        };
+
+        // AudioContext::new panics on an unknown sink, which happens when a device gets unplugged
+        let sink_id = if settings.sink_id.is_empty()
+            || output_devices().iter().any(|d| d.id == settings.sink_id)
+        {
+            settings.sink_id.clone()
+        } else {
+            warn!(
+                "Output device {:?} not found, falling back to the default device",
+                settings.sink_id
+            );
+            "".into()
+        };
+
         let ctx = AudioContext::new(AudioContextOptions {
-            latency_hint: web_audio_api::context::AudioContextLatencyCategory::Balanced,
-            sample_rate: Some(SAMPLE_RATE as f32),
-            sink_id: "".into(),
-            render_size_hint: web_audio_api::context::AudioContextRenderSizeCategory::Default,
+            latency_hint,
+            sample_rate: settings.sample_rate.map(|rate| rate as f32),
+            sink_id,
+            render_size_hint: AudioContextRenderSizeCategory::Default,
         });
 
-        setup_worklet(&ctx);
+        set_sample_rate(ctx.sample_rate() as u32);
 
-        let time_start = ctx.current_time();
-        Self { ctx, time_start }
+        let max_channels = ctx.destination().max_channels_count();
+        let channels = settings.channels.clamp(1, max_channels);
+        if channels != settings.channels {
+            warn!(
+                "Output device only supports {max_channels} channels, {} were requested",
+                settings.channels
+            );
+        }
+        setup_worklet(&ctx, channels);
+
+        Self {
+            ctx,
+            latency: settings.latency,
+            channels,
+            max_channels,
+        }
+    }
+
+    /// Latency reported by the output device, in seconds
+    pub fn output_latency(&self) -> Float {
+        self.ctx.base_latency() + self.ctx.output_latency()
     }
 }
+
+impl Drop for SoundResources {
+    fn drop(&mut self) {
+        self.ctx.close_sync();
+    }
+}
+
+pub fn output_devices() -> Vec<OutputDevice> {
+    enumerate_devices()
+        .into_iter()
+        .filter(|d| d.kind() == MediaDeviceInfoKind::AudioOutput)
+        .map(|d| OutputDevice {
+            id: d.device_id().into(),
+            label: d.label().into(),
+        })
+        .collect()
+}
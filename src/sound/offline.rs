@@ -0,0 +1,170 @@
+use std::{path::Path, sync::Arc};
+
+use hound::{SampleFormat, WavSpec, WavWriter};
+
+use super::{
+    mixer::{Mix, Mixer},
+    safety::{self, SafetyStage},
+    Float, FloatOut, SoundFn,
+};
+
+// frames per call when rendering tracks, the same as the native backend's render quantum
+const QUANTUM: usize = 128;
+
+/// Render a sound function without an audio device, at any sample rate, through the same
+/// [`SafetyStage`] as the device output. Time is in seconds, so the output has the same pitch
+/// whatever the rate is.
+pub fn render<const N: usize>(
+    sound_fn: &SoundFn<N>,
+    sample_rate: u32,
+    start: Float,
+    duration: Float,
+) -> Vec<[Float; N]> {
+    safe(sample_rate, duration, |frames| {
+        render_raw(sound_fn, sample_rate, start, frames)
+    })
+}
+
+/// Like [`render`] without the safety stage, `frames` frames exactly as the function gives them
+pub fn render_raw<const N: usize>(
+    sound_fn: &SoundFn<N>,
+    sample_rate: u32,
+    start: Float,
+    frames: usize,
+) -> Vec<[Float; N]> {
+    let inv_sample_rate = 1.0 / sample_rate as Float;
+    (0..frames)
+        .map(|i| sound_fn(start + i as Float * inv_sample_rate))
+        .collect()
+}
+
+/// Render a stereo mix the way the audio thread does, `workers` as in [`Mixer::new`]
+pub fn render_mix(
+    mix: &Arc<Mix>,
+    workers: usize,
+    sample_rate: u32,
+    start: Float,
+    duration: Float,
+) -> Vec<[Float; 2]> {
+    safe(sample_rate, duration, |frames| {
+        render_mix_raw(mix, workers, sample_rate, start, frames)
+    })
+}
+
+/// Like [`render_mix`] without the safety stage
+pub fn render_mix_raw(
+    mix: &Arc<Mix>,
+    workers: usize,
+    sample_rate: u32,
+    start: Float,
+    frames: usize,
+) -> Vec<[Float; 2]> {
+    let mut mixer = Mixer::new(workers);
+    let inv_sample_rate = 1.0 / sample_rate as Float;
+    let mut out = vec![0.0; frames * 2];
+    for (q, chunk) in out.chunks_mut(QUANTUM * 2).enumerate() {
+        let t0 = start + (q * QUANTUM) as Float * inv_sample_rate;
+        mixer.render(mix, chunk, 2, t0, inv_sample_rate);
+    }
+    out.chunks_exact(2)
+        .map(|frame| [frame[0], frame[1]])
+        .collect()
+}
+
+// `duration` seconds through a safety stage, rendering its lookahead beyond the end and dropping
+// it from the start so the output lines up with the raw render
+fn safe<const N: usize>(
+    sample_rate: u32,
+    duration: Float,
+    render_raw: impl FnOnce(usize) -> Vec<[Float; N]>,
+) -> Vec<[Float; N]> {
+    let frames = (duration * sample_rate as Float).round() as usize;
+    if !safety::enabled() {
+        return render_raw(frames);
+    }
+    let latency = safety::latency(sample_rate);
+    let mut out = render_raw(frames + latency);
+    SafetyStage::new(N).process(out.as_flattened_mut(), sample_rate);
+    out.drain(..latency);
+    out
+}
+
+/// Write frames as a 32 bit float WAV with one channel per frame element, as they are.
+/// Frames from [`render`] or [`render_mix`] have already been limited
+pub fn write_wav<const N: usize>(
+    path: impl AsRef<Path>,
+    frames: &[[Float; N]],
+    sample_rate: u32,
+) -> anyhow::Result<()> {
+    let spec = WavSpec {
+        channels: N as u16,
+        sample_rate,
+        bits_per_sample: 32,
+        sample_format: SampleFormat::Float,
+    };
+    let mut writer = WavWriter::create(path, spec)?;
+    for sample in frames.iter().flatten() {
+        writer.write_sample(*sample as FloatOut)?;
+    }
+    writer.finalize()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::math::sin;
+    use crate::sound::{safety::ceiling_db, Float, SoundFn, SAMPLE_RATES};
+
+    use super::{render, render_raw, write_wav};
+
+    #[test]
+    fn test_same_pitch_at_every_rate() {
+        let sound_fn: SoundFn = Box::new(|t| [sin(441.0 * t), 0.0]);
+        for sample_rate in SAMPLE_RATES {
+            let out = render(&sound_fn, sample_rate, 0.0, 1.0);
+            assert_eq!(out.len(), sample_rate as usize);
+            let rising_crossings = out
+                .windows(2)
+                .filter(|w| w[0][0] < 0.0 && w[1][0] >= 0.0)
+                .count();
+            // the crossing at t=0 starts on zero rather than rising through it
+            assert_eq!(rising_crossings, 440, "at {sample_rate}Hz");
+        }
+    }
+
+    #[test]
+    fn test_multichannel_wav() {
+        let sound_fn: SoundFn<8> = Box::new(|_| std::array::from_fn(|c| c as f64 / 8.0));
+        let frames = render_raw(&sound_fn, 48_000, 0.0, 480);
+        let dir = crate::test_dir("multichannel");
+        let path = dir.join("multichannel.wav");
+        write_wav(&path, &frames, 48_000).unwrap();
+
+        let mut reader = hound::WavReader::open(&path).unwrap();
+        assert_eq!(reader.spec().channels, 8);
+        let samples: Vec<f32> = reader.samples().map(Result::unwrap).collect();
+        assert_eq!(samples.len(), frames.len() * 8);
+        assert_eq!(samples[3], 3.0 / 8.0);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_limited_like_the_device() {
+        let loud: SoundFn = Box::new(|t| [4.0 * sin(441.0 * t), Float::NAN]);
+        let out = render(&loud, 48_000, 0.0, 0.5);
+        assert_eq!(out.len(), 24_000);
+        let ceiling = (10.0 as Float).powf(ceiling_db() / 20.0);
+        assert!(out.iter().flatten().all(|x| x.abs() <= ceiling));
+        assert!(out.iter().all(|frame| frame[1] == 0.0));
+
+        // lined up with the raw render rather than delayed by the lookahead
+        let quiet: SoundFn = Box::new(|t| [0.5 * sin(441.0 * t); 2]);
+        let raw = render_raw(&quiet, 48_000, 0.0, 24_000);
+        let out = render(&quiet, 48_000, 0.0, 0.5);
+        // give or take the DC blocker's phase shift
+        assert!(out
+            .iter()
+            .zip(&raw)
+            .all(|(a, b)| (a[0] - b[0]).abs() < 0.03));
+    }
+}
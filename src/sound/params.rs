@@ -0,0 +1,344 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{fence, AtomicU32, AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use arc_swap::ArcSwap;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use super::{audio_time, Float};
+
+// Swapped as a whole when parameters are added, values and specs are changed in place
+static PARAMS: Lazy<ArcSwap<HashMap<String, Arc<Param>>>> = Lazy::new(Default::default);
+
+/// How a parameter moves towards a new value, so changes don't click
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Smoothing {
+    None,
+    /// Exponential approach with this time constant in seconds
+    OnePole(Float),
+    /// Straight line to the new value over this many seconds
+    Linear(Float),
+}
+
+impl Default for Smoothing {
+    fn default() -> Self {
+        Smoothing::OnePole(0.02)
+    }
+}
+
+impl Smoothing {
+    /// Value `elapsed` seconds after a change from `from` to `to`
+    pub fn apply(self, from: Float, to: Float, elapsed: Float) -> Float {
+        if elapsed < 0.0 {
+            return from;
+        }
+        match self {
+            Smoothing::None => to,
+            Smoothing::OnePole(tau) if tau > 0.0 => to + (from - to) * (-elapsed / tau).exp(),
+            Smoothing::Linear(duration) if duration > 0.0 => {
+                from + (to - from) * (elapsed / duration).min(1.0)
+            }
+            _ => to,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ParamSpec {
+    pub min: Float,
+    pub max: Float,
+    pub default: Float,
+    pub smoothing: Smoothing,
+}
+
+impl ParamSpec {
+    pub fn new(min: Float, max: Float, default: Float) -> Self {
+        Self {
+            min,
+            max,
+            default,
+            smoothing: Default::default(),
+        }
+    }
+
+    pub fn smoothing(self, smoothing: Smoothing) -> Self {
+        Self { smoothing, ..self }
+    }
+}
+
+// A ramp: the value it started from, the one it is heading to, and the audio time it started
+type Ramp = (Float, Float, Float);
+
+/// A named value the audio thread reads without locking.
+/// Changes are stored as a ramp (from, to, audio time of the change) behind a seqlock,
+/// so the smoothed value is a pure function of time and any thread can read it.
+/// The spec is behind the same seqlock, so it can change under anything holding the parameter.
+/// A writer claims the seqlock by making its count odd, so writers never wait on a lock
+pub struct Param {
+    seq: AtomicU32,
+    from: AtomicU64,
+    to: AtomicU64,
+    changed: AtomicU64,
+    min: AtomicU64,
+    max: AtomicU64,
+    default: AtomicU64,
+    // which kind of smoothing, and its time
+    smoothing: AtomicU32,
+    smoothing_time: AtomicU64,
+}
+
+impl Param {
+    fn new(spec: ParamSpec, value: Float) -> Self {
+        let value = value.clamp(spec.min, spec.max);
+        let param = Self {
+            seq: AtomicU32::new(0),
+            from: AtomicU64::new(value.to_bits()),
+            to: AtomicU64::new(value.to_bits()),
+            changed: AtomicU64::new(0.0f64.to_bits()),
+            min: Default::default(),
+            max: Default::default(),
+            default: Default::default(),
+            smoothing: Default::default(),
+            smoothing_time: Default::default(),
+        };
+        param.store_spec(spec);
+        param
+    }
+
+    pub fn spec(&self) -> ParamSpec {
+        self.read().0
+    }
+
+    /// Change the range and smoothing in place, clamping the value to the new range
+    pub fn set_spec(&self, spec: ParamSpec) {
+        let clamp = |x: Float| x.clamp(spec.min, spec.max);
+        while !self.try_write(|_, (from, to, changed)| (spec, (clamp(from), clamp(to), changed))) {
+            std::hint::spin_loop();
+        }
+    }
+
+    // only while holding the seqlock, or reading under it
+    fn load_spec(&self) -> ParamSpec {
+        let time = Float::from_bits(self.smoothing_time.load(Ordering::Relaxed));
+        ParamSpec {
+            min: Float::from_bits(self.min.load(Ordering::Relaxed)),
+            max: Float::from_bits(self.max.load(Ordering::Relaxed)),
+            default: Float::from_bits(self.default.load(Ordering::Relaxed)),
+            smoothing: match self.smoothing.load(Ordering::Relaxed) {
+                0 => Smoothing::None,
+                1 => Smoothing::OnePole(time),
+                _ => Smoothing::Linear(time),
+            },
+        }
+    }
+
+    fn store_spec(&self, spec: ParamSpec) {
+        self.min.store(spec.min.to_bits(), Ordering::Relaxed);
+        self.max.store(spec.max.to_bits(), Ordering::Relaxed);
+        self.default
+            .store(spec.default.to_bits(), Ordering::Relaxed);
+        let (kind, time) = match spec.smoothing {
+            Smoothing::None => (0, 0.0),
+            Smoothing::OnePole(time) => (1, time),
+            Smoothing::Linear(time) => (2, time),
+        };
+        self.smoothing.store(kind, Ordering::Relaxed);
+        self.smoothing_time.store(time.to_bits(), Ordering::Relaxed);
+    }
+
+    fn load_ramp(&self) -> Ramp {
+        (
+            Float::from_bits(self.from.load(Ordering::Relaxed)),
+            Float::from_bits(self.to.load(Ordering::Relaxed)),
+            Float::from_bits(self.changed.load(Ordering::Relaxed)),
+        )
+    }
+
+    fn read(&self) -> (ParamSpec, Ramp) {
+        loop {
+            let seq = self.seq.load(Ordering::Acquire);
+            if seq % 2 == 1 {
+                std::hint::spin_loop();
+                continue;
+            }
+            let read = (self.load_spec(), self.load_ramp());
+            fence(Ordering::Acquire);
+            if self.seq.load(Ordering::Relaxed) == seq {
+                return read;
+            }
+        }
+    }
+
+    /// Smoothed value at time `t`
+    pub fn value(&self, t: Float) -> Float {
+        let (spec, (from, to, changed)) = self.read();
+        spec.smoothing.apply(from, to, t - changed)
+    }
+
+    /// The value being smoothed towards
+    pub fn target(&self) -> Float {
+        let (_, (_, to, _)) = self.read();
+        to
+    }
+
+    /// Start moving to a new value (clamped to the range) from the current audio time
+    pub fn set(&self, value: Float) {
+        self.set_at(value, audio_time());
+    }
+
+    /// Like [`Self::set`] from audio time `now`, waiting for any other writer to finish
+    pub fn set_at(&self, value: Float, now: Float) {
+        while !self.try_set_at(value, now) {
+            std::hint::spin_loop();
+        }
+    }
+
+    /// Like [`Self::set_at`], but gives up and returns false while another thread is writing.
+    /// This is how the audio thread changes parameters
+    pub fn try_set_at(&self, value: Float, now: Float) -> bool {
+        self.try_write(|spec, (from, old, changed)| {
+            let from = spec.smoothing.apply(from, old, now - changed);
+            (spec, (from, value.clamp(spec.min, spec.max), now))
+        })
+    }
+
+    /// Finish the ramp straight away, so a jump back in time doesn't undo the change.
+    /// Gives up like [`Self::try_set_at`]
+    pub fn try_settle(&self) -> bool {
+        self.try_write(|spec, (_, to, changed)| (spec, (to, to, changed)))
+    }
+
+    // claim the seqlock and replace the spec and ramp with what `f` makes of them
+    fn try_write(&self, f: impl FnOnce(ParamSpec, Ramp) -> (ParamSpec, Ramp)) -> bool {
+        let seq = self.seq.load(Ordering::Relaxed);
+        if seq % 2 == 1
+            || self
+                .seq
+                .compare_exchange(
+                    seq,
+                    seq.wrapping_add(1),
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                )
+                .is_err()
+        {
+            return false;
+        }
+        fence(Ordering::Release);
+        // nobody else writes while the count is odd
+        let old_spec = self.load_spec();
+        let (spec, (from, to, changed)) = f(old_spec, self.load_ramp());
+        if spec != old_spec {
+            self.store_spec(spec);
+        }
+        self.from.store(from.to_bits(), Ordering::Relaxed);
+        self.to.store(to.to_bits(), Ordering::Relaxed);
+        self.changed.store(changed.to_bits(), Ordering::Relaxed);
+        self.seq.store(seq.wrapping_add(2), Ordering::Release);
+        true
+    }
+}
+
+/// Add a parameter, or change the spec of an existing one in place while keeping its value
+pub fn register_param(name: &str, spec: ParamSpec) -> Arc<Param> {
+    if !PARAMS.load().contains_key(name) {
+        PARAMS.rcu(|params| {
+            let mut params = HashMap::clone(params);
+            params
+                .entry(name.to_string())
+                .or_insert_with(|| Arc::new(Param::new(spec, spec.default)));
+            params
+        });
+    }
+    let param = PARAMS.load()[name].clone();
+    if param.spec() != spec {
+        param.set_spec(spec);
+    }
+    param
+}
+
+/// Returns false if there is no parameter with this name
+pub fn set_param(name: &str, value: Float) -> bool {
+    match PARAMS.load().get(name) {
+        Some(param) => {
+            param.set(value);
+            true
+        }
+        None => false,
+    }
+}
+
+/// The registered parameter with this name
+pub fn get_param(name: &str) -> Option<Arc<Param>> {
+    PARAMS.load().get(name).cloned()
+}
+
+/// Finish every ramp, for the audio thread when the transport jumps. One that another thread is
+/// writing to is left alone, it's getting a new ramp anyway
+pub fn settle_params() {
+    for param in PARAMS.load().values() {
+        param.try_settle();
+    }
+}
+
+/// Every registered parameter, sorted by name
+pub fn params() -> Vec<(String, Arc<Param>)> {
+    let mut params: Vec<_> = PARAMS
+        .load()
+        .iter()
+        .map(|(name, param)| (name.clone(), param.clone()))
+        .collect();
+    params.sort_by(|a, b| a.0.cmp(&b.0));
+    params
+}
+
+/// Read a registered parameter from a sound function, e.g. `sin(param("freq")(t) * t)`,
+/// or pass it anywhere a [`crate::math::Callable`] goes. Unregistered parameters are 0
+pub fn param(name: &str) -> impl Fn(Float) -> Float + Clone + '_ {
+    move |t| PARAMS.load().get(name).map_or(0.0, |param| param.value(t))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{param, register_param, set_param, ParamSpec, Smoothing};
+
+    #[test]
+    fn test_smoothing() {
+        assert_eq!(Smoothing::None.apply(0.0, 1.0, 0.001), 1.0);
+        assert_eq!(Smoothing::Linear(0.1).apply(0.0, 1.0, 0.05), 0.5);
+        assert_eq!(Smoothing::Linear(0.1).apply(0.0, 1.0, 1.0), 1.0);
+        let one_pole = Smoothing::OnePole(0.1).apply(0.0, 1.0, 0.1);
+        assert!((one_pole - (1.0 - (-1.0f64).exp())).abs() < 1e-12);
+        // a read from before the change still sees the old value
+        assert_eq!(Smoothing::Linear(0.1).apply(0.0, 1.0, -0.5), 0.0);
+    }
+
+    #[test]
+    fn test_registry() {
+        let spec = ParamSpec::new(0.0, 10.0, 2.0).smoothing(Smoothing::None);
+        let cutoff = register_param("test_cutoff", spec);
+        assert_eq!(param("test_cutoff")(0.0), 2.0);
+        assert_eq!(param("test_missing")(0.0), 0.0);
+
+        assert!(set_param("test_cutoff", 20.0));
+        assert_eq!(cutoff.target(), 10.0);
+        assert!(!set_param("test_missing", 1.0));
+
+        // registering again keeps the value, a new range clamps it
+        register_param("test_cutoff", spec);
+        assert_eq!(param("test_cutoff")(1e9), 10.0);
+        let narrower = ParamSpec::new(0.0, 5.0, 0.0);
+        register_param("test_cutoff", narrower);
+        assert_eq!(param("test_cutoff")(1e9), 5.0);
+        // in place, so handles from before see the new spec too
+        assert_eq!(cutoff.spec(), narrower);
+        assert_eq!(cutoff.value(1e9), 5.0);
+        cutoff.set_at(7.0, 0.0);
+        assert_eq!(cutoff.target(), 5.0);
+    }
+}
@@ -0,0 +1,241 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use super::Float;
+
+pub const DEFAULT_CEILING_DB: Float = -0.3;
+// how far ahead the limiter looks, this is also the latency it adds
+const LOOKAHEAD: Float = 0.0015;
+// time for the limiter to recover after a peak
+const RELEASE: Float = 0.05;
+// the DC blocker's cutoff
+const DC_CUTOFF: Float = 10.0;
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+static CEILING: AtomicU64 = AtomicU64::new(DEFAULT_CEILING_DB.to_bits());
+static NAN_SAMPLES: AtomicU64 = AtomicU64::new(0);
+static CLIPPED_SAMPLES: AtomicU64 = AtomicU64::new(0);
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Turn the whole stage on or off, when off the output goes to the device untouched
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Limiter ceiling in dBFS
+pub fn ceiling_db() -> Float {
+    Float::from_bits(CEILING.load(Ordering::Relaxed))
+}
+
+pub fn set_ceiling_db(db: Float) {
+    CEILING.store(db.min(0.0).to_bits(), Ordering::Relaxed);
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Telemetry {
+    /// NaN or infinite samples replaced with silence
+    pub nan_samples: u64,
+    /// Samples over the ceiling that the limiter had to pull down
+    pub clipped_samples: u64,
+}
+
+pub fn telemetry() -> Telemetry {
+    Telemetry {
+        nan_samples: NAN_SAMPLES.load(Ordering::Relaxed),
+        clipped_samples: CLIPPED_SAMPLES.load(Ordering::Relaxed),
+    }
+}
+
+/// Frames the limiter's lookahead delays the output by
+pub fn latency(sample_rate: u32) -> usize {
+    ((LOOKAHEAD * sample_rate as Float) as usize).max(1)
+}
+
+pub fn reset_telemetry() {
+    NAN_SAMPLES.store(0, Ordering::Relaxed);
+    CLIPPED_SAMPLES.store(0, Ordering::Relaxed);
+}
+
+/// The last thing before the device: replaces NaN/Inf with silence, removes DC offset
+/// and runs a brickwall lookahead limiter, linked across channels so the image doesn't shift
+pub struct SafetyStage {
+    channels: usize,
+    sample_rate: u32,
+    // dc blocker state per channel
+    dc_in: Vec<Float>,
+    dc_out: Vec<Float>,
+    dc_coef: Float,
+    // interleaved frames waiting to go out, and the gain each one needs
+    delay: Vec<Float>,
+    needed_gain: Vec<Float>,
+    pos: usize,
+    gain: Float,
+    release_coef: Float,
+}
+
+impl SafetyStage {
+    pub fn new(channels: usize) -> Self {
+        Self {
+            channels,
+            sample_rate: 0,
+            dc_in: vec![],
+            dc_out: vec![],
+            dc_coef: 0.0,
+            delay: vec![],
+            needed_gain: vec![],
+            pos: 0,
+            gain: 1.0,
+            release_coef: 0.0,
+        }
+    }
+
+    fn reset(&mut self, sample_rate: u32) {
+        let rate = sample_rate as Float;
+        let lookahead = latency(sample_rate);
+        self.sample_rate = sample_rate;
+        self.dc_in = vec![0.0; self.channels];
+        self.dc_out = vec![0.0; self.channels];
+        self.dc_coef = 1.0 - std::f64::consts::TAU as Float * DC_CUTOFF / rate;
+        self.delay = vec![0.0; lookahead * self.channels];
+        self.needed_gain = vec![1.0; lookahead];
+        self.pos = 0;
+        self.gain = 1.0;
+        self.release_coef = 1.0 - (-1.0 / (RELEASE * rate)).exp();
+    }
+
+    /// Process interleaved frames in place
+    pub fn process(&mut self, frames: &mut [Float], sample_rate: u32) {
+        if !enabled() {
+            return;
+        }
+        if sample_rate != self.sample_rate {
+            self.reset(sample_rate);
+        }
+        let ceiling = (10.0 as Float).powf(ceiling_db() / 20.0);
+        let mut nans = 0;
+        let mut clipped = 0;
+
+        for frame in frames.chunks_exact_mut(self.channels) {
+            let mut peak: Float = 0.0;
+            for (c, x) in frame.iter_mut().enumerate() {
+                if !x.is_finite() {
+                    nans += 1;
+                    *x = 0.0;
+                }
+                let y = *x - self.dc_in[c] + self.dc_coef * self.dc_out[c];
+                self.dc_in[c] = *x;
+                self.dc_out[c] = y;
+                *x = y;
+                if y.abs() > ceiling {
+                    clipped += 1;
+                }
+                peak = peak.max(y.abs());
+            }
+
+            // swap the new frame into the delay line, and take out the one from a lookahead ago
+            let delayed = &mut self.delay[self.pos * self.channels..][..self.channels];
+            delayed.swap_with_slice(frame);
+
+            // every frame in the lookahead, starting with the one going out, needs the gain down
+            // by the time it leaves. Ramping there from unity as it comes in spreads the attack
+            // across the lookahead instead of dropping the gain in one step
+            let len = self.needed_gain.len();
+            let target = (0..len).fold(1.0, |target: Float, ahead| {
+                let needed = self.needed_gain[(self.pos + ahead) % len];
+                target.min(needed + (1.0 - needed) * ahead as Float / len as Float)
+            });
+            self.needed_gain[self.pos] = if peak > ceiling { ceiling / peak } else { 1.0 };
+            self.pos = (self.pos + 1) % len;
+
+            // and recovers slowly
+            self.gain = if target < self.gain {
+                target
+            } else {
+                self.gain + (target - self.gain) * self.release_coef
+            };
+            for x in frame.iter_mut() {
+                *x = (*x * self.gain).clamp(-ceiling, ceiling);
+            }
+        }
+
+        if nans > 0 {
+            NAN_SAMPLES.fetch_add(nans, Ordering::Relaxed);
+        }
+        if clipped > 0 {
+            CLIPPED_SAMPLES.fetch_add(clipped, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ceiling_db, telemetry, SafetyStage};
+    use crate::{math::sin, sound::Float};
+
+    const RATE: u32 = 48_000;
+
+    fn run(stage: &mut SafetyStage, sound_fn: impl Fn(Float) -> Float) -> Vec<Float> {
+        let mut frames: Vec<_> = (0..RATE)
+            .flat_map(|i| {
+                let x = sound_fn(i as Float / RATE as Float);
+                [x, x]
+            })
+            .collect();
+        stage.process(&mut frames, RATE);
+        frames
+    }
+
+    #[test]
+    fn test_nan_and_dc() {
+        let before = telemetry();
+        let out = run(&mut SafetyStage::new(2), |t| {
+            if t < 0.1 {
+                Float::NAN
+            } else {
+                0.5
+            }
+        });
+        assert!(out.iter().all(|x| x.is_finite()));
+        assert!(telemetry().nan_samples - before.nan_samples >= 2 * 4800);
+        // the offset has decayed away by the end
+        assert!(out.last().unwrap().abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_limiter() {
+        let before = telemetry();
+        let ceiling = (10.0 as Float).powf(ceiling_db() / 20.0);
+        let out = run(&mut SafetyStage::new(2), |t| sin(440.0 * t) * 4.0);
+        assert!(out.iter().all(|x| x.abs() <= ceiling));
+        assert!(telemetry().clipped_samples > before.clipped_samples);
+
+        // quiet signals pass through, just delayed by the lookahead
+        let quiet = run(&mut SafetyStage::new(2), |t| sin(440.0 * t) * 0.5);
+        let peak = quiet[RATE as usize..]
+            .iter()
+            .fold(0.0, |a: Float, b| a.max(b.abs()));
+        assert!((peak - 0.5).abs() < 0.01, "{peak}");
+    }
+
+    #[test]
+    fn test_limiter_attack() {
+        let ceiling = (10.0 as Float).powf(ceiling_db() / 20.0);
+        let mut stage = SafetyStage::new(1);
+        // silence, then a loud burst the gain has to be down for when it comes out
+        let mut gains = vec![];
+        let mut out = vec![];
+        for i in 0..RATE / 10 {
+            let mut frame = [if i < RATE / 20 { 0.0 } else { 4.0 }];
+            stage.process(&mut frame, RATE);
+            gains.push(stage.gain);
+            out.push(frame[0]);
+        }
+        let steepest = gains.windows(2).map(|w| w[0] - w[1]).fold(0.0, Float::max);
+        assert!(steepest < 0.02, "{steepest}");
+        // the first sample of the burst already gets the full reduction
+        let first = out.iter().position(|x| *x != 0.0).unwrap();
+        assert!((out[first] - ceiling).abs() < 1e-9, "{}", out[first]);
+    }
+}
@@ -0,0 +1,290 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::Context;
+use arc_swap::ArcSwap;
+use bevy::log::{info, warn};
+use once_cell::sync::Lazy;
+
+use super::{sample_rate, Float, FloatOut};
+
+/// Loaded at startup if it exists
+pub const DEFAULT_SAMPLE_DIR: &str = "samples";
+// what the decoders built into web-audio-api can read, it is built without mp3
+const EXTENSIONS: [&str; 3] = ["wav", "flac", "ogg"];
+
+// Swapped as a whole when samples are loaded, so the audio thread never waits on a lock
+static SAMPLE_BANK: Lazy<ArcSwap<SampleBank>> = Lazy::new(Default::default);
+
+pub struct Sample {
+    /// One buffer per channel
+    channels: Vec<Vec<FloatOut>>,
+    rate: u32,
+}
+
+impl Sample {
+    pub fn new(channels: Vec<Vec<FloatOut>>, rate: u32) -> Self {
+        assert!(!channels.is_empty(), "a sample needs at least one channel");
+        Self { channels, rate }
+    }
+
+    pub fn len(&self) -> usize {
+        self.channels[0].len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn duration(&self) -> Float {
+        self.len() as Float / self.rate as Float
+    }
+
+    /// Linearly interpolated read at a fractional frame position
+    fn read(&self, channel: usize, pos: Float) -> Float {
+        let data = &self.channels[channel.min(self.channels.len() - 1)];
+        let i = pos as usize;
+        let frac = pos.fract();
+        let a = data.get(i).copied().unwrap_or(0.0) as Float;
+        let b = data.get(i + 1).copied().unwrap_or(0.0) as Float;
+        a * (1.0 - frac) + b * frac
+    }
+
+    /// `t` is the time in seconds since the sample was triggered
+    pub fn play(&self, t: Float, opts: &SampleOpts) -> [Float; 2] {
+        let len = self.len() as Float;
+        let start = opts.start.clamp(0.0, 1.0) * len;
+        let end = opts.end.clamp(0.0, 1.0) * len;
+        let region = end - start;
+        if t < 0.0 || region <= 0.0 {
+            return [0.0, 0.0];
+        }
+
+        let mut offset = t * opts.speed * self.rate as Float;
+        if opts.looping {
+            offset %= region;
+        } else if offset >= region {
+            return [0.0, 0.0];
+        }
+        let pos = if opts.reverse {
+            end - 1.0 - offset
+        } else {
+            start + offset
+        };
+        if pos < 0.0 {
+            return [0.0, 0.0];
+        }
+
+        [self.read(0, pos), self.read(1, pos)]
+    }
+}
+
+/// Playback options for [`sample_with`]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SampleOpts {
+    /// Playback speed, 2.0 is an octave up
+    pub speed: Float,
+    /// Fraction of the sample to start playing from
+    pub start: Float,
+    /// Fraction of the sample to stop playing at
+    pub end: Float,
+    pub looping: bool,
+    pub reverse: bool,
+}
+
+impl Default for SampleOpts {
+    fn default() -> Self {
+        Self {
+            speed: 1.0,
+            start: 0.0,
+            end: 1.0,
+            looping: false,
+            reverse: false,
+        }
+    }
+}
+
+impl SampleOpts {
+    pub fn speed(self, speed: Float) -> Self {
+        Self { speed, ..self }
+    }
+
+    /// Pitch shift in semitones, by changing the speed
+    pub fn pitch(self, semitones: Float) -> Self {
+        self.speed((2.0 as Float).powf(semitones / 12.0))
+    }
+
+    pub fn region(self, start: Float, end: Float) -> Self {
+        Self { start, end, ..self }
+    }
+
+    pub fn looping(self) -> Self {
+        Self {
+            looping: true,
+            ..self
+        }
+    }
+
+    pub fn reverse(self) -> Self {
+        Self {
+            reverse: true,
+            ..self
+        }
+    }
+}
+
+#[derive(Default, Clone)]
+pub struct SampleBank {
+    samples: HashMap<String, Arc<Sample>>,
+}
+
+impl SampleBank {
+    pub fn get(&self, name: &str) -> Option<&Arc<Sample>> {
+        self.samples.get(name)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.samples.keys().map(String::as_str)
+    }
+}
+
+pub fn sample_bank() -> Arc<SampleBank> {
+    SAMPLE_BANK.load_full()
+}
+
+/// Add (or replace) samples in the shared bank
+pub fn insert_samples(samples: impl IntoIterator<Item = (String, Sample)>) {
+    let samples: Vec<_> = samples
+        .into_iter()
+        .map(|(name, sample)| (name, Arc::new(sample)))
+        .collect();
+    SAMPLE_BANK.rcu(|bank| {
+        let mut bank = SampleBank::clone(bank);
+        bank.samples.extend(samples.iter().cloned());
+        bank
+    });
+}
+
+/// Play a loaded sample, `t` is the time in seconds since it was triggered.
+/// Mixed down to mono so it fits in with the other Callables, e.g.
+/// `seq![|t| sample("kick", t), |t| sample("snare", t)](t)`. Unknown samples are silent
+pub fn sample(name: &str, t: Float) -> Float {
+    let [l, r] = sample_with(name, t, &SampleOpts::default());
+    (l + r) * 0.5
+}
+
+/// Stereo playback with options, e.g. `sample_with("pad", t, &SampleOpts::default().pitch(7.0).looping())`
+pub fn sample_with(name: &str, t: Float, opts: &SampleOpts) -> [Float; 2] {
+    match SAMPLE_BANK.load().get(name) {
+        Some(sample) => sample.play(t, opts),
+        None => [0.0, 0.0],
+    }
+}
+
+/// Decode a file, resampled to the current engine rate
+pub fn load_file(path: impl AsRef<Path>) -> anyhow::Result<Sample> {
+    let path = path.as_ref();
+    let file = std::fs::File::open(path).with_context(|| format!("opening {path:?}"))?;
+    decode(file, sample_rate()).with_context(|| format!("decoding {path:?}"))
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(target_arch = "wasm32")] {
+        fn decode(_file: std::fs::File, _rate: u32) -> anyhow::Result<Sample> {
+            // todo_major: decode through the browser's AudioContext
+            anyhow::bail!("sample loading is not supported on the web yet")
+        }
+    } else {
+        fn decode(file: std::fs::File, rate: u32) -> anyhow::Result<Sample> {
+            use web_audio_api::context::{BaseAudioContext, OfflineAudioContext};
+
+            // an offline context decodes and resamples without needing a device
+            let ctx = OfflineAudioContext::new(1, 1, rate as f32);
+            let buffer = ctx
+                .decode_audio_data_sync(file)
+                .map_err(|e| anyhow::anyhow!("{e}"))?;
+            let channels = (0..buffer.number_of_channels())
+                .map(|c| buffer.get_channel_data(c).to_vec())
+                .collect();
+            Ok(Sample::new(channels, buffer.sample_rate() as u32))
+        }
+    }
+}
+
+/// Load every audio file in a directory, named by file stem, on a background thread
+pub fn load_dir(dir: impl Into<PathBuf>) {
+    let dir = dir.into();
+    std::thread::spawn(move || {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) => return warn!("Couldn't read sample dir {dir:?}: {e}"),
+        };
+        let samples: Vec<_> = entries
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|path| {
+                path.extension()
+                    .and_then(|e| e.to_str())
+                    .is_some_and(|e| EXTENSIONS.contains(&e.to_lowercase().as_str()))
+            })
+            .filter_map(|path| {
+                let name = path.file_stem()?.to_string_lossy().into_owned();
+                match load_file(&path) {
+                    Ok(sample) => Some((name, sample)),
+                    Err(e) => {
+                        warn!("{e:#}");
+                        None
+                    }
+                }
+            })
+            .collect();
+        info!("Loaded {} samples from {dir:?}", samples.len());
+        insert_samples(samples);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load_file, Sample, SampleOpts};
+    use crate::sound::{offline::write_wav, sample_rate};
+
+    fn ramp() -> Sample {
+        // 10 frames at 10Hz, so one second long
+        Sample::new(vec![(0..10).map(|i| i as f32).collect()], 10)
+    }
+
+    #[test]
+    fn test_sample_playback() {
+        let s = ramp();
+        let opts = SampleOpts::default();
+        assert_eq!(s.play(0.0, &opts), [0.0, 0.0]);
+        assert_eq!(s.play(0.5, &opts), [5.0, 5.0]);
+        assert_eq!(s.play(0.25, &opts), [2.5, 2.5]);
+        assert_eq!(s.play(1.5, &opts), [0.0, 0.0]);
+        assert_eq!(s.play(-0.1, &opts), [0.0, 0.0]);
+
+        assert_eq!(s.play(0.2, &opts.speed(2.0)), [4.0, 4.0]);
+        assert_eq!(s.play(0.5, &opts.pitch(12.0)), [0.0, 0.0]);
+        assert_eq!(s.play(0.0, &opts.reverse()), [9.0, 9.0]);
+        assert_eq!(s.play(0.1, &opts.region(0.5, 1.0)), [6.0, 6.0]);
+        assert_eq!(s.play(0.6, &opts.region(0.5, 1.0)), [0.0, 0.0]);
+        assert_eq!(s.play(0.6, &opts.region(0.5, 1.0).looping()), [6.0, 6.0]);
+        assert_eq!(s.play(1.2, &opts.looping().reverse()), [7.0, 7.0]);
+    }
+
+    #[test]
+    fn test_load_resamples() {
+        let dir = crate::test_dir("load");
+        let path = dir.join("load.wav");
+        write_wav(&path, &vec![[0.5, -0.5]; 44_100], 44_100).unwrap();
+        let sample = load_file(&path).unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+
+        assert_eq!(sample.rate, sample_rate());
+        assert!((sample.duration() - 1.0).abs() < 0.01);
+        let [l, r] = sample.play(0.5, &SampleOpts::default());
+        assert!((l - 0.5).abs() < 1e-3 && (r + 0.5).abs() < 1e-3);
+    }
+}
@@ -0,0 +1,471 @@
+use std::sync::{
+    atomic::{fence, AtomicU32, AtomicU64, Ordering},
+    Arc, Mutex,
+};
+
+use super::Float;
+
+pub const VOICE_PARAMS: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Adsr {
+    pub attack: Float,
+    pub decay: Float,
+    pub sustain: Float,
+    pub release: Float,
+}
+
+impl Default for Adsr {
+    fn default() -> Self {
+        Self {
+            attack: 0.01,
+            decay: 0.1,
+            sustain: 0.7,
+            release: 0.3,
+        }
+    }
+}
+
+impl Adsr {
+    fn held_level(&self, since_on: Float) -> Float {
+        if since_on < 0.0 {
+            0.0
+        } else if since_on < self.attack {
+            since_on / self.attack
+        } else if since_on < self.attack + self.decay {
+            let d = (since_on - self.attack) / self.decay;
+            1.0 - d * (1.0 - self.sustain)
+        } else {
+            self.sustain
+        }
+    }
+
+    /// Envelope level at `t` for a note pressed at `on` and released at `off`
+    pub fn level(&self, on: Float, off: Option<Float>, t: Float) -> Float {
+        match off {
+            Some(off) if t >= off => {
+                let since_off = t - off;
+                if since_off >= self.release {
+                    0.0
+                } else {
+                    self.held_level(off - on) * (1.0 - since_off / self.release)
+                }
+            }
+            _ => self.held_level(t - on),
+        }
+    }
+}
+
+/// Which voice to take over when a note comes in and every voice is busy
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum StealPolicy {
+    #[default]
+    Oldest,
+    Quietest,
+    /// Drop the new note instead
+    None,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Voice {
+    pub note: u8,
+    pub velocity: Float,
+    /// Time the note was pressed
+    pub on: Float,
+    /// Time the note was released
+    pub off: Option<Float>,
+    pub params: [Float; VOICE_PARAMS],
+    pub adsr: Adsr,
+}
+
+impl Voice {
+    /// Equal temperament, A4 (note 69) is 440Hz
+    pub fn freq(&self) -> Float {
+        440.0 * (2.0 as Float).powf((self.note as Float - 69.0) / 12.0)
+    }
+
+    /// Envelope level times velocity
+    pub fn env(&self, t: Float) -> Float {
+        self.adsr.level(self.on, self.off, t) * self.velocity
+    }
+
+    /// Time since the note was pressed, handy as the phase for oscillators
+    pub fn since_on(&self, t: Float) -> Float {
+        t - self.on
+    }
+
+    fn sounding(&self, t: Float) -> bool {
+        t >= self.on && self.off.is_none_or(|off| t - off < self.adsr.release)
+    }
+}
+
+const NONE_BITS: u64 = u64::MAX;
+
+// One voice, written by the control side and read by the audio thread as a seqlock with two
+// copies. The writer updates one copy and then the other, so while it is part way through one the
+// other still holds the whole of the previous voice. The audio thread never waits on it: a read
+// that overlaps a write falls back to the copy the writer isn't on
+#[derive(Default)]
+struct Slot {
+    seq: AtomicU32,
+    copies: [SlotCopy; 2],
+}
+
+#[derive(Default)]
+struct SlotCopy {
+    note: AtomicU32,
+    velocity: AtomicU64,
+    on: AtomicU64,
+    off: AtomicU64,
+    params: [AtomicU64; VOICE_PARAMS],
+    // attack, decay, sustain, release
+    adsr: [AtomicU64; 4],
+}
+
+impl Slot {
+    // only ever called with the pool's voices locked, so there's one writer at a time
+    fn write(&self, voice: &Voice) {
+        let seq = self.seq.load(Ordering::Relaxed);
+        // readers go to the second copy while the first is written, then back
+        for (i, copy) in self.copies.iter().enumerate() {
+            self.seq
+                .store(seq.wrapping_add(i as u32 + 1), Ordering::Release);
+            fence(Ordering::Release);
+            copy.write(voice);
+        }
+    }
+
+    fn read(&self) -> Voice {
+        let seq = self.seq.load(Ordering::Acquire);
+        let voice = self.copies[seq as usize % 2].read();
+        fence(Ordering::Acquire);
+        match self.seq.load(Ordering::Acquire) {
+            now if now == seq => voice,
+            now => self.copies[now as usize % 2].read(),
+        }
+    }
+}
+
+impl SlotCopy {
+    fn write(&self, voice: &Voice) {
+        self.note.store(voice.note as u32, Ordering::Relaxed);
+        self.velocity
+            .store(voice.velocity.to_bits(), Ordering::Relaxed);
+        self.on.store(voice.on.to_bits(), Ordering::Relaxed);
+        self.off.store(
+            voice.off.map_or(NONE_BITS, Float::to_bits),
+            Ordering::Relaxed,
+        );
+        for (param, value) in self.params.iter().zip(voice.params) {
+            param.store(value.to_bits(), Ordering::Relaxed);
+        }
+        let Adsr {
+            attack,
+            decay,
+            sustain,
+            release,
+        } = voice.adsr;
+        for (stage, value) in self.adsr.iter().zip([attack, decay, sustain, release]) {
+            stage.store(value.to_bits(), Ordering::Relaxed);
+        }
+    }
+
+    fn read(&self) -> Voice {
+        let off = self.off.load(Ordering::Relaxed);
+        Voice {
+            note: self.note.load(Ordering::Relaxed) as u8,
+            velocity: Float::from_bits(self.velocity.load(Ordering::Relaxed)),
+            on: Float::from_bits(self.on.load(Ordering::Relaxed)),
+            off: (off != NONE_BITS).then(|| Float::from_bits(off)),
+            params: std::array::from_fn(|i| {
+                Float::from_bits(self.params[i].load(Ordering::Relaxed))
+            }),
+            adsr: {
+                let [attack, decay, sustain, release] =
+                    std::array::from_fn(|i| Float::from_bits(self.adsr[i].load(Ordering::Relaxed)));
+                Adsr {
+                    attack,
+                    decay,
+                    sustain,
+                    release,
+                }
+            },
+        }
+    }
+}
+
+struct Pool {
+    slots: Vec<Slot>,
+    // the writers' own copy of the voices, so allocation never reads back from the slots
+    voices: Mutex<Vec<Option<Voice>>>,
+    // for notes that don't bring their own envelope
+    adsr: Adsr,
+    policy: StealPolicy,
+}
+
+/// A fixed number of voices running the same instrument, e.g.
+/// ```ignore
+/// let voices = Voices::new(8, Adsr::default(), StealPolicy::Oldest);
+/// let v = voices.clone();
+/// sound.push_soundfn(Box::new(move |t| v.render(t, |voice, t| {
+///     let x = sin(voice.freq() * voice.since_on(t)) * voice.env(t);
+///     [x, x]
+/// })));
+/// voices.note_on(60, 1.0, sound.time());
+/// ```
+/// Notes are allocated when they come in, rendering only reads, so it's safe on the audio thread
+#[derive(Clone)]
+pub struct Voices(Arc<Pool>);
+
+impl Voices {
+    /// `adsr` is the envelope for notes that don't have their own, see [`Self::note_on_with`]
+    pub fn new(count: usize, adsr: Adsr, policy: StealPolicy) -> Self {
+        Self(Arc::new(Pool {
+            slots: (0..count).map(|_| Slot::default()).collect(),
+            voices: Mutex::new(vec![None; count]),
+            adsr,
+            policy,
+        }))
+    }
+
+    /// Returns the voice index the note was given, or None if it was dropped
+    pub fn note_on(&self, note: u8, velocity: Float, time: Float) -> Option<usize> {
+        self.note_on_with(note, velocity, [0.0; VOICE_PARAMS], self.0.adsr, time)
+    }
+
+    /// Like [`Self::note_on`] with the voice's own parameters and envelope
+    pub fn note_on_with(
+        &self,
+        note: u8,
+        velocity: Float,
+        params: [Float; VOICE_PARAMS],
+        adsr: Adsr,
+        time: Float,
+    ) -> Option<usize> {
+        let pool = &self.0;
+        let mut voices = pool.voices.lock().unwrap();
+
+        // retrigger the same note, then a free voice, then steal
+        let index = voices
+            .iter()
+            .position(|v| v.is_some_and(|v| v.note == note && v.off.is_none()))
+            .or_else(|| {
+                voices
+                    .iter()
+                    .position(|v| v.is_none_or(|v| !v.sounding(time) && v.on <= time))
+            })
+            .or_else(|| {
+                let busy = voices
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(i, v)| Some((i, (*v)?)));
+                match pool.policy {
+                    StealPolicy::Oldest => busy
+                        .min_by(|(_, a), (_, b)| a.on.total_cmp(&b.on))
+                        .map(|(i, _)| i),
+                    StealPolicy::Quietest => busy
+                        .min_by(|(_, a), (_, b)| a.env(time).total_cmp(&b.env(time)))
+                        .map(|(i, _)| i),
+                    StealPolicy::None => None,
+                }
+            })?;
+
+        let voice = Voice {
+            note,
+            velocity,
+            on: time,
+            off: None,
+            params,
+            adsr,
+        };
+        voices[index] = Some(voice);
+        pool.slots[index].write(&voice);
+        Some(index)
+    }
+
+    pub fn note_off(&self, note: u8, time: Float) {
+        let pool = &self.0;
+        let mut voices = pool.voices.lock().unwrap();
+        for (index, voice) in voices.iter_mut().enumerate() {
+            if let Some(voice) = voice.as_mut().filter(|v| v.note == note && v.off.is_none()) {
+                voice.off = Some(time.max(voice.on));
+                pool.slots[index].write(voice);
+            }
+        }
+    }
+
+    /// Change a parameter of every held voice playing `note`
+    pub fn set_param(&self, note: u8, param: usize, value: Float) {
+        let pool = &self.0;
+        let mut voices = pool.voices.lock().unwrap();
+        for (index, voice) in voices.iter_mut().enumerate() {
+            if let Some(voice) = voice.as_mut().filter(|v| v.note == note && v.off.is_none()) {
+                voice.params[param] = value;
+                pool.slots[index].write(voice);
+            }
+        }
+    }
+
+    /// Change the envelope of every held voice playing `note`, from the start of the note
+    pub fn set_adsr(&self, note: u8, adsr: Adsr) {
+        let pool = &self.0;
+        let mut voices = pool.voices.lock().unwrap();
+        for (index, voice) in voices.iter_mut().enumerate() {
+            if let Some(voice) = voice.as_mut().filter(|v| v.note == note && v.off.is_none()) {
+                voice.adsr = adsr;
+                pool.slots[index].write(voice);
+            }
+        }
+    }
+
+    /// Release every voice
+    pub fn all_off(&self, time: Float) {
+        let pool = &self.0;
+        let mut voices = pool.voices.lock().unwrap();
+        for (index, voice) in voices.iter_mut().enumerate() {
+            if let Some(voice) = voice.as_mut().filter(|v| v.off.is_none()) {
+                voice.off = Some(time.max(voice.on));
+                pool.slots[index].write(voice);
+            }
+        }
+    }
+
+    /// Notes currently held down
+    pub fn held(&self) -> Vec<u8> {
+        let voices = self.0.voices.lock().unwrap();
+        voices
+            .iter()
+            .flatten()
+            .filter(|v| v.off.is_none())
+            .map(|v| v.note)
+            .collect()
+    }
+
+    /// Sum of the instrument over every sounding voice
+    pub fn render(&self, t: Float, instrument: impl Fn(&Voice, Float) -> [Float; 2]) -> [Float; 2] {
+        let pool = &self.0;
+        pool.slots
+            .iter()
+            .filter(|slot| slot.seq.load(Ordering::Relaxed) != 0)
+            .map(Slot::read)
+            .filter(|voice| voice.sounding(t))
+            .fold([0.0, 0.0], |[l, r], voice| {
+                let [vl, vr] = instrument(&voice, t);
+                [l + vl, r + vr]
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use super::{Adsr, Slot, StealPolicy, Voice, Voices, VOICE_PARAMS};
+
+    fn adsr() -> Adsr {
+        Adsr {
+            attack: 0.0,
+            decay: 1.0,
+            sustain: 0.0,
+            release: 0.5,
+        }
+    }
+
+    #[test]
+    fn test_adsr() {
+        let adsr = Adsr {
+            attack: 1.0,
+            decay: 1.0,
+            sustain: 0.5,
+            release: 1.0,
+        };
+        assert_eq!(adsr.level(0.0, None, 0.5), 0.5);
+        assert_eq!(adsr.level(0.0, None, 1.5), 0.75);
+        assert_eq!(adsr.level(0.0, None, 10.0), 0.5);
+        assert_eq!(adsr.level(0.0, Some(10.0), 10.5), 0.25);
+        assert_eq!(adsr.level(0.0, Some(10.0), 11.0), 0.0);
+    }
+
+    #[test]
+    fn test_allocation() {
+        let voices = Voices::new(2, adsr(), StealPolicy::Oldest);
+        assert_eq!(voices.note_on(60, 1.0, 0.0), Some(0));
+        assert_eq!(voices.note_on(64, 1.0, 0.1), Some(1));
+        // retrigger keeps the same voice
+        assert_eq!(voices.note_on(64, 1.0, 0.2), Some(1));
+        // full, steal the oldest
+        assert_eq!(voices.note_on(67, 1.0, 0.3), Some(0));
+        assert_eq!(voices.held(), vec![67, 64]);
+
+        // a released voice is free once its release has finished
+        voices.note_off(67, 0.4);
+        assert_eq!(voices.note_on(72, 1.0, 0.5), Some(1));
+        assert_eq!(voices.note_on(74, 1.0, 1.0), Some(0));
+
+        let none = Voices::new(1, adsr(), StealPolicy::None);
+        assert_eq!(none.note_on(60, 1.0, 0.0), Some(0));
+        assert_eq!(none.note_on(62, 1.0, 0.0), None);
+    }
+
+    #[test]
+    fn test_steal_quietest() {
+        let voices = Voices::new(2, adsr(), StealPolicy::Quietest);
+        voices.note_on(60, 1.0, 0.0);
+        voices.note_on(64, 1.0, 0.5);
+        // 60 has decayed further than 64
+        assert_eq!(voices.note_on(67, 1.0, 0.6), Some(0));
+
+        // a soft note is quieter than a loud one that has decayed further
+        let voices = Voices::new(2, adsr(), StealPolicy::Quietest);
+        voices.note_on(60, 1.0, 0.0);
+        voices.note_on(64, 0.1, 0.5);
+        assert_eq!(voices.note_on(67, 1.0, 0.6), Some(1));
+    }
+
+    #[test]
+    fn test_voice_envelopes() {
+        let voices = Voices::new(2, adsr(), StealPolicy::Oldest);
+        let pad = Adsr {
+            attack: 1.0,
+            decay: 0.0,
+            sustain: 1.0,
+            release: 1.0,
+        };
+        voices.note_on(60, 1.0, 0.0);
+        voices.note_on_with(64, 1.0, [0.0; VOICE_PARAMS], pad, 0.0);
+        // each note on its own side
+        let levels = |t| {
+            voices.render(t, |voice, t| match voice.note {
+                60 => [voice.env(t), 0.0],
+                _ => [0.0, voice.env(t)],
+            })
+        };
+        assert_eq!(levels(0.5), [0.5, 0.5]);
+        assert_eq!(levels(1.0), [0.0, 1.0]);
+
+        voices.set_adsr(64, adsr());
+        assert_eq!(levels(0.5), [0.5, 0.5]);
+        assert_eq!(levels(1.0), [0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_slot_mid_write() {
+        let voice = |note| Voice {
+            note,
+            velocity: 1.0,
+            on: 0.0,
+            off: None,
+            params: [0.0; VOICE_PARAMS],
+            adsr: adsr(),
+        };
+        let slot = Slot::default();
+        slot.write(&voice(60));
+        assert_eq!(slot.read(), voice(60));
+        // a writer stopped part way through the first copy leaves the previous voice readable
+        slot.seq.fetch_add(1, Ordering::Relaxed);
+        slot.copies[0].write(&voice(61));
+        assert_eq!(slot.read(), voice(60));
+        slot.seq.fetch_add(1, Ordering::Relaxed);
+        assert_eq!(slot.read(), voice(61));
+    }
+}
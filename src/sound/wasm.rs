@@ -1,14 +1,31 @@
 pub struct SoundResources {
     ctx: Option<AudioContext>,
+    pub latency: Latency,
+    pub channels: usize,
+    pub max_channels: usize,
 }
 
-impl Default for SoundResources {
-    fn default() -> Self {
+impl SoundResources {
+    // todo_major: the browser picks the output device and latency for us
+    pub fn new(settings: &OutputSettings) -> Self {
         let ctx = get_ctx();
-        Self { ctx }
+        Self {
+            ctx,
+            latency: settings.latency,
+            channels: 2,
+            max_channels: 2,
+        }
+    }
+
+    pub fn output_latency(&self) -> Float {
+        0.0
     }
 }
 
+pub fn output_devices() -> Vec<OutputDevice> {
+    vec![]
+}
+
 fn get_ctx() -> Option<AudioContext> {
     // todo_major: actually return the audio context
     spawn_local(web_main());
@@ -17,11 +34,11 @@ fn get_ctx() -> Option<AudioContext> {
 
 // editted from the wasm_bindgen audio worklet example: https://github.com/rustwasm/wasm-bindgen/tree/c5b073ae58cb3b6d44252108ea9862bf0d04f3b6/examples/wasm-audio-worklet
 
-use super::{empty_sound_fn, SoundFn};
+use super::mixer::{Mixer, MAX_BLOCK};
+use super::{Float, Latency, OutputDevice, OutputSettings};
 use itertools::izip;
 use js_sys::Array;
 use js_sys::JsString;
-use std::sync::Arc;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsValue;
 use wasm_bindgen_futures::spawn_local;
@@ -29,7 +46,7 @@ use wasm_bindgen_futures::JsFuture;
 use web_sys::AudioContextOptions;
 use web_sys::{AudioContext, AudioWorkletNode, AudioWorkletNodeOptions};
 
-use crate::CURRENT_SOUND_FN;
+use super::CURRENT_MIX;
 use crate::SAMPLE_INDEX;
 
 // todo_cleanup
@@ -56,32 +73,78 @@ impl WasmAudioProcessor {
 
 pub async fn wasm_audio() -> Result<AudioContext, JsValue> {
     let mut options = AudioContextOptions::new();
-    options.sample_rate(SAMPLE_RATE as f32);
+    options.sample_rate(sample_rate() as f32);
     let ctx = AudioContext::new_with_context_options(&options)?;
+    // the browser may not give us the rate we asked for
+    let rate = ctx.sample_rate() as u32;
+    set_sample_rate(rate);
     prepare_wasm_audio(&ctx).await?;
-    let process = make_process_function();
+    let process = make_process_function(rate);
     let node = wasm_audio_node(&ctx, process)?;
     node.connect_with_audio_node(&ctx.destination()).unwrap();
     Ok(ctx)
 }
 
-fn make_process_function() -> Box<dyn FnMut(&mut [f32], &mut [f32]) -> bool> {
-    let mut idx: usize = SAMPLE_INDEX.load(std::sync::atomic::Ordering::Relaxed);
-    let mut sound_fn: Arc<SoundFn> = Arc::new(empty_sound_fn());
+// the rate is fixed for the life of the context, so it's only read once
+fn make_process_function(rate: u32) -> Box<dyn FnMut(&mut [f32], &mut [f32]) -> bool> {
+    let dt = 1.0 / rate as Float;
+    // worklets can't spawn threads, so every track renders here
+    let mut mixer = Mixer::realtime(0);
+    let mut safety = super::safety::SafetyStage::new(2);
+    let mut scheduler = super::events::Scheduler::default();
+    let mut clock = super::clock::AudioClock::default();
+    let mut frames: Vec<Float> = vec![0.0; MAX_BLOCK * 2];
     Box::new(move |buf0: &mut [f32], buf1: &mut [f32]| {
-        if let Ok(current_sound_fn) = CURRENT_SOUND_FN.try_lock() {
-            sound_fn = current_sound_fn.clone();
+        let block_start = super::load::start_block();
+        let mix = CURRENT_MIX.load_full();
+
+        let idx: usize = SAMPLE_INDEX.load(std::sync::atomic::Ordering::Relaxed);
+
+        // all of it in one go, unless the transport stops or jumps part way through
+        let segments = scheduler.begin_block(idx, buf0.len(), rate);
+        clock.tick(
+            segments.first().map(|segment| segment.start),
+            block_start,
+            rate,
+        );
+        if segments.is_empty() {
+            buf0.fill(0.0);
+            buf1.fill(0.0);
+            // a seek moves the counter even while stopped
+            SAMPLE_INDEX.store(
+                scheduler.next_sample(),
+                std::sync::atomic::Ordering::Relaxed,
+            );
+            return true;
         }
 
-        let mut idx: usize = SAMPLE_INDEX.load(std::sync::atomic::Ordering::Relaxed);
-
-        izip!(buf0.iter_mut(), buf1.iter_mut())
-            .enumerate()
-            .for_each(|(i, (f0, f1))| {
-                let t = (idx + i) as f32 / SAMPLE_RATE as f32;
-                [*f0, *f1] = sound_fn(t);
-            });
-        SAMPLE_INDEX.store(idx + buf0.len(), std::sync::atomic::Ordering::Relaxed);
+        // todo_major: the worklet only hands us two channels
+        frames.resize(buf0.len() * 2, 0.0);
+        frames.fill(0.0);
+        let mut offset = 0;
+        for segment in segments {
+            let end = offset + segment.frames;
+            mixer.render(
+                &mix,
+                &mut frames[offset * 2..end * 2],
+                2,
+                segment.start as Float * dt,
+                dt,
+            );
+            offset = end;
+        }
+        safety.process(&mut frames, rate);
+        izip!(buf0.iter_mut(), buf1.iter_mut(), frames.chunks_exact(2)).for_each(
+            |(f0, f1, frame)| {
+                *f0 = frame[0] as f32;
+                *f1 = frame[1] as f32;
+            },
+        );
+        SAMPLE_INDEX.store(
+            scheduler.next_sample(),
+            std::sync::atomic::Ordering::Relaxed,
+        );
+        super::load::end_block(block_start, buf0.len(), rate);
         true
     })
 }
@@ -112,7 +175,7 @@ pub async fn prepare_wasm_audio(ctx: &AudioContext) -> Result<(), JsValue> {
 
 use web_sys::{Blob, BlobPropertyBag, Url};
 
-use super::SAMPLE_RATE;
+use super::{sample_rate, set_sample_rate};
 
 #[wasm_bindgen]
 extern "C" {
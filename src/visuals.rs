@@ -3,16 +3,16 @@ use std::collections::VecDeque;
 use bevy::{
     app::{FixedUpdate, PostUpdate},
     prelude::{Plugin, Res, ResMut, Resource},
-    time::Time,
 };
 use bevy_egui::{
     egui::{self, emath, epaint, Color32, Pos2, Rect, Stroke},
     EguiContexts,
 };
+use serde::{Deserialize, Serialize};
 
 use crate::{
     fft::{fft, FreqMag, FFT_BUFFER_SIZE},
-    sound::{Float, FloatOut, SoundControl, INV_SAMPLE_RATE, SAMPLE_RATE},
+    sound::{sample_rate, Float, FloatOut, SoundControl},
 };
 
 pub struct VisualsPlugin;
@@ -26,13 +26,15 @@ impl Plugin for VisualsPlugin {
     }
 }
 
-#[derive(Resource)]
+#[derive(Resource, Default)]
 struct VisualData {
     wave_history: VecDeque<Vec<FloatOut>>,
     fft_data: Vec<FreqMag>,
+    fft_sample_rate: u32,
 }
 
-#[derive(Resource)]
+#[derive(Resource, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct VisualsControls {
     pub wave_inv_time_scale: Float,
     pub wave_fade_off: f32,
@@ -44,6 +46,96 @@ pub struct VisualsControls {
     pub wave_samples: usize,
 }
 
+/// Names a [`VisualsControls`] field, so it can be driven from outside the UI (e.g. MIDI learn)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum VisualsField {
+    WaveInvTimeScale,
+    WaveFadeOff,
+    WaveHeightScale,
+    WaveLineWidth,
+    FftLineWidth,
+    WaveTimeRounding,
+    WaveHistoryLen,
+    WaveSamples,
+}
+
+impl VisualsField {
+    pub const ALL: [VisualsField; 8] = [
+        VisualsField::WaveInvTimeScale,
+        VisualsField::WaveFadeOff,
+        VisualsField::WaveHeightScale,
+        VisualsField::WaveLineWidth,
+        VisualsField::FftLineWidth,
+        VisualsField::WaveTimeRounding,
+        VisualsField::WaveHistoryLen,
+        VisualsField::WaveSamples,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            VisualsField::WaveInvTimeScale => "wave_inv_time_scale",
+            VisualsField::WaveFadeOff => "wave_fade_off",
+            VisualsField::WaveHeightScale => "wave_height_scale",
+            VisualsField::WaveLineWidth => "wave_line_width",
+            VisualsField::FftLineWidth => "fft_line_width",
+            VisualsField::WaveTimeRounding => "wave_time_rounding",
+            VisualsField::WaveHistoryLen => "wave_history_len",
+            VisualsField::WaveSamples => "wave_samples",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|field| field.name() == name)
+    }
+
+    /// Sensible range for controllers that only give us 0 to 1
+    pub fn range(&self) -> (f64, f64) {
+        match self {
+            VisualsField::WaveInvTimeScale => (1.0, 1000.0),
+            VisualsField::WaveFadeOff => (0.0, 10.0),
+            VisualsField::WaveHeightScale => (0.0, 2.0),
+            VisualsField::WaveLineWidth | VisualsField::FftLineWidth => (0.0, 5.0),
+            VisualsField::WaveTimeRounding => (0.0, 240.0),
+            VisualsField::WaveHistoryLen => (1.0, 64.0),
+            VisualsField::WaveSamples => (16.0, 8192.0),
+        }
+    }
+}
+
+impl VisualsControls {
+    pub fn get(&self, field: VisualsField) -> f64 {
+        match field {
+            VisualsField::WaveInvTimeScale => self.wave_inv_time_scale,
+            VisualsField::WaveFadeOff => self.wave_fade_off as f64,
+            VisualsField::WaveHeightScale => self.wave_height_scale as f64,
+            VisualsField::WaveLineWidth => self.wave_line_width as f64,
+            VisualsField::FftLineWidth => self.fft_line_width as f64,
+            VisualsField::WaveTimeRounding => self.wave_time_rounding,
+            VisualsField::WaveHistoryLen => self.wave_history_len as f64,
+            VisualsField::WaveSamples => self.wave_samples as f64,
+        }
+    }
+
+    pub fn set(&mut self, field: VisualsField, value: f64) {
+        match field {
+            VisualsField::WaveInvTimeScale => self.wave_inv_time_scale = value,
+            VisualsField::WaveFadeOff => self.wave_fade_off = value as f32,
+            VisualsField::WaveHeightScale => self.wave_height_scale = value as f32,
+            VisualsField::WaveLineWidth => self.wave_line_width = value as f32,
+            VisualsField::FftLineWidth => self.fft_line_width = value as f32,
+            VisualsField::WaveTimeRounding => self.wave_time_rounding = value,
+            VisualsField::WaveHistoryLen => self.wave_history_len = value.max(0.0) as usize,
+            VisualsField::WaveSamples => self.wave_samples = value.max(1.0) as usize,
+        }
+    }
+
+    /// Set a field from 0 to 1 across its [`VisualsField::range`]
+    pub fn set_normalized(&mut self, field: VisualsField, value: f64) {
+        let (min, max) = field.range();
+        self.set(field, min + value.clamp(0.0, 1.0) * (max - min));
+    }
+}
+
 impl Default for VisualsControls {
     fn default() -> Self {
         Self {
@@ -59,48 +151,46 @@ impl Default for VisualsControls {
     }
 }
 
-impl Default for VisualData {
-    fn default() -> Self {
-        Self {
-            wave_history: Default::default(),
-            fft_data: Default::default(),
-        }
-    }
-}
-
 fn update_data(
-    time: Res<Time>,
     mut data: ResMut<VisualData>,
     controls: Res<VisualsControls>,
     sound_control: Res<SoundControl>,
 ) {
-    let time = time.elapsed_seconds_f64();
+    // the audio clock, so the wave is what is playing rather than where the frame clock is
+    let time = sound_control.time();
     let height = controls.wave_height_scale;
-    let n = controls.wave_samples;
+    // an old session or a slip of the drag value could ask for none
+    let n = controls.wave_samples.max(1);
     let sound_fn = sound_control.current_soundfn();
     let wave_time_scale = 1.0 / controls.wave_inv_time_scale;
-    data.wave_history.push_front(
-        (0..=n)
+    let sample_rate = sample_rate();
+    // a panicking sound function is reported by the audio thread, the visuals just skip a frame
+    let Ok((wave, fft_buffer)) = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let wave: Vec<_> = (0..=n)
             .map(|i| {
                 let t = (i as f64 / (n as f64)) * wave_time_scale + time;
-                let y = sound_fn(t)[0] * height as Float;
+                let y = sound_fn.channel(t, 0) * height as Float;
                 y as FloatOut
             })
-            .collect(),
-    );
+            .collect();
+        let fft_buffer: Vec<_> = (0..FFT_BUFFER_SIZE)
+            .map(|buffer_idx| {
+                let t = time + (buffer_idx as f64 / sample_rate as f64);
+                sound_fn.channel(t, 0) as FloatOut
+            })
+            .collect();
+        (wave, fft_buffer)
+    })) else {
+        return;
+    };
+    data.wave_history.push_front(wave);
     let data = data.as_mut();
     data.wave_history.truncate(controls.wave_history_len);
-    let mut fft_buffer: Vec<_> = (0..FFT_BUFFER_SIZE)
-        .into_iter()
-        .map(|buffer_idx| {
-            let t = time + (buffer_idx as f64 * INV_SAMPLE_RATE);
-            sound_fn(t)[0] as FloatOut
-        })
-        .collect();
-
-    data.fft_data = fft(&mut fft_buffer)
+
+    data.fft_data = fft(&fft_buffer, sample_rate)
         .map(|x| x.collect())
         .unwrap_or_default();
+    data.fft_sample_rate = sample_rate;
 }
 
 fn draw_visuals(
@@ -110,7 +200,7 @@ fn draw_visuals(
 ) {
     egui::CentralPanel::default().show(egui_context.ctx_mut(), |ui| {
         ui.ctx().request_repaint();
-        let n = controls.wave_samples;
+        let n = controls.wave_samples.max(1);
         let to_screen = emath::RectTransform::from_to(
             Rect::from_x_y_ranges(0.0..=1.0, -1.0..=1.0),
             ui.ctx().available_rect(),
@@ -141,7 +231,7 @@ fn draw_visuals(
             .fft_data
             .iter()
             .map(|fm| fm.mag)
-            .max_by(|a, b| a.total_cmp(&b))
+            .max_by(|a, b| a.total_cmp(b))
             .unwrap_or(1.0);
 
         let margin = 0.025;
@@ -152,8 +242,8 @@ fn draw_visuals(
             .fft_data
             .iter()
             .map(|FreqMag { freq, mag }| {
-                let x = (2.0 * freq / (SAMPLE_RATE as f32)) * non_margin + margin;
-                let y = ((mag / max_mag) * 2.0 * non_margin + margin - 1.0) * -1.0;
+                let x = (2.0 * freq / (data.fft_sample_rate as f32)) * non_margin + margin;
+                let y = -((mag / max_mag) * 2.0 * non_margin + margin - 1.0);
                 to_screen * bevy_egui::egui::pos2(x, y)
             })
             .collect();
@@ -0,0 +1,134 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::Context;
+use bevy::{
+    app::Update,
+    ecs::event::EventWriter,
+    log::{info, warn},
+    prelude::{Plugin, Res, Resource},
+};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+
+use crate::lang::SubmitCode;
+
+pub struct WatchPlugin;
+
+impl Plugin for WatchPlugin {
+    fn build(&self, app: &mut bevy::prelude::App) {
+        app.add_systems(Update, reload);
+    }
+}
+
+/// Re-submits a script whenever it changes on disk, insert it as a resource to start watching
+#[derive(Resource)]
+pub struct ScriptWatcher {
+    path: PathBuf,
+    changed: Arc<AtomicBool>,
+    _watcher: RecommendedWatcher,
+}
+
+impl ScriptWatcher {
+    pub fn new(path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let path: PathBuf = path.into();
+        let name = path.file_name().context("no file to watch")?.to_owned();
+        // starts as changed, so the script is loaded straight away
+        let changed = Arc::new(AtomicBool::new(true));
+
+        let mut watcher = notify::recommended_watcher({
+            let changed = changed.clone();
+            let name = name.clone();
+            move |event: notify::Result<notify::Event>| match event {
+                Ok(event) if event.kind.is_create() || event.kind.is_modify() => {
+                    if event.paths.iter().any(|p| p.file_name() == Some(&name)) {
+                        changed.store(true, Ordering::Relaxed);
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Watch error: {e}"),
+            }
+        })?;
+        // editors like neovim and helix save by writing a new file and renaming it over the old one,
+        // which would end a watch on the file itself, so the directory is watched instead
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        watcher
+            .watch(dir, RecursiveMode::NonRecursive)
+            .with_context(|| format!("watching {dir:?}"))?;
+        info!("Watching {path:?}");
+
+        Ok(Self {
+            path,
+            changed,
+            _watcher: watcher,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether the file changed since the last call
+    pub fn take_changed(&self) -> bool {
+        self.changed.swap(false, Ordering::Relaxed)
+    }
+
+    /// The script's source if it changed since the last call
+    pub fn poll(&self) -> Option<anyhow::Result<String>> {
+        self.take_changed().then(|| {
+            std::fs::read_to_string(&self.path).with_context(|| format!("reading {:?}", self.path))
+        })
+    }
+}
+
+fn reload(watcher: Option<Res<ScriptWatcher>>, mut code: EventWriter<SubmitCode>) {
+    let Some(watcher) = watcher else { return };
+    match watcher.poll() {
+        Some(Ok(source)) => {
+            code.send(SubmitCode::new(source));
+        }
+        Some(Err(e)) => warn!("{e:#}"),
+        None => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::ScriptWatcher;
+
+    #[test]
+    fn test_watch_rename() {
+        let dir = crate::test_dir("watch");
+        let path = dir.join("live.txt");
+        std::fs::write(&path, "one").unwrap();
+
+        let watcher = ScriptWatcher::new(&path).unwrap();
+        assert_eq!(watcher.poll().unwrap().unwrap(), "one");
+        assert!(watcher.poll().is_none());
+
+        // save the way neovim does, by renaming a new file over the old one
+        let tmp = dir.join("live.txt~");
+        std::fs::write(&tmp, "two").unwrap();
+        std::fs::rename(&tmp, &path).unwrap();
+
+        let start = Instant::now();
+        let source = loop {
+            if let Some(source) = watcher.poll() {
+                break source.unwrap();
+            }
+            assert!(start.elapsed() < Duration::from_secs(5), "no change seen");
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(source, "two");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}